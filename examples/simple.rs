@@ -3,12 +3,13 @@ extern crate raytracer;
 extern crate collision;
 extern crate cgmath;
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use raytracer::{
     camera::*,
     color::*,
     draw::*,
+    integrator::*,
     light::*,
     sdl::*,
     trace::*,
@@ -36,38 +37,42 @@ fn main() {
     };
 
     let material1 = Material {
-        shading: Rc::from(PhongShading {
-            diffuse_color: Color::from_rgb(0.18, 0.0, 0.0),
-            specular_color: Color::from_rgb(0.05, 0.05, 0.05),
+        shading: Arc::from(PhongShading {
+            diffuse_color: Arc::new(Color::from_rgb(0.18, 0.0, 0.0)),
+            specular_color: Arc::new(Color::from_rgb(0.05, 0.05, 0.05)),
             specular_exponent: 20,
         }),
+        ..Material::new()
     };
 
     let material2 = Material {
-        shading: Rc::from(PhongShading {
-            diffuse_color: Color::from_rgb(0.0, 0.18, 0.0),
-            specular_color: Color::from_rgb(0.05, 0.05, 0.05),
+        shading: Arc::from(PhongShading {
+            diffuse_color: Arc::new(Color::from_rgb(0.0, 0.18, 0.0)),
+            specular_color: Arc::new(Color::from_rgb(0.05, 0.05, 0.05)),
             specular_exponent: 20,
         }),
+        ..Material::new()
     };
 
     let material3 = Material {
-        shading: Rc::from(PhongShading {
-            diffuse_color: Color::from_rgb(0.0, 0.0, 0.18),
-            specular_color: Color::from_rgb(0.05, 0.05, 0.05),
+        shading: Arc::from(PhongShading {
+            diffuse_color: Arc::new(Color::from_rgb(0.0, 0.0, 0.18)),
+            specular_color: Arc::new(Color::from_rgb(0.05, 0.05, 0.05)),
             specular_exponent: 20,
         }),
+        ..Material::new()
     };
 
     let material4 = Material {
-        shading: Rc::from(SimpleDiffuseShading {
-            diffuse_color: Color::from_rgb(1.0, 1.0, 1.0),
+        shading: Arc::from(SimpleDiffuseShading {
+            diffuse_color: Arc::new(Color::from_rgb(1.0, 1.0, 1.0)),
             albedo: 0.18,
         }),
+        ..Material::new()
     };
 
-    let scene = RenderScene {
-        objects: vec![
+    let scene = RenderScene::new(
+        vec![
             Box::from(SimpleObject {
                 solid: Box::from(Sphere {
                     center: Point3 { x: -1.8, y: 1.5, z: 0.0 },
@@ -119,7 +124,7 @@ fn main() {
             }),
         ],
         camera,
-        lighting: Lighting {
+        Lighting {
             ambient: Color::from_rgb(0.03, 0.03, 0.03),
             lights: vec![
                 Light::point_light(
@@ -132,8 +137,8 @@ fn main() {
                 ),
             ],
         },
-        background: Color::from_rgb(0.0, 0.0, 0.0),
-    };
+        Color::from_rgb(0.0, 0.0, 0.0),
+    );
 
     draw_and_wait(&scene);
 }
@@ -143,7 +148,7 @@ fn draw_and_wait(scene: &RenderScene) {
     let mut events = ctx.event_pump().unwrap();
     let mut canvas = create_sdl_canvas(&ctx, 1200, 900);
 
-    let frame = draw(&scene, &RenderOptions::default());
+    let frame = draw(&scene, &WhittedIntegrator::default(), &RenderOptions::default());
     render_to_canvas(&mut canvas, &frame);
 
     'main: loop {
@@ -0,0 +1,445 @@
+use cgmath::{
+    EuclideanSpace,
+    InnerSpace,
+    Point3,
+};
+use collision::{
+    Aabb3,
+    Ray3,
+};
+
+/// The number of buckets that primitives are sorted into along an axis when looking for the best
+/// place to split a node.
+const SAH_BUCKETS: usize = 12;
+
+/// Nodes with at most this many primitives are never split.
+const MAX_LEAF_SIZE: usize = 4;
+
+/// The cost of visiting a node relative to the cost of intersecting a primitive, used by the
+/// surface area heuristic.
+const TRAVERSAL_COST: f32 = 0.5;
+
+/// A bounding volume hierarchy over a set of primitives, such as the objects of a scene, which
+/// lets rays skip the primitives whose bounding boxes they miss. Primitives are identified by
+/// their index in the list of bounds the hierarchy was built from; unbounded primitives are
+/// tested against every ray.
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+
+    /// The indices of the bounded primitives, ordered so that each leaf covers a contiguous
+    /// range.
+    primitives: Vec<usize>,
+
+    /// The indices of the unbounded primitives.
+    unbounded: Vec<usize>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct BvhNode {
+    bounds: Aabb3<f32>,
+    kind: BvhNodeKind,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum BvhNodeKind {
+    /// A leaf covering `count` entries of `Bvh::primitives`, starting at `first`.
+    Leaf { first: usize, count: usize },
+
+    /// An interior node, whose first child immediately follows it in `Bvh::nodes`. Its children
+    /// were split along `axis`.
+    Interior { second_child: usize, axis: usize },
+}
+
+/// A primitive that's being sorted into the hierarchy.
+#[derive(Copy, Clone)]
+struct BuildPrimitive {
+    index: usize,
+    bounds: Aabb3<f32>,
+    centroid: Point3<f32>,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over primitives with the given bounds, using the surface area
+    /// heuristic to choose how to split each node. A bound of `None` marks an unbounded
+    /// primitive.
+    pub fn build(bounds: &[Option<Aabb3<f32>>]) -> Self {
+        let mut build_primitives = vec![];
+        let mut unbounded = vec![];
+        for (index, bound) in bounds.iter().enumerate() {
+            match *bound {
+                Some(bounds) => build_primitives.push(BuildPrimitive {
+                    index,
+                    bounds,
+                    centroid: Point3::midpoint(bounds.min, bounds.max),
+                }),
+                None => unbounded.push(index),
+            }
+        }
+        let mut bvh = Bvh { nodes: vec![], primitives: vec![], unbounded };
+        if !build_primitives.is_empty() {
+            bvh.build_node(&mut build_primitives);
+        }
+        bvh
+    }
+
+    /// Calls `intersect` with the index of each primitive that `ray` might hit, which returns the
+    /// distance along the ray to the hit and any other information about it, or `None` if the
+    /// primitive was missed. Returns the index and information of the closest hit.
+    ///
+    /// Distances are in world units, not units of the ray direction's length.
+    pub fn closest_hit<T, F>(&self, ray: &Ray3<f32>, mut intersect: F) -> Option<(usize, T)>
+        where F: FnMut(usize) -> Option<(f32, T)>
+    {
+        let mut closest = None;
+        let mut max_distance = ::std::f32::INFINITY;
+        {
+            let mut consider = |index: usize, max_distance: &mut f32| {
+                if let Some((distance, hit)) = intersect(index) {
+                    if distance < *max_distance {
+                        *max_distance = distance;
+                        closest = Some((index, hit));
+                    }
+                }
+            };
+            for &index in &self.unbounded {
+                consider(index, &mut max_distance);
+            }
+            self.traverse(ray, &mut max_distance, |index, max_distance| {
+                consider(index, max_distance);
+                false
+            });
+        }
+        closest
+    }
+
+    /// Returns `true` if `intersects` returns `true` for any of the primitives that `ray` might
+    /// hit within `max_distance` world units of its origin. Stops as soon as one is found.
+    pub fn any_hit<F>(
+        &self,
+        ray: &Ray3<f32>,
+        max_distance: Option<f32>,
+        mut intersects: F,
+    ) -> bool
+        where F: FnMut(usize) -> bool
+    {
+        if self.unbounded.iter().any(|&index| intersects(index)) {
+            return true;
+        }
+        let mut max_distance = max_distance.unwrap_or(::std::f32::INFINITY);
+        self.traverse(ray, &mut max_distance, |index, _| intersects(index))
+    }
+
+    /// Calls `visit` for each bounded primitive in a leaf whose bounds `ray` enters within
+    /// `max_distance`, visiting nearer children first. `visit` may reduce `max_distance`, and
+    /// returns `true` to stop the traversal early. Returns `true` if it was stopped.
+    fn traverse<F>(&self, ray: &Ray3<f32>, max_distance: &mut f32, mut visit: F) -> bool
+        where F: FnMut(usize, &mut f32) -> bool
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let direction_length = ray.direction.magnitude();
+        let inverse_direction = [
+            1.0 / ray.direction.x,
+            1.0 / ray.direction.y,
+            1.0 / ray.direction.z,
+        ];
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            match entry_distance(&node.bounds, ray, &inverse_direction) {
+                Some(t) if t * direction_length <= *max_distance => {},
+                _ => continue,
+            }
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for &index in &self.primitives[first..first + count] {
+                        if visit(index, max_distance) {
+                            return true;
+                        }
+                    }
+                },
+                BvhNodeKind::Interior { second_child, axis } => {
+                    // Push the far child first so that the near child is visited first.
+                    if ray.direction[axis] < 0.0 {
+                        stack.push(node_index + 1);
+                        stack.push(second_child);
+                    } else {
+                        stack.push(second_child);
+                        stack.push(node_index + 1);
+                    }
+                },
+            }
+        }
+        false
+    }
+
+    /// Appends a node covering `primitives`, and its descendants, to `nodes`, returning its index.
+    fn build_node(&mut self, primitives: &mut [BuildPrimitive]) -> usize {
+        let node_index = self.nodes.len();
+        let bounds = primitives.iter().skip(1)
+            .fold(primitives[0].bounds, |bounds, primitive| union(&bounds, &primitive.bounds));
+        let leaf = BvhNode {
+            bounds,
+            kind: BvhNodeKind::Leaf { first: self.primitives.len(), count: primitives.len() },
+        };
+
+        let split = if primitives.len() <= MAX_LEAF_SIZE {
+            None
+        } else {
+            find_split(primitives, &bounds)
+        };
+        let (axis, split_index) = match split {
+            Some(split) => split,
+            None => {
+                self.primitives.extend(primitives.iter().map(|primitive| primitive.index));
+                self.nodes.push(leaf);
+                return node_index;
+            },
+        };
+
+        self.nodes.push(leaf);
+        let (first, second) = primitives.split_at_mut(split_index);
+        self.build_node(first);
+        let second_child = self.build_node(second);
+        self.nodes[node_index].kind = BvhNodeKind::Interior { second_child, axis };
+        node_index
+    }
+}
+
+/// Returns the axis to split `primitives` along and the number of primitives that go into the
+/// first child, after sorting them into the two children, or `None` if it's cheaper not to split
+/// them.
+fn find_split(primitives: &mut [BuildPrimitive], bounds: &Aabb3<f32>) -> Option<(usize, usize)> {
+    let centroid_bounds = primitives.iter().skip(1).fold(
+        Aabb3::new(primitives[0].centroid, primitives[0].centroid),
+        |bounds, primitive| union(&bounds, &Aabb3::new(primitive.centroid, primitive.centroid)),
+    );
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+    if extent[axis] <= 0.0 {
+        return None;
+    }
+
+    let bucket = |primitive: &BuildPrimitive| {
+        let offset = (primitive.centroid[axis] - centroid_bounds.min[axis]) / extent[axis];
+        ((offset * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+    };
+    let mut counts = [0; SAH_BUCKETS];
+    let mut bucket_bounds: [Option<Aabb3<f32>>; SAH_BUCKETS] = [None; SAH_BUCKETS];
+    for primitive in primitives.iter() {
+        let index = bucket(primitive);
+        counts[index] += 1;
+        bucket_bounds[index] = Some(bucket_bounds[index].map_or(primitive.bounds, |bounds| {
+            union(&bounds, &primitive.bounds)
+        }));
+    }
+
+    // The cost of splitting after each bucket, relative to the cost of intersecting one
+    // primitive, is the cost of visiting the node plus the expected cost of intersecting the
+    // primitives of each child, given that the node's bounds were hit.
+    let area = surface_area(bounds);
+    let mut best: Option<(usize, f32)> = None;
+    for split in 0..SAH_BUCKETS - 1 {
+        let side_cost = |buckets: &[usize]| {
+            let mut count = 0;
+            let mut side_bounds: Option<Aabb3<f32>> = None;
+            for &index in buckets {
+                count += counts[index];
+                side_bounds = match (side_bounds, bucket_bounds[index]) {
+                    (Some(a), Some(b)) => Some(union(&a, &b)),
+                    (a, b) => a.or(b),
+                };
+            }
+            side_bounds.map_or(0.0, |bounds| count as f32 * surface_area(&bounds))
+        };
+        let below: Vec<usize> = (0..split + 1).collect();
+        let above: Vec<usize> = (split + 1..SAH_BUCKETS).collect();
+        let cost = TRAVERSAL_COST + (side_cost(&below) + side_cost(&above)) / area;
+        if best.map_or(true, |(_, best_cost)| cost < best_cost) {
+            best = Some((split, cost));
+        }
+    }
+    let (split, cost) = best.unwrap();
+    if cost >= primitives.len() as f32 {
+        return None;
+    }
+
+    // Partition the primitives so that the ones in the first child come first.
+    let mut split_index = 0;
+    for i in 0..primitives.len() {
+        if bucket(&primitives[i]) <= split {
+            primitives.swap(i, split_index);
+            split_index += 1;
+        }
+    }
+    if split_index == 0 || split_index == primitives.len() {
+        return None;
+    }
+    Some((axis, split_index))
+}
+
+/// Returns the distance along `ray`, in units of its direction's length, at which it enters
+/// `bounds`, or 0 if it starts inside, or `None` if it misses.
+fn entry_distance(
+    bounds: &Aabb3<f32>,
+    ray: &Ray3<f32>,
+    inverse_direction: &[f32; 3],
+) -> Option<f32> {
+    let mut t_min: f32 = 0.0;
+    let mut t_max = ::std::f32::INFINITY;
+    for axis in 0..3 {
+        let t0 = (bounds.min[axis] - ray.origin[axis]) * inverse_direction[axis];
+        let t1 = (bounds.max[axis] - ray.origin[axis]) * inverse_direction[axis];
+        // `max` and `min` ignore the NaNs produced by rays parallel to a slab's planes that start
+        // on one of them.
+        t_min = t_min.max(t0.min(t1));
+        t_max = t_max.min(t0.max(t1));
+    }
+    if t_min <= t_max {
+        Some(t_min)
+    } else {
+        None
+    }
+}
+
+/// Returns the smallest box containing both `a` and `b`.
+pub fn union(a: &Aabb3<f32>, b: &Aabb3<f32>) -> Aabb3<f32> {
+    Aabb3::new(
+        Point3 {
+            x: a.min.x.min(b.min.x),
+            y: a.min.y.min(b.min.y),
+            z: a.min.z.min(b.min.z),
+        },
+        Point3 {
+            x: a.max.x.max(b.max.x),
+            y: a.max.y.max(b.max.y),
+            z: a.max.z.max(b.max.z),
+        },
+    )
+}
+
+/// Returns the smallest box containing all of `points`, or `None` if there aren't any.
+pub fn bounds_of<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<Aabb3<f32>> {
+    points.into_iter().fold(None, |bounds, point| {
+        let point_bounds = Aabb3::new(point, point);
+        Some(bounds.map_or(point_bounds, |bounds| union(&bounds, &point_bounds)))
+    })
+}
+
+fn surface_area(bounds: &Aabb3<f32>) -> f32 {
+    let extent = bounds.max - bounds.min;
+    2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::Vector3;
+    use collision::Sphere;
+    use sampling::*;
+    use trace::*;
+
+    fn spheres(count: usize) -> Vec<Sphere<f32>> {
+        let mut rng = Rng::new(0);
+        (0..count).map(|_| Sphere {
+            center: Point3 {
+                x: rng.next_f32() * 100.0 - 50.0,
+                y: rng.next_f32() * 100.0 - 50.0,
+                z: rng.next_f32() * 100.0 - 50.0,
+            },
+            radius: 0.5 + rng.next_f32(),
+        }).collect()
+    }
+
+    fn rays(count: usize) -> Vec<Ray3<f32>> {
+        let mut rng = Rng::new(1);
+        (0..count).map(|_| {
+            let direction = Vector3 {
+                x: rng.next_f32() - 0.5,
+                y: rng.next_f32() - 0.5,
+                z: rng.next_f32() - 0.5,
+            };
+            Ray3::new(Point3 { x: 0.0, y: 0.0, z: 0.0 }, direction.normalize())
+        }).collect()
+    }
+
+    fn trace_sphere(sphere: &Sphere<f32>, ray: &Ray3<f32>) -> Option<(f32, SolidHit)> {
+        sphere.trace(ray).map(|hit| ((hit.point - ray.origin).magnitude(), hit))
+    }
+
+    #[test]
+    fn test_closest_hit_matches_linear_search() {
+        let spheres = spheres(500);
+        let bvh = Bvh::build(&spheres.iter().map(|s| s.bounds()).collect::<Vec<_>>());
+        for ray in rays(500) {
+            let expected = spheres.iter().enumerate()
+                .filter_map(|(index, sphere)| trace_sphere(sphere, &ray).map(|hit| (index, hit)))
+                .min_by(|a, b| (a.1).0.partial_cmp(&(b.1).0).unwrap())
+                .map(|(index, _)| index);
+            let actual = bvh.closest_hit(&ray, |index| trace_sphere(&spheres[index], &ray));
+            assert_eq!(actual.map(|(index, _)| index), expected);
+            assert_eq!(bvh.any_hit(&ray, None, |index| spheres[index].trace(&ray).is_some()),
+                       expected.is_some());
+        }
+    }
+
+    #[test]
+    fn test_unbounded_primitives() {
+        let bvh = Bvh::build(&[None, spheres(1)[0].bounds(), None]);
+        let ray = rays(1)[0];
+        let mut visited = vec![];
+        bvh.closest_hit::<(), _>(&ray, |index| {
+            visited.push(index);
+            None
+        });
+        assert!(visited.contains(&0) && visited.contains(&2));
+        assert!(bvh.any_hit(&ray, Some(1.0), |index| index == 2));
+    }
+
+    #[test]
+    fn test_speedup() {
+        let spheres = spheres(5000);
+        let rays = rays(2000);
+        let bvh = Bvh::build(&spheres.iter().map(|s| s.bounds()).collect::<Vec<_>>());
+
+        let mut linear_tests = 0;
+        let mut linear_hits = 0;
+        for ray in &rays {
+            let mut closest = ::std::f32::INFINITY;
+            for sphere in &spheres {
+                linear_tests += 1;
+                if let Some((distance, _)) = trace_sphere(sphere, ray) {
+                    closest = closest.min(distance);
+                }
+            }
+            if closest.is_finite() {
+                linear_hits += 1;
+            }
+        }
+
+        let mut bvh_tests = 0;
+        let mut bvh_hits = 0;
+        for ray in &rays {
+            let hit = bvh.closest_hit(ray, |index| {
+                bvh_tests += 1;
+                trace_sphere(&spheres[index], ray)
+            });
+            if hit.is_some() {
+                bvh_hits += 1;
+            }
+        }
+
+        assert_eq!(linear_hits, bvh_hits);
+        // Timings are too noisy to assert on, but the number of intersection tests isn't.
+        assert!(bvh_tests * 20 < linear_tests);
+    }
+}
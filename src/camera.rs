@@ -1,9 +1,12 @@
+use std::f32::consts::PI;
+
 use cgmath::{
     Angle,
     Deg,
     InnerSpace,
     Matrix4,
     Point3,
+    Rad,
     SquareMatrix,
     Vector3,
     Vector4,
@@ -11,6 +14,30 @@ use cgmath::{
 use collision::{
     Ray3,
 };
+use sampling::*;
+
+/// Determines how points in the image are mapped to rays leaving the camera.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Rays leave the eye through points on an image plane in front of it, which covers the
+    /// camera's `fov` vertically.
+    Perspective,
+
+    /// Rays are all parallel to the viewing direction, leaving from points on a rectangle
+    /// centered on the eye that's `height` units tall in view space.
+    Orthographic { height: f32 },
+
+    /// Rays cover every direction around the eye: longitude runs from -180 degrees at the left
+    /// edge of the image to 180 degrees at the right, with the viewing direction in the middle,
+    /// and latitude runs from 90 degrees at the top to -90 degrees at the bottom.
+    Equirectangular,
+
+    /// An equidistant fisheye projection: a ray's angle from the viewing direction is
+    /// proportional to the distance of its point in the image from the center. The circle
+    /// touching the top and bottom of the image covers `fov`; points outside of it continue
+    /// the same mapping.
+    Fisheye { fov: Deg<f32> },
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct Camera {
@@ -32,6 +59,25 @@ pub struct Camera {
     /// will be mapped to the position of the eye in world space. This transformation should consist
     /// only of translation and rotation components.
     pub eye: Matrix4<f32>,
+
+    /// The radius of the camera's lens. If it's 0, the camera is a pinhole camera and everything
+    /// is in perfect focus; otherwise objects further from the focus plane are blurrier.
+    pub aperture_radius: f32,
+
+    /// The distance from the eye, along the viewing direction, to the plane that's in perfect
+    /// focus.
+    pub focus_distance: f32,
+
+    /// The number of blades in the aperture, which gives out-of-focus highlights (bokeh) the
+    /// shape of a regular polygon. If it's less than 3, the aperture is a circle.
+    pub aperture_blades: usize,
+
+    /// The rotation of a polygonal aperture about the viewing direction.
+    pub aperture_rotation: Deg<f32>,
+
+    /// The projection used to generate rays. The aperture settings only apply to perspective
+    /// projection.
+    pub projection: Projection,
 }
 
 impl Camera {
@@ -42,7 +88,18 @@ impl Camera {
         image_resolution: (usize, usize),
         eye: Matrix4<f32>
     ) -> Self {
-        Camera{ near, far, fov, image_resolution, eye }
+        Camera{
+            near,
+            far,
+            fov,
+            image_resolution,
+            eye,
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            aperture_blades: 0,
+            aperture_rotation: Deg(0.0),
+            projection: Projection::Perspective,
+        }
     }
 }
 
@@ -55,31 +112,137 @@ impl Default for Camera {
 impl Camera {
     /// Returns the point in world space corresponding to the center of the specified pixel.
     pub fn pixel_to_world(&self, x: usize, y: usize) -> Point3<f32> {
-        let pixel_size = 1.0 / self.image_resolution.1 as f32;
-        // Adding pixel_size / 2.0 gives us the center of the pixel.
-        let (screen_x, screen_y) = (
-            x as f32 / self.image_resolution.0 as f32 + pixel_size / 2.0,
-            y as f32 / self.image_resolution.1 as f32 + pixel_size / 2.0,
-        );
-        let (ndc_x, ndc_y) = (screen_x * 2.0 - 1.0, 1.0 -  2.0 * screen_y);
+        // Adding 0.5 gives us the center of the pixel.
+        self.image_to_world(x as f32 + 0.5, y as f32 + 0.5)
+    }
+
+    /// Returns the point in world space corresponding to the continuous image coordinates
+    /// `(x, y)`, measured in pixels from the top left corner of the image. The pixel `(i, j)`
+    /// covers the coordinates from `(i, j)` to `(i + 1, j + 1)`.
+    pub fn image_to_world(&self, x: f32, y: f32) -> Point3<f32> {
+        let world_coordinates = self.eye * self.image_to_view(x, y);
+        Point3::from((world_coordinates.x, world_coordinates.y, world_coordinates.z))
+    }
+
+    /// Returns the point on the image plane in view space corresponding to the continuous image
+    /// coordinates `(x, y)`.
+    fn image_to_view(&self, x: f32, y: f32) -> Vector4<f32> {
+        let (ndc_x, ndc_y) = self.image_to_ndc(x, y);
         let (image_width, image_height) = self.image_size();
         let (view_x, view_y, view_z) = (
             ndc_x * (image_width / 2.0),
             ndc_y * (image_height / 2.0),
             -1.0,
         );
-        let view_coordinates = Vector4{ x: view_x, y: view_y, z: view_z, w: 1.0 };
-        let world_coordinates = self.eye * view_coordinates;
-        Point3::from((world_coordinates.x, world_coordinates.y, world_coordinates.z))
+        Vector4{ x: view_x, y: view_y, z: view_z, w: 1.0 }
+    }
+
+    /// Returns the normalized device coordinates of the continuous image coordinates `(x, y)`,
+    /// which run from -1 at the left and bottom of the image to 1 at the right and top.
+    fn image_to_ndc(&self, x: f32, y: f32) -> (f32, f32) {
+        let (screen_x, screen_y) = (
+            x / self.image_resolution.0 as f32,
+            y / self.image_resolution.1 as f32,
+        );
+        (screen_x * 2.0 - 1.0, 1.0 -  2.0 * screen_y)
     }
 
     /// Returns a ray that originates at the camera's eye and passes through the point in world
     /// space corresponding to the center of the specified pixel.
     pub fn pixel_ray(&self, x: usize, y: usize) -> Ray3<f32> {
+        self.ray_through(x as f32 + 0.5, y as f32 + 0.5)
+    }
+
+    /// Returns the ray leaving the camera for the continuous image coordinates `(x, y)`, which
+    /// allows rays to be cast through any point within a pixel, not just its center. With a
+    /// perspective projection, it originates at the camera's eye and passes through the
+    /// corresponding point in world space; other projections are described by `Projection`.
+    pub fn ray_through(&self, x: f32, y: f32) -> Ray3<f32> {
         let eye = self.world_eye();
-        let dir = self.pixel_to_world(x, y) - eye;
-        let normalized_dir = dir / dir.magnitude();
-        Ray3::new(eye, normalized_dir)
+        let (ndc_x, ndc_y) = self.image_to_ndc(x, y);
+        let view_direction = match self.projection {
+            Projection::Perspective => {
+                let dir = self.image_to_world(x, y) - eye;
+                return Ray3::new(eye, dir / dir.magnitude());
+            },
+            Projection::Orthographic { height } => {
+                let origin = self.eye * Vector4{
+                    x: ndc_x * height * self.aspect() / 2.0,
+                    y: ndc_y * height / 2.0,
+                    z: 0.0,
+                    w: 1.0,
+                };
+                let dir = (self.eye * Vector4{ x: 0.0, y: 0.0, z: -1.0, w: 0.0 }).truncate();
+                return Ray3::new(
+                    Point3{ x: origin.x, y: origin.y, z: origin.z },
+                    dir / dir.magnitude(),
+                );
+            },
+            Projection::Equirectangular => {
+                let longitude = ndc_x * PI;
+                let latitude = ndc_y * PI / 2.0;
+                Vector3{
+                    x: latitude.cos() * longitude.sin(),
+                    y: latitude.sin(),
+                    z: -latitude.cos() * longitude.cos(),
+                }
+            },
+            Projection::Fisheye { fov } => {
+                let (image_x, image_y) = (ndc_x * self.aspect(), ndc_y);
+                let radius = (image_x * image_x + image_y * image_y).sqrt();
+                if radius == 0.0 {
+                    Vector3{ x: 0.0, y: 0.0, z: -1.0 }
+                } else {
+                    let theta = radius * Rad::from(fov).0 / 2.0;
+                    let sin_theta = theta.sin();
+                    Vector3{
+                        x: sin_theta * image_x / radius,
+                        y: sin_theta * image_y / radius,
+                        z: -theta.cos(),
+                    }
+                }
+            },
+        };
+        let dir = (self.eye * view_direction.extend(0.0)).truncate();
+        Ray3::new(eye, dir / dir.magnitude())
+    }
+
+    /// Returns a ray that passes through the point `(lens_u, lens_v)` on the camera's lens and
+    /// then through the point on the focus plane seen at the continuous image coordinates
+    /// `(x, y)`. `(lens_u, lens_v)` should be uniformly distributed in `[0, 1) x [0, 1)`, and are
+    /// mapped onto the aperture so that averaging over many of them gives depth of field. For a
+    /// pinhole camera, or a projection other than perspective, this is the same as `ray_through`.
+    pub fn lens_ray(&self, x: f32, y: f32, lens_u: f32, lens_v: f32) -> Ray3<f32> {
+        if self.aperture_radius <= 0.0 || self.projection != Projection::Perspective {
+            return self.ray_through(x, y);
+        }
+        let (lens_x, lens_y) = if self.aperture_blades >= 3 {
+            let rotation = Rad::from(self.aperture_rotation).0;
+            sample_regular_polygon(self.aperture_blades, rotation, lens_u, lens_v)
+        } else {
+            concentric_sample_disk(lens_u, lens_v)
+        };
+        let lens_point = Vector4{
+            x: lens_x * self.aperture_radius,
+            y: lens_y * self.aperture_radius,
+            z: 0.0,
+            w: 1.0,
+        };
+        // The image plane is at z = -1 in view space, so scaling a point on it gives the point on
+        // the focus plane in the same direction from the eye.
+        let image_point = self.image_to_view(x, y);
+        let focus_point = Vector4{
+            x: image_point.x * self.focus_distance,
+            y: image_point.y * self.focus_distance,
+            z: image_point.z * self.focus_distance,
+            w: 1.0,
+        };
+        let origin = self.eye * lens_point;
+        let dir = (self.eye * (focus_point - lens_point)).truncate();
+        Ray3::new(
+            Point3{ x: origin.x, y: origin.y, z: origin.z },
+            dir / dir.magnitude(),
+        )
     }
 
     /// Returns the position of the camera's eye in world space.
@@ -88,6 +251,13 @@ impl Camera {
         Point3{ x: eye_coordinates.x, y: eye_coordinates.y, z: eye_coordinates.z }
     }
 
+    /// Returns the depth of `point` in camera space, i.e. its distance from the eye along the
+    /// camera's viewing direction.
+    pub fn view_depth(&self, point: Point3<f32>) -> f32 {
+        let forward = self.eye * Vector4{ x: 0.0, y: 0.0, z: -1.0, w: 0.0 };
+        (point - self.world_eye()).dot(Vector3{ x: forward.x, y: forward.y, z: forward.z })
+    }
+
     /// Returns the aspect ratio of the camera's image.
     pub fn aspect(&self) -> f32 {
         self.image_resolution.0 as f32 / self.image_resolution.1 as f32
@@ -99,6 +269,20 @@ impl Camera {
         let image_width = image_height * self.aspect();
         (image_width, image_height)
     }
+
+    /// Returns the approximate width, in world units, of the area covered by a pixel at `point`,
+    /// which textures use to decide how much to filter. It's only exact for surfaces seen
+    /// directly by the camera.
+    pub fn pixel_footprint(&self, point: Point3<f32>) -> f32 {
+        let rows = self.image_resolution.1 as f32;
+        let distance = (point - self.world_eye()).magnitude();
+        match self.projection {
+            Projection::Perspective => self.image_size().1 / rows * distance,
+            Projection::Orthographic { height } => height / rows,
+            Projection::Equirectangular => PI / rows * distance,
+            Projection::Fisheye { fov } => Rad::from(fov).0 / rows * distance,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -131,6 +315,15 @@ mod tests {
         assert_eq!(camera.world_eye(), Point3{x: -1.0, y: 1.0, z: 1.0});
     }
 
+    #[test]
+    fn test_view_depth() {
+        let mut camera = CameraTest::new().camera;
+        assert_eq!(camera.view_depth(Point3{ x: 3.0, y: -2.0, z: -5.0 }), 5.0);
+        camera.eye = Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: 1.0})
+            * Matrix4::from_angle_y(Deg(90.0));
+        assert!((camera.view_depth(Point3{ x: -2.0, y: 7.0, z: 1.0 }) - 2.0).abs() < 1e-6);
+    }
+
     #[test]
     fn test_aspect() {
         let camera = CameraTest::new().camera;
@@ -157,6 +350,126 @@ mod tests {
         assert_eq!(camera.pixel_to_world(1, 1), Point3{ x: 1.5, y: 0.5, z: 0.0 });
     }
 
+    #[test]
+    fn test_pixel_to_world_non_square() {
+        let mut camera = CameraTest::new().camera;
+        camera.image_resolution = (4, 2);
+        // The image is 4 units wide, so the first pixel's center is 1.5 units left of center.
+        assert_eq!(camera.pixel_to_world(0, 0), Point3{ x: -1.5, y: 0.5, z: -1.0 });
+        assert_eq!(camera.pixel_to_world(3, 1), Point3{ x: 1.5, y: -0.5, z: -1.0 });
+    }
+
+    #[test]
+    fn test_image_to_world() {
+        let camera = CameraTest::new().camera;
+        assert_eq!(camera.image_to_world(0.0, 0.0), Point3{ x: -1.0, y: 1.0, z: -1.0 });
+        assert_eq!(camera.image_to_world(2.0, 2.0), Point3{ x: 1.0, y: -1.0, z: -1.0 });
+        assert_eq!(camera.image_to_world(0.5, 0.5), camera.pixel_to_world(0, 0));
+        assert_eq!(camera.image_to_world(1.25, 0.75), Point3{ x: 0.25, y: 0.25, z: -1.0 });
+    }
+
+    #[test]
+    fn test_ray_through() {
+        let camera = CameraTest::new().camera;
+        assert_eq!(camera.ray_through(1.5, 0.5), camera.pixel_ray(1, 0));
+        assert_eq!(camera.ray_through(1.0, 1.0), Ray3::new(
+            Point3{x: 0.0, y: 0.0, z: 0.0},
+            Vector3{x: 0.0, y: 0.0, z: -1.0},
+        ));
+    }
+
+    #[test]
+    fn test_lens_ray() {
+        let mut camera = CameraTest::new().camera;
+        camera.eye = Matrix4::from_translation(Vector3{x: 1.0, y: 2.0, z: 3.0});
+        assert_eq!(camera.lens_ray(0.5, 1.5, 0.1, 0.9), camera.ray_through(0.5, 1.5));
+
+        camera.aperture_radius = 0.5;
+        camera.focus_distance = 4.0;
+        let focus_point = Point3{x: 1.0 - 2.0, y: 2.0 - 2.0, z: 3.0 - 4.0};
+        for &(lens_u, lens_v) in &[(0.5, 0.5), (0.0, 0.0), (0.9, 0.2)] {
+            let ray = camera.lens_ray(0.5, 1.5, lens_u, lens_v);
+            // Every ray through the lens passes through the same point on the focus plane.
+            let t = (focus_point.z - ray.origin.z) / ray.direction.z;
+            assert!((ray.origin + ray.direction * t - focus_point).magnitude() < 1e-5);
+            assert!((ray.origin - camera.world_eye()).magnitude() <= 0.5 + 1e-6);
+            assert_eq!(ray.origin.z, 3.0);
+        }
+        assert_eq!(camera.lens_ray(0.5, 1.5, 0.5, 0.5).origin, camera.world_eye());
+
+        camera.aperture_blades = 6;
+        let ray = camera.lens_ray(0.5, 1.5, 0.3, 0.7);
+        let t = (focus_point.z - ray.origin.z) / ray.direction.z;
+        assert!((ray.origin + ray.direction * t - focus_point).magnitude() < 1e-5);
+    }
+
+    fn assert_ray_near(actual: Ray3<f32>, expected: Ray3<f32>) {
+        assert!((actual.origin - expected.origin).magnitude() < 1e-5, "{:?}", actual);
+        assert!((actual.direction - expected.direction).magnitude() < 1e-5, "{:?}", actual);
+    }
+
+    #[test]
+    fn test_orthographic_ray() {
+        let mut camera = CameraTest::new().camera;
+        camera.projection = Projection::Orthographic { height: 4.0 };
+        camera.eye = Matrix4::from_translation(Vector3{x: 1.0, y: 1.0, z: 1.0});
+        let forward = Vector3{x: 0.0, y: 0.0, z: -1.0};
+        assert_ray_near(camera.ray_through(1.0, 1.0), Ray3::new(
+            Point3{x: 1.0, y: 1.0, z: 1.0},
+            forward,
+        ));
+        assert_ray_near(camera.pixel_ray(0, 0), Ray3::new(
+            Point3{x: 0.0, y: 2.0, z: 1.0},
+            forward,
+        ));
+        assert_ray_near(camera.ray_through(2.0, 2.0), Ray3::new(
+            Point3{x: 3.0, y: -1.0, z: 1.0},
+            forward,
+        ));
+    }
+
+    #[test]
+    fn test_equirectangular_ray() {
+        let mut camera = CameraTest::new().camera;
+        camera.projection = Projection::Equirectangular;
+        let origin = Point3{x: 0.0, y: 0.0, z: 0.0};
+        assert_ray_near(camera.ray_through(1.0, 1.0), Ray3::new(
+            origin,
+            Vector3{x: 0.0, y: 0.0, z: -1.0},
+        ));
+        assert_ray_near(camera.ray_through(0.0, 1.0), Ray3::new(
+            origin,
+            Vector3{x: 0.0, y: 0.0, z: 1.0},
+        ));
+        assert_ray_near(camera.ray_through(1.5, 1.0), Ray3::new(
+            origin,
+            Vector3{x: 1.0, y: 0.0, z: 0.0},
+        ));
+        assert_ray_near(camera.ray_through(1.0, 0.0), Ray3::new(
+            origin,
+            Vector3{x: 0.0, y: 1.0, z: 0.0},
+        ));
+    }
+
+    #[test]
+    fn test_fisheye_ray() {
+        let mut camera = CameraTest::new().camera;
+        camera.projection = Projection::Fisheye { fov: Deg(180.0) };
+        let origin = Point3{x: 0.0, y: 0.0, z: 0.0};
+        assert_ray_near(camera.ray_through(1.0, 1.0), Ray3::new(
+            origin,
+            Vector3{x: 0.0, y: 0.0, z: -1.0},
+        ));
+        assert_ray_near(camera.ray_through(1.0, 0.0), Ray3::new(
+            origin,
+            Vector3{x: 0.0, y: 1.0, z: 0.0},
+        ));
+        assert_ray_near(camera.ray_through(1.5, 1.0), Ray3::new(
+            origin,
+            Vector3{x: 0.5f32.sqrt(), y: 0.0, z: -0.5f32.sqrt()},
+        ));
+    }
+
     #[test]
     fn test_pixel_ray() {
         let camera = CameraTest::new().camera;
@@ -165,4 +478,14 @@ mod tests {
             Vector3{x: -0.5, y: 0.5, z: -1.0},
         ));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pixel_footprint() {
+        // The 90 degree image plane is 2 units tall one unit from the eye, across 2 pixels.
+        let mut camera = CameraTest::new().camera;
+        let point = Point3 { x: 0.0, y: 0.0, z: -3.0 };
+        assert!((camera.pixel_footprint(point) - 3.0).abs() < 1e-5);
+        camera.projection = Projection::Orthographic { height: 4.0 };
+        assert_eq!(camera.pixel_footprint(point), 2.0);
+    }
+}
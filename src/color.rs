@@ -172,8 +172,37 @@ impl Add<Color> for Color {
     }
 }
 
+/// Subtracts the color channels only, keeping the alpha of `self`, so that weights such as
+/// `white - reflectance` stay opaque.
+impl Sub<Color> for Color {
+    type Output = Color;
+
+    fn sub(self, rhs: Color) -> <Self as Sub<Color>>::Output {
+        return Color {
+            a: self.a,
+            r: self.r - rhs.r,
+            g: self.g - rhs.g,
+            b: self.b - rhs.b,
+        }
+    }
+}
+
 impl Sum for Color {
     fn sum<I: Iterator<Item=Color>>(iter: I) -> Self {
         iter.fold(Color::from_argb(0.0, 0.0, 0.0, 0.0), |a, b| a + b)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sub_keeps_alpha() {
+        let white = Color::from_rgb(1.0, 1.0, 1.0);
+        let weight = white - Color::from_rgb(0.25, 0.5, 1.0);
+        assert_eq!((weight.a, weight.r, weight.g, weight.b), (1.0, 0.75, 0.5, 0.0));
+        let mixed = Color::from_rgb(0.2, 0.4, 0.6) * weight;
+        assert_eq!(mixed.a, 1.0);
+    }
+}
@@ -0,0 +1,225 @@
+use std::cmp::Ordering;
+
+use cgmath::Point3;
+use collision::{
+    Aabb3,
+    Ray3,
+};
+use bvh::*;
+use trace::*;
+
+/// The ways in which a `Csg` node combines its two solids.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CsgOperation {
+    /// The points inside either solid.
+    Union,
+
+    /// The points inside both solids.
+    Intersection,
+
+    /// The points inside the first solid but not the second.
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match *self {
+            CsgOperation::Union => in_left || in_right,
+            CsgOperation::Intersection => in_left && in_right,
+            CsgOperation::Difference => in_left && !in_right,
+        }
+    }
+}
+
+/// A solid made by combining two other solids, which may themselves be `Csg` nodes. Both solids
+/// must be closed, so that their `intervals` are meaningful.
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Box<Solid>,
+    pub right: Box<Solid>,
+}
+
+impl Csg {
+    pub fn union(left: Box<Solid>, right: Box<Solid>) -> Self {
+        Csg { operation: CsgOperation::Union, left, right }
+    }
+
+    pub fn intersection(left: Box<Solid>, right: Box<Solid>) -> Self {
+        Csg { operation: CsgOperation::Intersection, left, right }
+    }
+
+    /// Returns the solid made by cutting `right` out of `left`.
+    pub fn difference(left: Box<Solid>, right: Box<Solid>) -> Self {
+        Csg { operation: CsgOperation::Difference, left, right }
+    }
+}
+
+impl Solid for Csg {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        first_crossing(&self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let (left, right) = (self.left.bounds(), self.right.bounds());
+        match self.operation {
+            CsgOperation::Union => match (left, right) {
+                (Some(left), Some(right)) => Some(union(&left, &right)),
+                _ => None,
+            },
+            // The intersection is inside both solids' bounds; either will do if the other is
+            // unbounded.
+            CsgOperation::Intersection => match (left, right) {
+                (Some(left), Some(right)) => {
+                    let min = Point3 {
+                        x: left.min.x.max(right.min.x),
+                        y: left.min.y.max(right.min.y),
+                        z: left.min.z.max(right.min.z),
+                    };
+                    // If the boxes don't overlap, the intersection is empty; keep the box valid.
+                    let max = Point3 {
+                        x: left.max.x.min(right.max.x).max(min.x),
+                        y: left.max.y.min(right.max.y).max(min.y),
+                        z: left.max.z.min(right.max.z).max(min.z),
+                    };
+                    Some(Aabb3::new(min, max))
+                },
+                (left, right) => left.or(right),
+            },
+            CsgOperation::Difference => left,
+        }
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        // Walk along the ray through the crossings of both solids, keeping track of whether we're
+        // inside each of them, and record the crossings where the combination changes.
+        let mut events = vec![];
+        for &(solid, is_left) in &[(&self.left, true), (&self.right, false)] {
+            for interval in solid.intervals(ray) {
+                events.push((interval.enter, is_left, true));
+                events.push((interval.exit, is_left, false));
+            }
+        }
+        events.sort_by(|a, b| (a.0).t.partial_cmp(&(b.0).t).unwrap_or(Ordering::Equal));
+
+        let mut intervals = vec![];
+        let (mut in_left, mut in_right) = (false, false);
+        let mut enter = None;
+        for (crossing, is_left, entering) in events {
+            let was_inside = self.operation.contains(in_left, in_right);
+            if is_left {
+                in_left = entering;
+            } else {
+                in_right = entering;
+            }
+            let inside = self.operation.contains(in_left, in_right);
+            if inside == was_inside {
+                continue;
+            }
+            let crossing = if !is_left && self.operation == CsgOperation::Difference {
+                // The surface of the cut-out solid faces the other way in the result.
+                SolidCrossing {
+                    t: crossing.t,
+                    hit: crossing.hit.map(|hit| SolidHit {
+                        normal: -hit.normal,
+                        front_face: !hit.front_face,
+                        ..hit
+                    }),
+                }
+            } else {
+                crossing
+            };
+            match enter.take() {
+                None => enter = Some(crossing),
+                Some(enter) => intervals.push(SolidInterval { enter, exit: crossing }),
+            }
+        }
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::{
+        InnerSpace,
+        Vector3,
+    };
+    use collision::{
+        Plane,
+        Sphere,
+    };
+
+    /// Two unit spheres, centered at x = -0.5 and x = 0.5.
+    fn spheres() -> (Box<Solid>, Box<Solid>) {
+        (
+            Box::from(Sphere { center: Point3 { x: -0.5, y: 0.0, z: 0.0 }, radius: 1.0 }),
+            Box::from(Sphere { center: Point3 { x: 0.5, y: 0.0, z: 0.0 }, radius: 1.0 }),
+        )
+    }
+
+    fn x_ray(x: f32, direction: f32) -> Ray3<f32> {
+        Ray3::new(Point3 { x, y: 0.0, z: 0.0 }, Vector3 { x: direction, y: 0.0, z: 0.0 })
+    }
+
+    fn assert_crossings(solid: &Solid, ray: &Ray3<f32>, expected: &[(f32, f32)]) {
+        let intervals = solid.intervals(ray);
+        assert_eq!(intervals.len(), expected.len());
+        for (interval, &(enter, exit)) in intervals.iter().zip(expected) {
+            assert!((interval.enter.t - enter).abs() < 1e-5, "{:?}", intervals);
+            assert!((interval.exit.t - exit).abs() < 1e-5, "{:?}", intervals);
+        }
+    }
+
+    #[test]
+    fn test_union() {
+        let (a, b) = spheres();
+        let union = Csg::union(a, b);
+        assert_crossings(&union, &x_ray(-5.0, 1.0), &[(3.5, 6.5)]);
+        let hit = union.trace(&x_ray(-5.0, 1.0)).unwrap();
+        assert_eq!(hit.normal, Vector3 { x: -1.0, y: 0.0, z: 0.0 });
+        assert!(hit.front_face);
+        // From inside, the first hit is where the ray leaves the union.
+        let hit = union.trace(&x_ray(0.0, 1.0)).unwrap();
+        assert!((hit.point.x - 1.5).abs() < 1e-5 && !hit.front_face);
+    }
+
+    #[test]
+    fn test_intersection() {
+        let (a, b) = spheres();
+        let intersection = Csg::intersection(a, b);
+        assert_crossings(&intersection, &x_ray(-5.0, 1.0), &[(4.5, 5.5)]);
+        assert!(intersection.trace(&Ray3::new(
+            Point3 { x: -0.9, y: 5.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        )).is_none());
+        let bounds = intersection.bounds().unwrap();
+        assert_eq!((bounds.min.x, bounds.max.x), (-0.5, 0.5));
+    }
+
+    #[test]
+    fn test_difference() {
+        let (a, b) = spheres();
+        let difference = Csg::difference(a, b);
+        assert_crossings(&difference, &x_ray(-5.0, 1.0), &[(3.5, 4.5)]);
+        // Coming from the right, the ray hits the inside of the cut-out sphere's surface.
+        let hit = difference.trace(&x_ray(5.0, -1.0)).unwrap();
+        assert!((hit.point.x + 0.5).abs() < 1e-5);
+        assert_eq!(hit.normal, Vector3 { x: 1.0, y: 0.0, z: 0.0 });
+        assert!(hit.front_face);
+    }
+
+    #[test]
+    fn test_nested_with_half_space() {
+        // A union of the spheres with everything above y = 0 cut away.
+        let (a, b) = spheres();
+        let half_space = Plane { n: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, d: 0.0 };
+        let dome = Csg::intersection(Box::from(Csg::union(a, b)), Box::from(half_space));
+        let down = Vector3 { x: 0.0, y: -1.0, z: 0.0 };
+        let hit = dome.trace(&Ray3::new(Point3 { x: 0.5, y: 5.0, z: 0.0 }, down)).unwrap();
+        assert!((hit.point - Point3 { x: 0.5, y: 0.0, z: 0.0 }).magnitude() < 1e-5);
+        assert_eq!(hit.normal, Vector3 { x: 0.0, y: 1.0, z: 0.0 });
+        assert!(dome.trace(&Ray3::new(Point3 { x: 3.0, y: 5.0, z: 0.0 }, down)).is_none());
+        assert!(dome.bounds().is_some());
+    }
+}
@@ -1,9 +1,18 @@
 use std::f32::consts::PI;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::thread;
 
+use bvh::*;
 use camera::*;
 use color::*;
 use frame::*;
+use integrator::*;
 use light::*;
+use sampling::*;
+use texture::*;
 use trace::*;
 use cgmath::{
     InnerSpace,
@@ -16,139 +25,513 @@ use collision::{
 
 /// A `RenderScene` is a scene that requires no additional processing (e.g. transformations)
 /// prior to being rendered.
+///
+/// The scene's objects can only be changed through its methods, which keep the structures built
+/// from them up to date.
 pub struct RenderScene {
-    pub objects: Vec<Box<SceneObject>>,
     pub camera: Camera,
     pub lighting: Lighting,
     pub background: Color,
+
+    objects: Vec<Box<SceneObject>>,
+
+    /// The hierarchy over `objects` that rays are traced through.
+    bvh: Bvh,
+
+    /// The indices in `objects` of the objects that emit light, which are sampled for direct
+    /// lighting.
+    emitters: Vec<usize>,
+
+    /// The emitting area of each object in `objects`, or `None` for objects that aren't
+    /// `emitters`.
+    emitting_areas: Vec<Option<f32>>,
+}
+
+impl RenderScene {
+    pub fn new(
+        objects: Vec<Box<SceneObject>>,
+        camera: Camera,
+        lighting: Lighting,
+        background: Color,
+    ) -> Self {
+        let mut scene = RenderScene {
+            objects,
+            camera,
+            lighting,
+            background,
+            bvh: Bvh::default(),
+            emitters: vec![],
+            emitting_areas: vec![],
+        };
+        scene.build_bvh();
+        scene
+    }
+
+    /// Returns the scene's objects. Hits report which object they're on by its index in this
+    /// list.
+    pub fn objects(&self) -> &[Box<SceneObject>] {
+        &self.objects
+    }
+
+    /// Returns the indices in `objects()` of the objects that emit light.
+    pub fn emitters(&self) -> &[usize] {
+        &self.emitters
+    }
+
+    /// Adds `object` to the scene, and returns its index in `objects()`.
+    ///
+    /// Every change to the scene's objects rebuilds its BVH, so it's much faster to pass all of
+    /// the objects to `new` than to add them one at a time.
+    pub fn add_object(&mut self, object: Box<SceneObject>) -> usize {
+        self.objects.push(object);
+        self.build_bvh();
+        self.objects.len() - 1
+    }
+
+    /// Replaces the object at `index` with `object`, and returns the old one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn replace_object(&mut self, index: usize, object: Box<SceneObject>) -> Box<SceneObject> {
+        let old = ::std::mem::replace(&mut self.objects[index], object);
+        self.build_bvh();
+        old
+    }
+
+    /// Removes the object at `index` and returns it. The objects after it move down by one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove_object(&mut self, index: usize) -> Box<SceneObject> {
+        let old = self.objects.remove(index);
+        self.build_bvh();
+        old
+    }
+
+    /// Rebuilds `bvh` from the bounds of `objects`, and finds the `emitters` among them.
+    fn build_bvh(&mut self) {
+        let bounds: Vec<_> = self.objects.iter().map(|object| object.bounds()).collect();
+        self.bvh = Bvh::build(&bounds);
+        self.emitting_areas = self.objects.iter().map(|object| object.emitting_area()).collect();
+        self.emitters = (0..self.objects.len())
+            .filter(|&index| self.emitting_areas[index].is_some())
+            .collect();
+    }
 }
 
 pub struct RenderOptions {
     /// When a ray intersects with a solid, the intersection point may be slightly above or below
     /// the surface that the ray hit. If it's below the surface, we'll incorrectly think that it's
     /// in a shadow because no light rays can reach it. So, before doing shadow computations, we
-    /// offset the hit location by `shadow_bias` units along the normal of the surface to ensure
-    /// that it's above the surface.
+    /// offset the hit location by `shadow_bias` units along the normal of the surface, towards the
+    /// side the ray came from, to ensure that it's above the surface. Refracted rays start the
+    /// same distance below the surface instead.
     pub shadow_bias: f32,
 
-    /// `max_ray_depth` is the maximum level of recursive depth to which `cast_ray` will be
-    /// computed. In other words, it's number of a ray will be reflected or refracted before we
-    /// stop computing it. If set to 0, no reflections of refractions will be computed.
-    pub max_ray_depth: usize,
+    /// The image is divided into square tiles of `tile_size` by `tile_size` pixels (smaller at
+    /// the right and bottom edges), which are handed out to the worker threads one at a time.
+    pub tile_size: usize,
+
+    /// The number of worker threads used to render tiles. If set to 0, one thread is used per
+    /// available CPU.
+    pub threads: usize,
+
+    /// The number of rays cast through each pixel. The color of the pixel is the average of the
+    /// colors of its samples.
+    pub samples_per_pixel: usize,
+
+    /// Determines where within each pixel the samples are placed.
+    pub sampler: PixelSampler,
+
+    /// The seed for all random sampling. Rendering the same scene with the same options and seed
+    /// always produces the same image.
+    pub seed: u64,
 }
 
 impl Default for RenderOptions {
     fn default() -> Self {
         RenderOptions {
             shadow_bias: 1e-4,
-            max_ray_depth: 0,
+            tile_size: 32,
+            threads: 0,
+            samples_per_pixel: 1,
+            sampler: PixelSampler::Regular,
+            seed: 0,
         }
     }
 }
 
-/// Draws the provided scene with the provided render options.
-pub fn draw(scene: &RenderScene, options: &RenderOptions) -> Frame<Color> {
-    let camera = scene.camera;
-    let (width, height) = camera.image_resolution;
-    let mut frame = Frame::new(
-        width,
-        height,
-        scene.background,
-    );
-    for y in 0..height {
-        for x in 0..width {
-            let ray = camera.pixel_ray(x, y);
-            match cast_ray(scene, options, &ray, 0) {
-                Some(mut color) => {
-                    // Not sure when this should happen.
-                    color = color.clamped();
-                    frame.set(x, y, color);
-                },
-                None => {},
+/// A rectangular region of the image, in pixels.
+#[derive(Copy, Clone, Debug)]
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+/// Selects the arbitrary output variables (AOVs) that `draw_layers` produces alongside the
+/// beauty pass. They are all computed from the same primary hits as the beauty pass.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AovOptions {
+    pub depth: bool,
+    pub normal: bool,
+    pub albedo: bool,
+    pub position: bool,
+    pub object_index: bool,
+
+    /// Produces both `RenderLayers::direct` and `RenderLayers::indirect`.
+    pub lighting: bool,
+}
+
+impl AovOptions {
+    /// Returns options that enable every AOV.
+    pub fn all() -> Self {
+        AovOptions {
+            depth: true,
+            normal: true,
+            albedo: true,
+            position: true,
+            object_index: true,
+            lighting: true,
+        }
+    }
+
+    /// Returns whether any of the AOVs that come from the first sample's hit are enabled.
+    fn first_hit(&self) -> bool {
+        self.depth || self.normal || self.position || self.object_index
+    }
+}
+
+/// The beauty pass produced by `draw_layers`, along with each AOV that was requested in its
+/// `AovOptions`. All layers have the same size.
+///
+/// The color layers are averaged over all of a pixel's samples, like the beauty pass. The
+/// geometric layers can't be meaningfully averaged, so they come from the first sample of each
+/// pixel. If that sample didn't hit anything, the pixel has infinite depth, a zero normal, a
+/// position at infinity, and no object index.
+pub struct RenderLayers {
+    pub beauty: Frame<Color>,
+
+    /// The depth of each hit in camera space, i.e. its distance from the eye along the camera's
+    /// viewing direction.
+    pub depth: Option<Frame<f32>>,
+
+    /// The world-space surface normal at each hit.
+    pub normal: Option<Frame<Vector3<f32>>>,
+
+    /// The unlit color of the surface at each hit (see `Material::albedo`).
+    pub albedo: Option<Frame<Color>>,
+
+    /// The world-space position of each hit.
+    pub position: Option<Frame<Point3<f32>>>,
+
+    /// The index in `scene.objects()` of the object that was hit.
+    pub object_index: Option<Frame<Option<usize>>>,
+
+    /// The light that reached the camera after reflecting off a single surface, or directly
+    /// from the background. Unlike the beauty pass, this isn't clamped.
+    pub direct: Option<Frame<Color>>,
+
+    /// The light that reached the camera after reflecting off more than one surface. Unlike the
+    /// beauty pass, this isn't clamped.
+    pub indirect: Option<Frame<Color>>,
+}
+
+/// Everything computed for a single pixel by `draw_pixel`.
+#[derive(Copy, Clone, Debug)]
+struct Pixel {
+    color: Color,
+    direct: Color,
+    indirect: Color,
+    albedo: Color,
+    depth: f32,
+    normal: Vector3<f32>,
+    position: Point3<f32>,
+    object_index: Option<usize>,
+}
+
+/// Draws the provided scene with the provided render options, using `integrator` to compute the
+/// color seen along each camera ray.
+///
+/// The image is split into tiles which are rendered in parallel; since every pixel is computed
+/// independently, the result doesn't depend on the number of threads used.
+pub fn draw(
+    scene: &RenderScene,
+    integrator: &Integrator,
+    options: &RenderOptions,
+) -> Frame<Color> {
+    draw_layers(scene, integrator, options, &AovOptions::default()).beauty
+}
+
+/// Like `draw`, but also produces the AOVs selected by `aovs`.
+pub fn draw_layers(
+    scene: &RenderScene,
+    integrator: &Integrator,
+    options: &RenderOptions,
+    aovs: &AovOptions,
+) -> RenderLayers {
+    let (width, height) = scene.camera.image_resolution;
+    let tiles = split_into_tiles(width, height, options.tile_size);
+    let next_tile = AtomicUsize::new(0);
+    let rendered_tiles: Vec<(Tile, Vec<Pixel>)> = thread::scope(|s| {
+        let workers: Vec<_> = (0..worker_count(options, tiles.len()))
+            .map(|_| s.spawn(|| {
+                let mut rendered = vec![];
+                loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    match tiles.get(index) {
+                        Some(tile) => rendered.push(
+                            (*tile, draw_tile(scene, integrator, options, aovs, tile))
+                        ),
+                        None => break,
+                    }
+                }
+                rendered
+            }))
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().expect("render worker panicked"))
+            .collect()
+    });
+
+    let black = Color::from_rgb(0.0, 0.0, 0.0);
+    let zero = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+    let infinity = Point3 { x: f32::INFINITY, y: f32::INFINITY, z: f32::INFINITY };
+    let mut layers = RenderLayers {
+        beauty: Frame::new(width, height, scene.background),
+        depth: optional_frame(aovs.depth, width, height, f32::INFINITY),
+        normal: optional_frame(aovs.normal, width, height, zero),
+        albedo: optional_frame(aovs.albedo, width, height, black),
+        position: optional_frame(aovs.position, width, height, infinity),
+        object_index: optional_frame(aovs.object_index, width, height, None),
+        direct: optional_frame(aovs.lighting, width, height, black),
+        indirect: optional_frame(aovs.lighting, width, height, black),
+    };
+    for (tile, pixels) in rendered_tiles {
+        for (i, pixel) in pixels.into_iter().enumerate() {
+            let (x, y) = (tile.x + i % tile.width, tile.y + i / tile.width);
+            layers.beauty.set(x, y, pixel.color);
+            if let Some(ref mut frame) = layers.depth {
+                frame.set(x, y, pixel.depth);
+            }
+            if let Some(ref mut frame) = layers.normal {
+                frame.set(x, y, pixel.normal);
+            }
+            if let Some(ref mut frame) = layers.albedo {
+                frame.set(x, y, pixel.albedo);
+            }
+            if let Some(ref mut frame) = layers.position {
+                frame.set(x, y, pixel.position);
+            }
+            if let Some(ref mut frame) = layers.object_index {
+                frame.set(x, y, pixel.object_index);
+            }
+            if let Some(ref mut frame) = layers.direct {
+                frame.set(x, y, pixel.direct);
+            }
+            if let Some(ref mut frame) = layers.indirect {
+                frame.set(x, y, pixel.indirect);
             }
         }
     }
-    frame
+    layers
 }
 
-/// Casts `ray` into the scene and returns the final computed color.
-pub fn cast_ray(
+/// Renders the pixels of `tile` in row-major order.
+fn draw_tile(
     scene: &RenderScene,
+    integrator: &Integrator,
     options: &RenderOptions,
-    ray: &Ray3<f32>,
-    ray_depth: usize,
-) -> Option<Color> {
-    match compute_scene_hit(scene, options, ray) {
-        Some(hit) => {
-            draw_hit(scene, options, ray, &hit, ray_depth)
-        },
-        _ => None,
+    aovs: &AovOptions,
+    tile: &Tile,
+) -> Vec<Pixel> {
+    let mut pixels = Vec::with_capacity(tile.width * tile.height);
+    for y in tile.y..(tile.y + tile.height) {
+        for x in tile.x..(tile.x + tile.width) {
+            pixels.push(draw_pixel(scene, integrator, options, aovs, x, y));
+        }
+    }
+    pixels
+}
+
+/// Computes the color of the pixel `(x, y)` by averaging the colors of all of its samples, along
+/// with the AOVs selected by `aovs`.
+fn draw_pixel(
+    scene: &RenderScene,
+    integrator: &Integrator,
+    options: &RenderOptions,
+    aovs: &AovOptions,
+    x: usize,
+    y: usize,
+) -> Pixel {
+    let camera = scene.camera;
+    let black = Color::from_rgb(0.0, 0.0, 0.0);
+    let mut rng = Rng::for_pixel(options.seed, x, y);
+    let samples = options.sampler.samples(options.samples_per_pixel.max(1), &mut rng);
+    let sample_count = samples.len() as f32;
+    let mut pixel = Pixel {
+        color: black,
+        direct: black,
+        indirect: black,
+        albedo: black,
+        depth: f32::INFINITY,
+        normal: Vector3 { x: 0.0, y: 0.0, z: 0.0 },
+        position: Point3 { x: f32::INFINITY, y: f32::INFINITY, z: f32::INFINITY },
+        object_index: None,
+    };
+    for (i, (dx, dy)) in samples.into_iter().enumerate() {
+        let ray = camera.lens_ray(x as f32 + dx, y as f32 + dy, rng.next_f32(), rng.next_f32());
+        // Finding the first hit is only worth it if something is going to use it.
+        let first_hit = i == 0 && aovs.first_hit();
+        let indexed_hit = if integrator.uses_hit() || aovs.albedo || first_hit {
+            compute_indexed_scene_hit(scene, options, &ray)
+        } else {
+            None
+        };
+        let radiance = if integrator.uses_hit() {
+            let hit = indexed_hit.as_ref().map(|&(_, ref hit)| hit);
+            integrator.split_radiance(scene, options, &ray, hit, &mut rng)
+        } else {
+            let direct = integrator.radiance(scene, options, &ray, &mut rng);
+            Radiance { direct, indirect: black }
+        };
+        pixel.color = pixel.color + radiance.total() / sample_count;
+        if aovs.lighting {
+            pixel.direct = pixel.direct + radiance.direct / sample_count;
+            pixel.indirect = pixel.indirect + radiance.indirect / sample_count;
+        }
+        if let Some((index, ref hit)) = indexed_hit {
+            if aovs.albedo {
+                let context = shading_context(scene, hit);
+                pixel.albedo = pixel.albedo + hit.material.albedo(&context) / sample_count;
+            }
+            if first_hit {
+                pixel.depth = camera.view_depth(hit.solid.point);
+                pixel.normal = hit.solid.normal;
+                pixel.position = hit.solid.point;
+                pixel.object_index = Some(index);
+            }
+        }
+    }
+    // Clamp the average rather than each sample, which would darken bright lights and
+    // highlights that only some samples see.
+    pixel.color = pixel.color.clamped();
+    pixel
+}
+
+fn optional_frame<T: Copy>(
+    enabled: bool,
+    width: usize,
+    height: usize,
+    value: T,
+) -> Option<Frame<T>> {
+    if enabled {
+        Some(Frame::new(width, height, value))
+    } else {
+        None
+    }
+}
+
+fn split_into_tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let mut tiles = vec![];
+    for y in (0..height).step_by(tile_size) {
+        for x in (0..width).step_by(tile_size) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_size.min(width - x),
+                height: tile_size.min(height - y),
+            });
+        }
     }
+    tiles
+}
+
+fn worker_count(options: &RenderOptions, tile_count: usize) -> usize {
+    let threads = if options.threads == 0 {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    } else {
+        options.threads
+    };
+    threads.min(tile_count).max(1)
 }
 
 /// Casts `ray` into the scene and returns a `SceneObjectHit` corresponding to the first hit
 /// if there is one, or nothing if not.
-fn compute_scene_hit(
+pub fn compute_scene_hit(
     scene: &RenderScene,
     options: &RenderOptions,
     ray: &Ray3<f32>
 ) -> Option<SceneObjectHit> {
-    let mut current: (Option<SceneObjectHit>, Option<f32>) = (None, None);
-    for object in &scene.objects {
-        match object.trace(&ray) {
-            Some(hit) => {
-                let distance = (hit.solid.point - ray.origin).magnitude();
-                current = match current {
-                    (Some(previous_hit), Some(previous_distance)) => {
-                        if distance < previous_distance {
-                            (Some(hit), Some(distance))
-                        } else {
-                            (Some(previous_hit), Some(previous_distance))
-                        }
-                    },
-                    _ => (Some(hit), Some(distance))
-                };
-            },
-            None => continue,
-        }
-    }
-    match current.0 {
-        Some(mut hit) => {
-            let adjusted_hit_point = hit.solid.point + (hit.solid.normal * options.shadow_bias);
+    compute_indexed_scene_hit(scene, options, ray).map(|(_, hit)| hit)
+}
+
+/// Like `compute_scene_hit`, but also returns the index in `scene.objects()` of the object that
+/// was hit.
+pub fn compute_indexed_scene_hit(
+    scene: &RenderScene,
+    options: &RenderOptions,
+    ray: &Ray3<f32>
+) -> Option<(usize, SceneObjectHit)> {
+    let closest = scene.bvh.closest_hit(ray, |index| {
+        scene.objects[index].trace(&ray).map(|hit| {
+            ((hit.solid.point - ray.origin).magnitude(), hit)
+        })
+    });
+    match closest {
+        Some((index, mut hit)) => {
+            let adjusted_hit_point = hit.solid.point
+                + (hit.solid.facing_normal() * options.shadow_bias);
             hit.solid.point = adjusted_hit_point;
-            Some(hit)
+            Some((index, hit))
         },
         None => None,
     }
 }
 
-fn draw_hit(
-    scene: &RenderScene,
-    options: &RenderOptions,
-    ray: &Ray3<f32>,
-    hit: &SceneObjectHit,
-    ray_depth: usize
-) -> Option<Color> {
-    let light_color: Color = scene.lighting.lights.iter()
-        .map(|light| { compute_light(light, scene, options, ray, hit) })
-        .sum();
-
-    // TODO: make this not awful.
-    let reflected_ray = compute_reflected_ray(ray, hit);
-    if ray_depth < options.max_ray_depth {
-        cast_ray(scene, options, &reflected_ray, ray_depth + 1)
-    } else {
-        Some(light_color + scene.lighting.ambient)
+/// Returns the context for shading `hit`, with the footprint of a camera pixel there.
+pub fn shading_context(scene: &RenderScene, hit: &SceneObjectHit) -> ShadingContext {
+    ShadingContext {
+        hit: hit.solid,
+        footprint: scene.camera.pixel_footprint(hit.solid.point),
     }
 }
 
-fn compute_reflected_ray(ray: &Ray3<f32>, hit: &SceneObjectHit) -> Ray3<f32> {
+/// Returns the ray reflected about the surface normal at `hit`.
+pub fn compute_reflected_ray(ray: &Ray3<f32>, hit: &SceneObjectHit) -> Ray3<f32> {
     let par_component = hit.solid.normal * ray.direction.dot(hit.solid.normal);
     let perp_component = ray.direction - par_component;
     Ray3::new(hit.solid.point, perp_component - par_component)
 }
 
-fn compute_light(
+/// Returns the ray refracted through the surface at `hit` according to Snell's law, where `eta` is
+/// the ratio of the index of refraction on the incident side to the one on the transmitted side.
+/// Returns `None` in the case of total internal reflection.
+pub fn compute_refracted_ray(
+    ray: &Ray3<f32>,
+    hit: &SceneObjectHit,
+    eta: f32,
+    options: &RenderOptions,
+) -> Option<Ray3<f32>> {
+    let normal = hit.solid.facing_normal();
+    let cos_incident = -ray.direction.dot(normal);
+    let k = 1.0 - eta * eta * (1.0 - cos_incident * cos_incident);
+    if k < 0.0 {
+        return None;
+    }
+    let direction = ray.direction * eta + normal * (eta * cos_incident - k.sqrt());
+    // The hit point was moved above the surface; refracted rays need to start below it.
+    let origin = hit.solid.point - normal * (2.0 * options.shadow_bias);
+    Some(Ray3::new(origin, direction.normalize()))
+}
+
+/// Returns the light from `light` reflected from `hit` back along `ray`, or black if the hit
+/// point is in shadow.
+pub fn compute_light(
     light: &Light,
     scene: &RenderScene,
     options: &RenderOptions,
@@ -169,6 +552,7 @@ fn compute_light(
             let light_distance = light_direction.magnitude();
             let normalized_light_direction = light_direction / light_distance;
             let m = hit.material.shading.brdf(
+                &shading_context(scene, hit),
                 &-ray.direction,
                 &normalized_light_direction,
                 &light.intensity,
@@ -176,12 +560,130 @@ fn compute_light(
             );
             m / (4.0 * PI * light_distance * light_distance)
         },
-        _ => panic!("oh nooooooo"),
+        LightType::Spot(ref spot_light) => {
+            let light_direction = spot_light.position - hit.solid.point;
+            let light_distance = light_direction.magnitude();
+            let normalized_light_direction = light_direction / light_distance;
+            let attenuation = spot_light.attenuation(-normalized_light_direction);
+            // Points outside the cone don't need a shadow ray.
+            if attenuation.r <= 0.0 && attenuation.g <= 0.0 && attenuation.b <= 0.0 {
+                return Color::from_rgb(0.0, 0.0, 0.0);
+            }
+            if !hit_visible(
+                hit.solid.point,
+                spot_light.position,
+                scene,
+                options,
+            ) {
+                return Color::from_rgb(0.0, 0.0, 0.0);
+            }
+            let m = hit.material.shading.brdf(
+                &shading_context(scene, hit),
+                &-ray.direction,
+                &normalized_light_direction,
+                &(light.intensity * attenuation),
+                &hit.solid.normal
+            );
+            m / (4.0 * PI * light_distance * light_distance)
+        },
+        LightType::Directional(ref directional_light) => {
+            let light_direction = -directional_light.direction;
+            if occluded(hit.solid.point, light_direction, None, scene) {
+                return Color::from_rgb(0.0, 0.0, 0.0);
+            }
+            hit.material.shading.brdf(
+                &shading_context(scene, hit),
+                &-ray.direction,
+                &light_direction,
+                &light.intensity,
+                &hit.solid.normal
+            )
+        },
+    }
+}
+
+/// Returns the light emitted by the surface at `hit` back along the ray that hit it.
+pub fn compute_emitted_light(scene: &RenderScene, hit: &SceneObjectHit) -> Color {
+    match hit.material.emission {
+        Some(ref emission) if hit.solid.front_face => {
+            emission.color(&shading_context(scene, hit))
+        },
+        _ => Color::from_rgb(0.0, 0.0, 0.0),
+    }
+}
+
+/// Light arriving at a point from a point drawn on one of the scene's emitters.
+#[derive(Copy, Clone, Debug)]
+pub struct EmitterSample {
+    /// The normalized direction from the point towards the emitter.
+    pub light: Vector3<f32>,
+
+    /// The probability density, with respect to solid angle, of drawing `light`.
+    pub pdf: f32,
+
+    /// The light arriving along `light`.
+    pub radiance: Color,
+}
+
+/// Draws a point on one of `scene.emitters()`, chosen at random, for estimating the light that
+/// they cast directly on `hit`. Returns `None` if the point is occluded or faces away from `hit`,
+/// which `emitter_pdf` accounts for.
+pub fn sample_emitters(
+    scene: &RenderScene,
+    options: &RenderOptions,
+    hit: &SceneObjectHit,
+    rng: &mut Rng,
+) -> Option<EmitterSample> {
+    if scene.emitters.is_empty() {
+        return None;
+    }
+    let choice = ((rng.next_f32() * scene.emitters.len() as f32) as usize)
+        .min(scene.emitters.len() - 1);
+    let index = scene.emitters[choice];
+    let area = scene.emitting_areas[index]?;
+    let (sample, emission) =
+        scene.objects[index].sample_emission(rng.next_f32(), rng.next_f32())?;
+    let offset = sample.point - hit.solid.point;
+    let distance = offset.magnitude();
+    let light = offset / distance;
+    let cos_emitter = -light.dot(sample.normal);
+    if cos_emitter <= 0.0 || distance <= options.shadow_bias {
+        return None;
+    }
+    // Stop short of the emitter, so that it doesn't occlude itself.
+    if occluded(hit.solid.point, light, Some(distance - options.shadow_bias), scene) {
+        return None;
+    }
+    Some(EmitterSample {
+        light,
+        pdf: distance * distance / (cos_emitter * area * scene.emitters.len() as f32),
+        radiance: emission,
+    })
+}
+
+/// Returns the probability density, with respect to solid angle, with which `sample_emitters`
+/// draws the direction from `origin` to `hit`, a hit on the object with index `index`.
+pub fn emitter_pdf(
+    scene: &RenderScene,
+    index: usize,
+    origin: Point3<f32>,
+    hit: &SceneObjectHit,
+) -> f32 {
+    let area = match scene.emitting_areas.get(index) {
+        Some(&Some(area)) if hit.solid.front_face => area,
+        _ => return 0.0,
+    };
+    let offset = hit.solid.point - origin;
+    let distance2 = offset.magnitude2();
+    let cos_emitter = -offset.dot(hit.solid.normal) / distance2.sqrt();
+    if cos_emitter <= 0.0 {
+        return 0.0;
     }
+    distance2 / (cos_emitter * area * scene.emitters.len() as f32)
 }
 
 /// Returns `true` if `hit` is visible in `scene` from `point`.
-fn hit_visible(
+pub fn hit_visible(
     start: Point3<f32>,
     point: Point3<f32>,
     scene: &RenderScene,
@@ -190,18 +692,340 @@ fn hit_visible(
     let unnormalized_ray = point - start;
     let distance = unnormalized_ray.magnitude();
     let ray = unnormalized_ray / distance;
-    for obj in &scene.objects {
-        match obj.trace(&Ray3::new(start, ray)) {
+    !occluded(start, ray, Some(distance), scene)
+}
+
+/// Returns `true` if a ray cast from `start` in the normalized direction `direction` hits an
+/// object within `max_distance` units. If `max_distance` is `None`, the ray extends to infinity.
+pub fn occluded(
+    start: Point3<f32>,
+    direction: Vector3<f32>,
+    max_distance: Option<f32>,
+    scene: &RenderScene,
+) -> bool {
+    let ray = Ray3::new(start, direction);
+    scene.bvh.any_hit(&ray, max_distance, |index| {
+        match scene.objects[index].trace(&ray) {
             Some(intersection) => {
                 let distance_to_object = (intersection.solid.point - start).magnitude();
-                if distance_to_object < distance {
-                    return false;
-                } else {
-                    continue;
+                match max_distance {
+                    Some(distance) => distance_to_object < distance,
+                    None => true,
                 }
             },
-            None => continue,
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use material::*;
+    use primitives::*;
+    use instance::*;
+    use procedural::*;
+    use std::sync::Arc;
+    use cgmath::Deg;
+    use collision::{
+        Plane,
+        Sphere,
+    };
+
+    pub fn test_scene() -> RenderScene {
+        let camera = {
+            let mut camera = Camera::default();
+            camera.eye = cgmath::Matrix4::from_translation(Vector3 { x: 0.0, y: 1.0, z: 5.0 });
+            camera.image_resolution = (67, 45);
+            camera
+        };
+        RenderScene::new(
+            vec![
+                Box::from(SimpleObject {
+                    solid: Box::from(Sphere {
+                        center: Point3 { x: 0.0, y: 1.0, z: 0.0 },
+                        radius: 1.0,
+                    }),
+                    material: Material::new(),
+                }),
+                Box::from(SimpleObject {
+                    solid: Box::from(Plane {
+                        n: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+                        d: 0.0,
+                    }),
+                    material: Material::new(),
+                }),
+            ],
+            camera,
+            Lighting {
+                ambient: Color::from_rgb(0.03, 0.03, 0.03),
+                lights: vec![
+                    Light::point_light(
+                        Point3 { x: 3.0, y: 5.0, z: 3.0 },
+                        Color::from_rgb(1000.0, 1000.0, 1000.0),
+                    ),
+                ],
+            },
+            Color::from_rgb(0.1, 0.2, 0.3),
+        )
+    }
+
+    pub fn assert_frames_equal(a: &Frame<Color>, b: &Frame<Color>) {
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        for (ca, cb) in a.cells().iter().zip(b.cells()) {
+            assert_eq!((ca.a, ca.r, ca.g, ca.b), (cb.a, cb.r, cb.g, cb.b));
+        }
+    }
+
+    #[test]
+    fn test_split_into_tiles() {
+        let tiles = split_into_tiles(10, 7, 4);
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles.iter().map(|t| t.width * t.height).sum::<usize>(), 70);
+        let last = tiles[5];
+        assert_eq!((last.x, last.y, last.width, last.height), (8, 4, 2, 3));
+    }
+
+    #[test]
+    fn test_draw_is_independent_of_threads() {
+        let scene = test_scene();
+        let integrator = WhittedIntegrator::default();
+        let single_threaded = draw(&scene, &integrator, &RenderOptions {
+            threads: 1,
+            ..RenderOptions::default()
+        });
+        let multi_threaded = draw(&scene, &integrator, &RenderOptions {
+            threads: 8,
+            tile_size: 7,
+            ..RenderOptions::default()
+        });
+        assert_frames_equal(&single_threaded, &multi_threaded);
+    }
+
+    #[test]
+    fn test_supersampling_is_reproducible() {
+        let scene = test_scene();
+        let options = RenderOptions {
+            samples_per_pixel: 5,
+            sampler: PixelSampler::Random,
+            seed: 42,
+            ..RenderOptions::default()
+        };
+        let integrator = PathTracingIntegrator::default();
+        let first = draw(&scene, &integrator, &options);
+        let second = draw(&scene, &integrator, &RenderOptions {
+            threads: 3,
+            tile_size: 5,
+            ..options
+        });
+        assert_frames_equal(&first, &second);
+    }
+
+    #[test]
+    fn test_draw_layers() {
+        let mut scene = test_scene();
+        scene.camera.image_resolution = (31, 21);
+        let integrator = WhittedIntegrator::default();
+        let options = RenderOptions::default();
+        let layers = draw_layers(&scene, &integrator, &options, &AovOptions::all());
+        assert_frames_equal(&layers.beauty, &draw(&scene, &integrator, &options));
+
+        // The center pixel looks straight at the sphere, 4 units in front of the camera.
+        let (x, y) = (15, 10);
+        assert!((layers.depth.as_ref().unwrap().at(x, y).unwrap() - 4.0).abs() < 1e-3);
+        let normal = layers.normal.as_ref().unwrap().at(x, y).unwrap();
+        assert!((normal - Vector3 { x: 0.0, y: 0.0, z: 1.0 }).magnitude() < 1e-3);
+        let position = layers.position.as_ref().unwrap().at(x, y).unwrap();
+        assert!((position - Point3 { x: 0.0, y: 1.0, z: 1.0 }).magnitude() < 1e-3);
+        assert_eq!(layers.object_index.as_ref().unwrap().at(x, y).unwrap(), Some(0));
+        assert_eq!(layers.object_index.as_ref().unwrap().at(x, 0).unwrap(), None);
+        assert_eq!(layers.depth.as_ref().unwrap().at(x, 0).unwrap(), f32::INFINITY);
+        assert!((layers.albedo.as_ref().unwrap().at(x, y).unwrap().r - 0.18).abs() < 1e-5);
+
+        let beauty = layers.beauty.at(x, y).unwrap();
+        let direct = layers.direct.as_ref().unwrap().at(x, y).unwrap();
+        let indirect = layers.indirect.as_ref().unwrap().at(x, y).unwrap();
+        assert!((direct.r + indirect.r - beauty.r).abs() < 1e-6);
+        assert_eq!(indirect.r, 0.0);
+
+        let layers = draw_layers(&scene, &integrator, &options, &AovOptions::default());
+        assert!(layers.depth.is_none() && layers.direct.is_none());
+
+        // Integrators that don't use the first hit still get AOVs, and all of their light is
+        // direct.
+        let integrator = DebugIntegrator::ObjectId;
+        let layers = draw_layers(&scene, &integrator, &options, &AovOptions::all());
+        assert_frames_equal(&layers.beauty, &draw(&scene, &integrator, &options));
+        assert_eq!(layers.object_index.as_ref().unwrap().at(x, y).unwrap(), Some(0));
+        assert!((layers.albedo.as_ref().unwrap().at(x, y).unwrap().r - 0.18).abs() < 1e-5);
+        let beauty = layers.beauty.at(x, y).unwrap();
+        let direct = layers.direct.as_ref().unwrap().at(x, y).unwrap();
+        assert_eq!((direct.r, direct.g, direct.b), (beauty.r, beauty.g, beauty.b));
+    }
+
+    #[test]
+    fn test_scene_objects_stay_traceable() {
+        let mut scene = test_scene();
+        let options = RenderOptions::default();
+        let ray = Ray3::new(Point3 { x: 3.0, y: 5.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+        let hit_index = |scene: &RenderScene| {
+            compute_indexed_scene_hit(scene, &options, &ray).map(|(index, _)| index)
+        };
+        assert_eq!(hit_index(&scene), Some(1));
+
+        let sphere = Box::from(SimpleObject {
+            solid: Box::from(Sphere { center: Point3 { x: 3.0, y: 1.0, z: 0.0 }, radius: 1.0 }),
+            material: Material::new(),
+        });
+        assert_eq!(scene.add_object(sphere), 2);
+        assert_eq!(hit_index(&scene), Some(2));
+
+        // Removing objects shifts the indices of the ones after them.
+        scene.remove_object(0);
+        assert_eq!(hit_index(&scene), Some(1));
+        scene.remove_object(1);
+        assert_eq!(hit_index(&scene), Some(0));
+        assert_eq!(scene.objects().len(), 1);
+    }
+
+    #[test]
+    fn test_textured_emission() {
+        let mut scene = test_scene();
+        let options = RenderOptions::default();
+        // A light panel facing down onto the floor, with a 2x2 checkerboard over its uv square.
+        let emission = Checkerboard {
+            even: Arc::new(Color::from_rgb(1.0, 1.0, 1.0)),
+            odd: Arc::new(Color::from_rgb(0.0, 0.0, 0.0)),
+            mapping: TextureMapping::uv().scaled(2.0),
+            solid: false,
+        };
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Quad {
+                corner: Point3 { x: 2.0, y: 2.0, z: -1.0 },
+                edge1: Vector3 { x: 2.0, y: 0.0, z: 0.0 },
+                edge2: Vector3 { x: 0.0, y: 0.0, z: 2.0 },
+            }),
+            material: Material::emissive(Arc::new(emission)),
+        }));
+        let expected = |point: Point3<f32>| {
+            let (u, v) = ((point.x - 2.0) / 2.0, (point.z + 1.0) / 2.0);
+            if ((2.0 * u).floor() + (2.0 * v).floor()) as i64 % 2 == 0 { 1.0 } else { 0.0 }
+        };
+        for &x in &[2.5, 3.5] {
+            let ray = Ray3::new(Point3 { x, y: 1.0, z: -0.5 }, Vector3 { x: 0.0, y: 1.0, z: 0.0 });
+            let hit = compute_scene_hit(&scene, &options, &ray).unwrap();
+            assert_eq!(compute_emitted_light(&scene, &hit).r, expected(hit.solid.point));
+        }
+
+        // Points drawn on the panel carry the emission at their own texture coordinates.
+        let ray = Ray3::new(Point3 { x: 3.0, y: 1.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+        let hit = compute_scene_hit(&scene, &options, &ray).unwrap();
+        let mut rng = Rng::new(3);
+        let mut lit = 0;
+        for _ in 0..200 {
+            let sample = sample_emitters(&scene, &options, &hit, &mut rng).unwrap();
+            let distance = (2.0 - hit.solid.point.y) / sample.light.y;
+            let point = hit.solid.point + sample.light * distance;
+            let (u, v) = ((point.x - 2.0) / 2.0, (point.z + 1.0) / 2.0);
+            // Points right on the edge of a square could round either way.
+            if ((2.0 * u).fract() - 0.5).abs() > 0.49 || ((2.0 * v).fract() - 0.5).abs() > 0.49 {
+                continue;
+            }
+            assert_eq!(sample.radiance.r, expected(point));
+            lit += sample.radiance.r as usize;
         }
+        assert!(lit > 50 && lit < 150);
+    }
+
+    #[test]
+    fn test_instanced_emitter() {
+        let mut scene = test_scene();
+        let panel: Arc<Solid> = Arc::new(Quad {
+            corner: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            edge1: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            edge2: Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+        });
+        let index = scene.add_object(Box::from(SimpleObject {
+            solid: Box::from(Instance::new(
+                panel,
+                cgmath::Matrix4::from_translation(Vector3 { x: 2.0, y: 2.0, z: -1.0 })
+                    * cgmath::Matrix4::from_scale(2.0),
+            )),
+            material: Material::emissive(Arc::new(Color::from_rgb(1.0, 1.0, 1.0))),
+        }));
+        assert_eq!(scene.emitters(), &[index]);
+        assert_eq!(scene.objects()[index].emitting_area(), Some(4.0));
+
+        // Points on the floor below the panel are lit by it.
+        let options = RenderOptions::default();
+        let ray = Ray3::new(Point3 { x: 3.0, y: 1.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+        let hit = compute_scene_hit(&scene, &options, &ray).unwrap();
+        let sample = sample_emitters(&scene, &options, &hit, &mut Rng::new(3)).unwrap();
+        assert_eq!(sample.radiance.r, 1.0);
+        assert!(sample.light.y > 0.0);
+    }
+
+    #[test]
+    fn test_spot_light() {
+        let mut scene = test_scene();
+        let options = RenderOptions::default();
+        let intensity = Color::from_rgb(1000.0, 1000.0, 1000.0);
+        // A spot light 4 units above the floor, pointing straight down with a 30 degree cone.
+        let light = Light::spot_light(
+            Point3 { x: 3.0, y: 4.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+            Deg(25.0),
+            Deg(30.0),
+            intensity,
+        );
+        let floor_hit = |scene: &RenderScene, x: f32| {
+            let ray = Ray3::new(Point3 { x, y: 1.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+            (ray, compute_scene_hit(scene, &options, &ray).unwrap())
+        };
+        // Right under the light, it's as bright as a point light.
+        let (ray, hit) = floor_hit(&scene, 3.0);
+        let color = compute_light(&light, &scene, &options, &ray, &hit);
+        let point_light = Light::point_light(Point3 { x: 3.0, y: 4.0, z: 0.0 }, intensity);
+        let expected = compute_light(&point_light, &scene, &options, &ray, &hit);
+        assert!((color.r - expected.r).abs() < 1e-6 && color.r > 0.0);
+        // 3 units to the side is 37 degrees off axis, outside the cone.
+        let (ray, hit) = floor_hit(&scene, 6.0);
+        assert_eq!(compute_light(&light, &scene, &options, &ray, &hit).r, 0.0);
+
+        // Objects inside the cone cast shadows.
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Sphere {
+                center: Point3 { x: 3.0, y: 2.0, z: 0.0 },
+                radius: 0.5,
+            }),
+            material: Material::new(),
+        }));
+        let (ray, hit) = floor_hit(&scene, 3.0);
+        assert_eq!(compute_light(&light, &scene, &options, &ray, &hit).r, 0.0);
+    }
+
+    #[test]
+    fn test_directional_light() {
+        let mut scene = test_scene();
+        let options = RenderOptions::default();
+        let light = Light::directional_light(
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+            Color::from_rgb(2.0, 2.0, 2.0),
+        );
+        let ray = Ray3::new(Point3 { x: 3.0, y: 1.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+        let hit = compute_scene_hit(&scene, &options, &ray).unwrap();
+        let color = compute_light(&light, &scene, &options, &ray, &hit);
+        assert!((color.r - 2.0 * 0.18 / PI).abs() < 1e-5);
+
+        // Move the sphere directly above the hit point so that it casts a shadow.
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Sphere {
+                center: Point3 { x: 3.0, y: 100.0, z: 0.0 },
+                radius: 1.0,
+            }),
+            material: Material::new(),
+        }));
+        let color = compute_light(&light, &scene, &options, &ray, &hit);
+        assert_eq!(color.r, 0.0);
     }
-    true
-}
\ No newline at end of file
+}
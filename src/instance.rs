@@ -0,0 +1,233 @@
+use std::sync::Arc;
+
+use cgmath::{
+    InnerSpace,
+    Matrix,
+    Matrix4,
+    Point3,
+    SquareMatrix,
+    Transform,
+    Vector3,
+};
+use collision::{
+    Aabb3,
+    Ray3,
+};
+use bvh::*;
+use trace::*;
+
+/// A `Solid` placed in the world by an object-to-world transform, which may include rotation,
+/// translation and non-uniform scaling. The solid is shared, so one solid (e.g. a large mesh)
+/// can be placed in many places without copying it.
+#[derive(Clone)]
+pub struct Instance {
+    pub solid: Arc<Solid>,
+    transform: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+    normal_transform: Matrix4<f32>,
+}
+
+impl Instance {
+    /// Places `solid` in the world using the object-to-world matrix `transform`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `transform` isn't invertible.
+    pub fn new(solid: Arc<Solid>, transform: Matrix4<f32>) -> Self {
+        let inverse = transform.invert().expect("instance transform must be invertible");
+        Instance {
+            solid,
+            transform,
+            inverse,
+            // Normals stay perpendicular to the surface when they're transformed by the
+            // inverse-transpose.
+            normal_transform: inverse.transpose(),
+        }
+    }
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        self.transform
+    }
+
+    /// Returns `ray` in object space, along with the length of its direction there. Solids expect
+    /// normalized directions, which scaling doesn't preserve, so the direction is normalized;
+    /// distances along the object space ray must be divided by the length to get distances along
+    /// `ray`.
+    fn object_ray(&self, ray: &Ray3<f32>) -> (Ray3<f32>, f32) {
+        let direction = self.inverse.transform_vector(ray.direction);
+        let length = direction.magnitude();
+        (Ray3::new(self.inverse.transform_point(ray.origin), direction / length), length)
+    }
+
+    /// Returns the factor by which the transform scales lengths in every direction, or `None` if
+    /// it scales them differently in different directions.
+    fn uniform_scale(&self) -> Option<f32> {
+        let axes: [Vector3<f32>; 3] =
+            [self.transform.x.truncate(), self.transform.y.truncate(), self.transform.z.truncate()];
+        let scale2 = axes[0].magnitude2();
+        let tolerance = 1e-4 * scale2;
+        for i in 0..3 {
+            if (axes[i].magnitude2() - scale2).abs() > tolerance {
+                return None;
+            }
+            for j in i + 1..3 {
+                if axes[i].dot(axes[j]).abs() > tolerance {
+                    return None;
+                }
+            }
+        }
+        Some(scale2.sqrt())
+    }
+
+    /// Returns `hit`, found along an object space ray with direction length `scale`, in world
+    /// space.
+    fn world_hit(&self, hit: &SolidHit, scale: f32) -> SolidHit {
+        SolidHit {
+            t: hit.t / scale,
+            point: self.transform.transform_point(hit.point),
+            normal: self.normal_transform.transform_vector(hit.normal).normalize(),
+            dpdu: self.transform.transform_vector(hit.dpdu),
+            dpdv: self.transform.transform_vector(hit.dpdv),
+            ..*hit
+        }
+    }
+}
+
+impl Solid for Instance {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        let (object_ray, scale) = self.object_ray(ray);
+        self.solid.trace(&object_ray).map(|hit| self.world_hit(&hit, scale))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        self.solid.bounds().map(|bounds| {
+            let corners = (0..8).map(|corner| self.transform.transform_point(Point3 {
+                x: if corner & 1 == 0 { bounds.min.x } else { bounds.max.x },
+                y: if corner & 2 == 0 { bounds.min.y } else { bounds.max.y },
+                z: if corner & 4 == 0 { bounds.min.z } else { bounds.max.z },
+            }));
+            bounds_of(corners).unwrap()
+        })
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let (object_ray, scale) = self.object_ray(ray);
+        let world_crossing = |crossing: SolidCrossing| SolidCrossing {
+            t: crossing.t / scale,
+            hit: crossing.hit.map(|hit| self.world_hit(&hit, scale)),
+        };
+        self.solid.intervals(&object_ray).into_iter().map(|interval| SolidInterval {
+            enter: world_crossing(interval.enter),
+            exit: world_crossing(interval.exit),
+        }).collect()
+    }
+
+    /// Only instances with a uniform scale can be sampled: a non-uniform scale stretches some
+    /// parts of the surface more than others, so uniformly distributed points in object space
+    /// aren't uniformly distributed in the world.
+    fn surface_area(&self) -> Option<f32> {
+        let scale = self.uniform_scale()?;
+        self.solid.surface_area().map(|area| area * scale * scale)
+    }
+
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SolidHit> {
+        self.uniform_scale()?;
+        self.solid.sample_surface(u1, u2).map(|hit| self.world_hit(&hit, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::Deg;
+    use collision::Sphere;
+    use mesh::*;
+
+    fn assert_near(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    fn unit_sphere() -> Arc<Solid> {
+        Arc::new(Sphere { center: Point3 { x: 0.0, y: 0.0, z: 0.0 }, radius: 1.0 })
+    }
+
+    #[test]
+    fn test_ellipsoid() {
+        let ellipsoid = Instance::new(
+            unit_sphere(),
+            Matrix4::from_translation(Vector3 { x: 0.0, y: 1.0, z: 0.0 })
+                * Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0),
+        );
+        let ray = Ray3::new(Point3 { x: -5.0, y: 1.0, z: 0.0 }, Vector3 { x: 1.0, y: 0.0, z: 0.0 });
+        let hit = ellipsoid.trace(&ray).unwrap();
+        assert_near(hit.point - ray.origin, Vector3 { x: 3.0, y: 0.0, z: 0.0 });
+        assert_near(hit.normal, Vector3 { x: -1.0, y: 0.0, z: 0.0 });
+        assert!(hit.front_face);
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        let intervals = ellipsoid.intervals(&ray);
+        assert!((intervals[0].enter.t - 3.0).abs() < 1e-5);
+        assert!((intervals[0].exit.t - 7.0).abs() < 1e-5);
+
+        // On the surface x^2 / 4 + y^2 = 1, the normal is proportional to (x / 4, y).
+        let x = 2.0f32.sqrt();
+        let ray = Ray3::new(Point3 { x, y: 5.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+        let hit = ellipsoid.trace(&ray).unwrap();
+        let y = 0.5f32.sqrt();
+        assert_near(hit.point - ray.origin, Vector3 { x: 0.0, y: y - 4.0, z: 0.0 });
+        assert_near(hit.normal, Vector3 { x: x / 4.0, y, z: 0.0 }.normalize());
+
+        let bounds = ellipsoid.bounds().unwrap();
+        assert_eq!(bounds.min, Point3 { x: -2.0, y: 0.0, z: -1.0 });
+        assert_eq!(bounds.max, Point3 { x: 2.0, y: 2.0, z: 1.0 });
+    }
+
+    #[test]
+    fn test_shared_mesh() {
+        // A triangle in the z = 0 plane facing +z, rotated to face +x.
+        let mesh: Arc<Solid> = Arc::new(Mesh::new(
+            vec![
+                Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                Point3 { x: 1.0, y: 0.0, z: 0.0 },
+                Point3 { x: 0.0, y: 1.0, z: 0.0 },
+            ],
+            vec![[0, 1, 2]],
+        ));
+        let instances: Vec<Instance> = (0..2).map(|i| Instance::new(
+            mesh.clone(),
+            Matrix4::from_translation(Vector3 { x: i as f32, y: 0.0, z: 0.0 })
+                * Matrix4::from_angle_y(Deg(90.0)),
+        )).collect();
+        let ray = Ray3::new(
+            Point3 { x: 5.0, y: 0.25, z: -0.25 },
+            Vector3 { x: -1.0, y: 0.0, z: 0.0 },
+        );
+        for (i, instance) in instances.iter().enumerate() {
+            let hit = instance.trace(&ray).unwrap();
+            assert!((hit.point.x - i as f32).abs() < 1e-5);
+            assert_near(hit.normal, Vector3 { x: 1.0, y: 0.0, z: 0.0 });
+        }
+        assert_eq!(Arc::strong_count(&mesh), 3);
+    }
+
+    #[test]
+    fn test_sample_surface() {
+        let sphere = Instance::new(
+            unit_sphere(),
+            Matrix4::from_translation(Vector3 { x: 3.0, y: 0.0, z: 0.0 })
+                * Matrix4::from_angle_z(Deg(30.0))
+                * Matrix4::from_scale(2.0),
+        );
+        let area = sphere.surface_area().unwrap();
+        assert!((area - 16.0 * ::std::f32::consts::PI).abs() < 1e-3);
+        let hit = sphere.sample_surface(0.3, 0.6).unwrap();
+        let offset = hit.point - Point3 { x: 3.0, y: 0.0, z: 0.0 };
+        assert!((offset.magnitude() - 2.0).abs() < 1e-5);
+        assert_near(hit.normal, offset / 2.0);
+        assert_eq!(hit.t, 0.0);
+
+        let ellipsoid = Instance::new(unit_sphere(), Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0));
+        assert!(ellipsoid.surface_area().is_none());
+        assert!(ellipsoid.sample_surface(0.3, 0.6).is_none());
+    }
+}
@@ -0,0 +1,889 @@
+use color::*;
+use draw::*;
+use material::*;
+use sampling::*;
+use trace::*;
+use cgmath::{
+    InnerSpace,
+};
+use collision::{
+    Ray3,
+};
+
+/// An `Integrator` computes the color seen along a camera ray. `draw` casts one or more rays
+/// through each pixel and averages the colors that its integrator returns for them.
+///
+/// The scene query functions in `draw` (`compute_scene_hit`, `compute_light`, `occluded` and so
+/// on) are public so that integrators can be implemented outside of this crate.
+pub trait Integrator: Send + Sync {
+    /// Returns the color seen along `ray`. Any random decisions should be made with `rng`, which
+    /// is seeded per pixel so that renders are reproducible.
+    fn radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        rng: &mut Rng,
+    ) -> Color;
+
+    /// Like `radiance`, but split into direct and indirect light, and given `hit`, the first hit
+    /// along `ray` (which `draw` has already computed), so that it doesn't need to be found
+    /// again. The default implementation counts everything as direct light and ignores `hit`.
+    ///
+    /// `draw` only calls this if `uses_hit` returns true.
+    fn split_radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        _hit: Option<&SceneObjectHit>,
+        rng: &mut Rng,
+    ) -> Radiance {
+        Radiance {
+            direct: self.radiance(scene, options, ray, rng),
+            indirect: Color::from_rgb(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Returns whether `draw` should find the first hit along each camera ray and pass it to
+    /// `split_radiance`. Integrators that override `split_radiance` must return true, or `draw`
+    /// won't call it. If this returns false, `draw` calls `radiance` instead, counts its result
+    /// as direct light, and only finds the first hit if an AOV needs it, which saves a trace
+    /// per ray for integrators that find their own hits.
+    fn uses_hit(&self) -> bool;
+}
+
+/// The light seen along a ray, split into the part that reflected off a single surface (or came
+/// straight from the background) and the part that reflected off several.
+#[derive(Copy, Clone, Debug)]
+pub struct Radiance {
+    pub direct: Color,
+    pub indirect: Color,
+}
+
+impl Radiance {
+    pub fn total(&self) -> Color {
+        self.direct + self.indirect
+    }
+
+    /// Adds `light` that reached the camera after `bounce` bounces after the first hit.
+    fn add(&mut self, bounce: usize, light: Color) {
+        if bounce == 0 {
+            self.direct = self.direct + light;
+        } else {
+            self.indirect = self.indirect + light;
+        }
+    }
+}
+
+/// Whitted-style ray tracing: direct lighting from `scene.lighting` and the scene's emitters, a
+/// flat ambient term, and perfect reflection and refraction.
+#[derive(Copy, Clone, Debug)]
+pub struct WhittedIntegrator {
+    /// `max_ray_depth` is the maximum level of recursive depth to which `cast_ray` will be
+    /// computed. In other words, it's number of a ray will be reflected or refracted before we
+    /// stop computing it. If set to 0, no reflections of refractions will be computed, and
+    /// transparent surfaces show the background.
+    pub max_ray_depth: usize,
+}
+
+impl Default for WhittedIntegrator {
+    fn default() -> Self {
+        WhittedIntegrator {
+            max_ray_depth: 0,
+        }
+    }
+}
+
+impl Integrator for WhittedIntegrator {
+    fn radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        rng: &mut Rng,
+    ) -> Color {
+        self.cast_ray(scene, options, ray, 0, rng)
+    }
+
+    fn split_radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        hit: Option<&SceneObjectHit>,
+        rng: &mut Rng,
+    ) -> Radiance {
+        match hit {
+            Some(hit) => self.draw_hit(scene, options, ray, hit, 0, rng),
+            None => Radiance {
+                direct: scene.background,
+                indirect: Color::from_rgb(0.0, 0.0, 0.0),
+            },
+        }
+    }
+
+    fn uses_hit(&self) -> bool {
+        true
+    }
+}
+
+impl WhittedIntegrator {
+    /// Casts `ray` into the scene and returns the final computed color. Rays that don't hit
+    /// anything take on the scene's background color. `rng` is used to sample the scene's
+    /// emitters.
+    pub fn cast_ray(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        ray_depth: usize,
+        rng: &mut Rng,
+    ) -> Color {
+        match compute_scene_hit(scene, options, ray) {
+            Some(hit) => {
+                self.draw_hit(scene, options, ray, &hit, ray_depth, rng).total()
+            },
+            _ => scene.background,
+        }
+    }
+
+    /// Computes the color of a hit. Light reflected or refracted from other surfaces is counted
+    /// as indirect.
+    fn draw_hit(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        hit: &SceneObjectHit,
+        ray_depth: usize,
+        rng: &mut Rng,
+    ) -> Radiance {
+        if let Some(ref dielectric) = hit.material.dielectric {
+            let color =
+                self.draw_dielectric_hit(scene, options, ray, hit, dielectric, ray_depth, rng);
+            return Radiance {
+                direct: Color::from_rgb(0.0, 0.0, 0.0),
+                indirect: color,
+            };
+        }
+
+        let context = shading_context(scene, hit);
+        let light_color: Color = scene.lighting.lights.iter()
+            .map(|light| { compute_light(light, scene, options, ray, hit) })
+            .sum();
+        // Light from emissive objects is estimated from a single point on one of them.
+        let emitter_color = match sample_emitters(scene, options, hit, rng) {
+            Some(sample) => hit.material.shading.brdf(
+                &context,
+                &-ray.direction,
+                &sample.light,
+                &sample.radiance,
+                &hit.solid.normal,
+            ) / sample.pdf,
+            None => Color::from_rgb(0.0, 0.0, 0.0),
+        };
+        let direct_color = light_color + emitter_color + scene.lighting.ambient;
+
+        let reflectance = hit.material.reflectance.color(&context);
+        let reflected_color = if ray_depth < self.max_ray_depth && !is_black(&reflectance) {
+            let reflected_ray = compute_reflected_ray(ray, hit);
+            self.cast_ray(scene, options, &reflected_ray, ray_depth + 1, rng)
+        } else {
+            Color::from_rgb(0.0, 0.0, 0.0)
+        };
+        let transmitted_color = match hit.material.shading.transmission(&context) {
+            Some(ref transmission) => {
+                self.draw_transmitted_light(scene, options, ray, hit, transmission, ray_depth, rng)
+            },
+            None => Color::from_rgb(0.0, 0.0, 0.0),
+        };
+        let diffuse_weight = Color::from_rgb(1.0, 1.0, 1.0) - reflectance;
+        Radiance {
+            direct: compute_emitted_light(scene, hit) + direct_color * diffuse_weight,
+            indirect: reflected_color * reflectance + transmitted_color * diffuse_weight,
+        }
+    }
+
+    /// Computes the light that a partly transparent surface transmits towards the viewer. Like
+    /// `draw_dielectric_hit`, the boundary splits it between the reflected and refracted rays,
+    /// and at the maximum ray depth it's the background.
+    fn draw_transmitted_light(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        hit: &SceneObjectHit,
+        transmission: &Transmission,
+        ray_depth: usize,
+        rng: &mut Rng,
+    ) -> Color {
+        let dielectric = &transmission.dielectric;
+        self.draw_dielectric_hit(scene, options, ray, hit, dielectric, ray_depth, rng)
+            * transmission.color
+    }
+
+    /// Computes the color of a hit on a transparent surface by blending the colors of the
+    /// reflected and refracted rays. At the maximum ray depth, the surface is treated as if it
+    /// let the background through, rather than as black.
+    fn draw_dielectric_hit(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        hit: &SceneObjectHit,
+        dielectric: &Dielectric,
+        ray_depth: usize,
+        rng: &mut Rng,
+    ) -> Color {
+        if ray_depth >= self.max_ray_depth {
+            return scene.background;
+        }
+        let (eta_incident, eta_transmitted) = refractive_indices(hit, dielectric);
+        let cos_incident = -ray.direction.dot(hit.solid.facing_normal());
+        let reflected_fraction = fresnel_dielectric(cos_incident, eta_incident, eta_transmitted);
+
+        let reflected_ray = compute_reflected_ray(ray, hit);
+        let reflected_color = self.cast_ray(scene, options, &reflected_ray, ray_depth + 1, rng);
+        match compute_refracted_ray(ray, hit, eta_incident / eta_transmitted, options) {
+            Some(refracted_ray) if reflected_fraction < 1.0 => {
+                let refracted_color =
+                    self.cast_ray(scene, options, &refracted_ray, ray_depth + 1, rng);
+                reflected_color * reflected_fraction
+                    + refracted_color * (1.0 - reflected_fraction)
+            },
+            _ => reflected_color,
+        }
+    }
+}
+
+/// Unidirectional Monte Carlo path tracing, which computes indirect lighting as well as direct
+/// lighting. `scene.lighting.ambient` is ignored, since light bouncing between surfaces takes its
+/// place.
+#[derive(Copy, Clone, Debug)]
+pub struct PathTracingIntegrator {
+    /// The maximum number of bounces in a path.
+    pub max_ray_depth: usize,
+
+    /// Paths that have bounced at least this many times are randomly terminated with a
+    /// probability based on how little light they can still carry (Russian roulette). Surviving
+    /// paths are weighted up to compensate, so the result is unbiased.
+    pub russian_roulette_depth: usize,
+}
+
+impl Default for PathTracingIntegrator {
+    fn default() -> Self {
+        PathTracingIntegrator {
+            max_ray_depth: 8,
+            russian_roulette_depth: 3,
+        }
+    }
+}
+
+impl Integrator for PathTracingIntegrator {
+    fn radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        rng: &mut Rng,
+    ) -> Color {
+        self.trace_path(scene, options, ray, rng)
+    }
+
+    fn split_radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        hit: Option<&SceneObjectHit>,
+        rng: &mut Rng,
+    ) -> Radiance {
+        self.trace_path_from_hit(scene, options, ray, hit.cloned(), rng)
+    }
+
+    fn uses_hit(&self) -> bool {
+        true
+    }
+}
+
+impl PathTracingIntegrator {
+    /// Estimates the light arriving along `ray` by following a single random path through the
+    /// scene.
+    ///
+    /// At each diffuse bounce, light from `scene.lighting.lights` and from a point on one of the
+    /// scene's emitters is added directly (next-event estimation) and the path continues in a
+    /// direction drawn by the material's `Shading::sample`. Reflective and transparent surfaces
+    /// randomly continue the path along the reflected or refracted direction, in proportion to
+    /// how much light each carries.
+    ///
+    /// Emitters that the path hits after a diffuse bounce could also have been sampled directly,
+    /// so the two estimates of their light are combined with multiple importance sampling.
+    pub fn trace_path(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        rng: &mut Rng,
+    ) -> Color {
+        let hit = compute_scene_hit(scene, options, ray);
+        self.trace_path_from_hit(scene, options, ray, hit, rng).total()
+    }
+
+    /// Like `trace_path`, but starting from `first_hit`, the first hit along `ray`. Light added
+    /// at the first hit (or from the background, if there is none) is counted as direct.
+    fn trace_path_from_hit(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        first_hit: Option<SceneObjectHit>,
+        rng: &mut Rng,
+    ) -> Radiance {
+        let white = Color::from_rgb(1.0, 1.0, 1.0);
+        let mut radiance = Radiance {
+            direct: Color::from_rgb(0.0, 0.0, 0.0),
+            indirect: Color::from_rgb(0.0, 0.0, 0.0),
+        };
+        let mut throughput = white;
+        let mut ray = *ray;
+        let mut next_hit = first_hit;
+        // The index of the object that `next_hit` is on, which isn't known for the first hit.
+        let mut hit_index = None;
+        // The probability density with which `ray` was drawn by `Shading::sample`, or `None` if
+        // it's the camera ray or a perfect reflection or refraction.
+        let mut scattering_pdf = None;
+        let mut bounce = 0;
+        loop {
+            let hit = match next_hit.take() {
+                Some(hit) => hit,
+                None => {
+                    radiance.add(bounce, throughput * scene.background);
+                    break;
+                },
+            };
+            let emitted = compute_emitted_light(scene, &hit);
+            if !is_black(&emitted) {
+                let weight = match (scattering_pdf, hit_index) {
+                    (Some(pdf), Some(index)) => {
+                        power_heuristic(pdf, emitter_pdf(scene, index, ray.origin, &hit))
+                    },
+                    _ => 1.0,
+                };
+                radiance.add(bounce, throughput * emitted * weight);
+            }
+            // Light is still gathered at the last bounce; only the path stops there.
+            let last_bounce = bounce >= self.max_ray_depth;
+
+            ray = if let Some(ref dielectric) = hit.material.dielectric {
+                if last_bounce {
+                    break;
+                }
+                scattering_pdf = None;
+                scatter_dielectric(options, &ray, &hit, dielectric, rng)
+            } else {
+                let context = shading_context(scene, &hit);
+                let reflectance = hit.material.reflectance.color(&context);
+                let diffuse_weight = white - reflectance;
+                let transmission = hit.material.shading.transmission(&context);
+                let transmit_weight = match transmission {
+                    Some(ref transmission) => diffuse_weight * transmission.color,
+                    None => Color::from_rgb(0.0, 0.0, 0.0),
+                };
+
+                let reflect_probability = max_component(&reflectance).min(1.0);
+                let transmit_probability = max_component(&transmit_weight).min(1.0)
+                    * (1.0 - reflect_probability);
+                let sample_probability = 1.0 - reflect_probability - transmit_probability;
+
+                let (view, normal) = (-ray.direction, hit.solid.facing_normal());
+                let mut direct_light: Color = scene.lighting.lights.iter()
+                    .map(|light| { compute_light(light, scene, options, &ray, &hit) })
+                    .sum();
+                if let Some(sample) = sample_emitters(scene, options, &hit, rng) {
+                    let shading = &hit.material.shading;
+                    // If the path stops here, the emitter can't also be reached by sampling the
+                    // BSDF, so this sample gets all of the weight.
+                    let pdf = if last_bounce {
+                        0.0
+                    } else {
+                        sample_probability * shading.pdf(&context, &view, &sample.light, &normal)
+                    };
+                    let light =
+                        shading.brdf(&context, &view, &sample.light, &sample.radiance, &normal);
+                    direct_light = direct_light
+                        + light * (power_heuristic(sample.pdf, pdf) / sample.pdf);
+                }
+                radiance.add(bounce, throughput * diffuse_weight * direct_light);
+                if last_bounce {
+                    break;
+                }
+
+                let choice = rng.next_f32();
+                if choice < reflect_probability {
+                    throughput = throughput * reflectance / reflect_probability;
+                    scattering_pdf = None;
+                    compute_reflected_ray(&ray, &hit)
+                } else if choice < reflect_probability + transmit_probability {
+                    throughput = throughput * transmit_weight / transmit_probability;
+                    scattering_pdf = None;
+                    // Like a dielectric, the boundary reflects some of the transmitted light.
+                    let dielectric = transmission.unwrap().dielectric;
+                    scatter_dielectric(options, &ray, &hit, &dielectric, rng)
+                } else {
+                    let sample = hit.material.shading.sample(&context, &view, &normal, rng);
+                    let sample = match sample {
+                        Some(sample) => sample,
+                        None => break,
+                    };
+                    throughput = throughput * diffuse_weight * sample.value
+                        / (sample.pdf * sample_probability);
+                    scattering_pdf = Some(sample.pdf * sample_probability);
+                    Ray3::new(hit.solid.point, sample.light)
+                }
+            };
+
+            bounce += 1;
+            if bounce >= self.russian_roulette_depth {
+                let survival_probability = max_component(&throughput).min(1.0);
+                if rng.next_f32() >= survival_probability {
+                    break;
+                }
+                throughput = throughput / survival_probability;
+            }
+            let indexed_hit = compute_indexed_scene_hit(scene, options, &ray);
+            hit_index = indexed_hit.as_ref().map(|&(index, _)| index);
+            next_hit = indexed_hit.map(|(_, hit)| hit);
+        }
+        radiance
+    }
+}
+
+/// Ambient occlusion: the color of a hit is the fraction of the hemisphere above it, weighted by
+/// the cosine of the angle to the normal, that isn't blocked by geometry within `max_distance`.
+/// Rays that don't hit anything are white.
+#[derive(Copy, Clone, Debug)]
+pub struct AmbientOcclusionIntegrator {
+    /// The number of occlusion rays cast from each hit.
+    pub samples: usize,
+
+    /// Geometry further away than this doesn't occlude the hit.
+    pub max_distance: f32,
+}
+
+impl Default for AmbientOcclusionIntegrator {
+    fn default() -> Self {
+        AmbientOcclusionIntegrator {
+            samples: 16,
+            max_distance: 1.0,
+        }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        rng: &mut Rng,
+    ) -> Color {
+        let hit = match compute_scene_hit(scene, options, ray) {
+            Some(hit) => hit,
+            None => return Color::from_rgb(1.0, 1.0, 1.0),
+        };
+        let normal = hit.solid.facing_normal();
+        let samples = self.samples.max(1);
+        let unoccluded = (0..samples)
+            .filter(|_| {
+                let direction = cosine_sample_hemisphere(normal, rng.next_f32(), rng.next_f32());
+                !occluded(hit.solid.point, direction, Some(self.max_distance), scene)
+            })
+            .count();
+        let visibility = unoccluded as f32 / samples as f32;
+        Color::from_rgb(visibility, visibility, visibility)
+    }
+
+    fn uses_hit(&self) -> bool {
+        false
+    }
+}
+
+/// A debugging aid that shows some property of the first hit along each ray instead of
+/// lighting the scene. Rays that don't hit anything are black.
+#[derive(Copy, Clone, Debug)]
+pub enum DebugIntegrator {
+    /// Shows the surface normal, mapping each component from `[-1, 1]` to `[0, 1]`.
+    Normal,
+
+    /// Shows the distance from the ray's origin to the hit as a shade of gray, from black at
+    /// distance 0 to white at `max_depth` and beyond.
+    Depth { max_depth: f32 },
+
+    /// Shows each object in `scene.objects()` in a different, arbitrary color.
+    ObjectId,
+}
+
+impl Integrator for DebugIntegrator {
+    fn radiance(
+        &self,
+        scene: &RenderScene,
+        options: &RenderOptions,
+        ray: &Ray3<f32>,
+        _rng: &mut Rng,
+    ) -> Color {
+        let (index, hit) = match compute_indexed_scene_hit(scene, options, ray) {
+            Some(indexed_hit) => indexed_hit,
+            None => return Color::from_rgb(0.0, 0.0, 0.0),
+        };
+        match *self {
+            DebugIntegrator::Normal => {
+                let normal = hit.solid.normal;
+                Color::from_rgb(
+                    normal.x * 0.5 + 0.5,
+                    normal.y * 0.5 + 0.5,
+                    normal.z * 0.5 + 0.5,
+                )
+            },
+            DebugIntegrator::Depth { max_depth } => {
+                let depth = (hit.solid.point - ray.origin).magnitude() / max_depth;
+                Color::from_rgb(depth, depth, depth)
+            },
+            DebugIntegrator::ObjectId => object_id_color(index),
+        }
+    }
+
+    fn uses_hit(&self) -> bool {
+        false
+    }
+}
+
+/// Returns an arbitrary but fixed color for the object with index `index`, such that objects
+/// with nearby indices get very different colors.
+fn object_id_color(index: usize) -> Color {
+    let bits = Rng::new(index as u64).next_u64();
+    Color::from_rgb_u8s(bits as u8, (bits >> 8) as u8, (bits >> 16) as u8)
+}
+
+/// Randomly continues a path that hit a dielectric boundary along either the reflected or the
+/// refracted ray, in proportion to how much light each carries.
+fn scatter_dielectric(
+    options: &RenderOptions,
+    ray: &Ray3<f32>,
+    hit: &SceneObjectHit,
+    dielectric: &Dielectric,
+    rng: &mut Rng,
+) -> Ray3<f32> {
+    let (eta_incident, eta_transmitted) = refractive_indices(hit, dielectric);
+    let cos_incident = -ray.direction.dot(hit.solid.facing_normal());
+    let reflected_fraction = fresnel_dielectric(cos_incident, eta_incident, eta_transmitted);
+    match compute_refracted_ray(ray, hit, eta_incident / eta_transmitted, options) {
+        Some(refracted_ray) if rng.next_f32() >= reflected_fraction => refracted_ray,
+        _ => compute_reflected_ray(ray, hit),
+    }
+}
+
+/// Returns the indices of refraction on the incident and transmitted sides of a hit on a
+/// dielectric, assuming that the outside of the solid is air.
+fn refractive_indices(hit: &SceneObjectHit, dielectric: &Dielectric) -> (f32, f32) {
+    if hit.solid.front_face {
+        (1.0, dielectric.index_of_refraction)
+    } else {
+        (dielectric.index_of_refraction, 1.0)
+    }
+}
+
+fn is_black(color: &Color) -> bool {
+    color.r <= 0.0 && color.g <= 0.0 && color.b <= 0.0
+}
+
+fn max_component(color: &Color) -> f32 {
+    color.r.max(color.g).max(color.b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use draw::tests::*;
+    use primitives::*;
+    use cgmath::{
+        Point3,
+        Vector3,
+    };
+    use collision::{
+        Plane,
+        Sphere,
+    };
+
+    #[test]
+    fn test_reflection_mixes_with_direct_light() {
+        let mut scene = test_scene();
+        scene.lighting.lights.clear();
+        scene.remove_object(0);
+        let options = RenderOptions::default();
+        let integrator = WhittedIntegrator { max_ray_depth: 1 };
+        // Looking down at the floor, the reflected ray leaves the scene and picks up the
+        // background color.
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        let ambient = scene.lighting.ambient;
+        let background = scene.background;
+        let color = integrator.cast_ray(&scene, &options, &ray, 0, &mut Rng::new(0));
+        assert!((color.r - ambient.r).abs() < 1e-6);
+
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Plane {
+                n: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+                d: 0.0,
+            }),
+            material: Material::new().with_reflectance(0.25),
+        }));
+        let color = integrator.cast_ray(&scene, &options, &ray, 0, &mut Rng::new(0));
+        assert!((color.r - (0.75 * ambient.r + 0.25 * background.r)).abs() < 1e-6);
+        assert!((color.b - (0.75 * ambient.b + 0.25 * background.b)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_dielectric_conserves_energy() {
+        let mut scene = test_scene();
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Sphere {
+                center: Point3 { x: 0.0, y: 1.0, z: 0.0 },
+                radius: 1.0,
+            }),
+            material: Material::dielectric(1.5),
+        }));
+        let options = RenderOptions::default();
+        let integrator = WhittedIntegrator { max_ray_depth: 8 };
+        // A ray through the center of a glass sphere is split into rays that all eventually
+        // leave the scene, so no light is lost except at the maximum ray depth.
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 1.0, z: 5.0 },
+            Vector3 { x: 0.0, y: 0.0, z: -1.0 },
+        );
+        let color = integrator.cast_ray(&scene, &options, &ray, 0, &mut Rng::new(0));
+        assert!((color.r - scene.background.r).abs() < 1e-3);
+        assert!((color.b - scene.background.b).abs() < 1e-3);
+
+        // At the maximum ray depth, glass lets the background through instead of turning black.
+        let integrator = WhittedIntegrator { max_ray_depth: 0 };
+        let color = integrator.cast_ray(&scene, &options, &ray, 0, &mut Rng::new(0));
+        assert_eq!(color.r, scene.background.r);
+    }
+
+    #[test]
+    fn test_transmission() {
+        let mut scene = test_scene();
+        scene.lighting.lights.clear();
+        scene.lighting.ambient = Color::from_rgb(0.0, 0.0, 0.0);
+        let shading = PrincipledShading {
+            base_color: Arc::new(Color::from_rgb(1.0, 0.5, 1.0)),
+            transmission: 1.0,
+            ..Default::default()
+        };
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Sphere {
+                center: Point3 { x: 0.0, y: 1.0, z: 0.0 },
+                radius: 1.0,
+            }),
+            material: Material { shading: Arc::new(shading), ..Material::new() },
+        }));
+        let options = RenderOptions::default();
+        // A ray through the center of the sphere picks up the base color at each side, where 96%
+        // of the light is refracted and 4% reflected. Inside, the reflected ray ends at the far
+        // side at the maximum ray depth, which shows the background.
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 1.0, z: 5.0 },
+            Vector3 { x: 0.0, y: 0.0, z: -1.0 },
+        );
+        let (background, color) = (scene.background, Color::from_rgb(1.0, 0.5, 1.0));
+        let inside = color * (background * 0.96 + color * background * 0.04);
+        let expected = color * (background * 0.04 + inside * 0.96);
+        let integrator = WhittedIntegrator { max_ray_depth: 2 };
+        let color = integrator.cast_ray(&scene, &options, &ray, 0, &mut Rng::new(0));
+        assert!((color.r - expected.r).abs() < 1e-3);
+        assert!((color.g - expected.g).abs() < 1e-3);
+        // Without any ray depth, the transmitted light is the background.
+        let integrator = WhittedIntegrator { max_ray_depth: 0 };
+        let color = integrator.cast_ray(&scene, &options, &ray, 0, &mut Rng::new(0));
+        assert!((color.g - background.g * 0.5).abs() < 1e-6);
+
+        let integrator = PathTracingIntegrator::default();
+        let mut rng = Rng::new(5);
+        let samples = 2000;
+        let mean = (0..samples)
+            .map(|_| integrator.trace_path(&scene, &options, &ray, &mut rng).g)
+            .sum::<f32>() / samples as f32;
+        assert!((mean - expected.g).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_emitters() {
+        let mut scene = test_scene();
+        scene.lighting.lights.clear();
+        scene.lighting.ambient = Color::from_rgb(0.0, 0.0, 0.0);
+        scene.background = Color::from_rgb(0.0, 0.0, 0.0);
+        // A round light panel facing down onto the floor.
+        scene.replace_object(0, Box::from(SimpleObject {
+            solid: Box::from(Disk {
+                center: Point3 { x: 0.0, y: 1.0, z: 0.0 },
+                normal: Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+                radius: 0.5,
+            }),
+            material: Material::emissive(Arc::new(Color::from_rgb(10.0, 10.0, 10.0))),
+        }));
+        assert_eq!(scene.emitters(), &[0]);
+        let options = RenderOptions::default();
+
+        // The panel is visible from below, but not from above.
+        let up = Ray3::new(
+            Point3 { x: 0.1, y: 0.5, z: 0.0 },
+            Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+        );
+        let down = Ray3::new(
+            Point3 { x: 0.1, y: 2.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        let whitted = WhittedIntegrator::default();
+        assert_eq!(whitted.cast_ray(&scene, &options, &up, 0, &mut Rng::new(0)).r, 10.0);
+        assert_eq!(whitted.cast_ray(&scene, &options, &down, 0, &mut Rng::new(0)).r, 0.0);
+
+        // A disk of radius r at height h above a point on the floor gives an irradiance of
+        // PI * L * r^2 / (h^2 + r^2), which the floor reflects with its albedo.
+        let expected = 0.18 * 10.0 * 0.25 / 1.25;
+        let floor = Ray3::new(
+            Point3 { x: 0.0, y: 0.5, z: 1.0 },
+            Vector3 { x: 0.0, y: -0.5, z: -1.0 }.normalize(),
+        );
+        let path_tracer = PathTracingIntegrator::default();
+        let mut rng = Rng::new(4);
+        let samples = 4000;
+        let (mut whitted_mean, mut path_mean) = (0.0, 0.0);
+        for _ in 0..samples {
+            whitted_mean += whitted.cast_ray(&scene, &options, &floor, 0, &mut rng).r;
+            path_mean += path_tracer.trace_path(&scene, &options, &floor, &mut rng).r;
+        }
+        whitted_mean /= samples as f32;
+        path_mean /= samples as f32;
+        assert!((whitted_mean - expected).abs() < 0.02 * expected);
+        assert!((path_mean - expected).abs() < 0.03 * expected);
+
+        // Without bounces, the emitter is only reached by sampling it directly.
+        let path_tracer = PathTracingIntegrator { max_ray_depth: 0, ..path_tracer };
+        let path_mean = (0..samples)
+            .map(|_| path_tracer.trace_path(&scene, &options, &floor, &mut rng).r)
+            .sum::<f32>() / samples as f32;
+        assert!((path_mean - expected).abs() < 0.03 * expected);
+    }
+
+    #[test]
+    fn test_path_tracing_direct_light_at_max_depth() {
+        let scene = test_scene();
+        let options = RenderOptions::default();
+        let integrator = PathTracingIntegrator {
+            max_ray_depth: 0,
+            ..PathTracingIntegrator::default()
+        };
+        // With no bounces, the floor is still lit directly by the point light.
+        let ray = Ray3::new(
+            Point3 { x: 3.0, y: 1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        let hit = compute_scene_hit(&scene, &options, &ray).unwrap();
+        let expected = compute_light(&scene.lighting.lights[0], &scene, &options, &ray, &hit);
+        let color = integrator.trace_path(&scene, &options, &ray, &mut Rng::new(0));
+        assert!(expected.r > 0.0);
+        assert!((color.r - expected.r).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_path_tracing_diffuse_plane_under_sky() {
+        let mut scene = test_scene();
+        scene.remove_object(0);
+        scene.lighting.lights.clear();
+        scene.background = Color::from_rgb(1.0, 1.0, 1.0);
+        let options = RenderOptions::default();
+        let integrator = PathTracingIntegrator {
+            max_ray_depth: 4,
+            ..PathTracingIntegrator::default()
+        };
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        // A Lambertian surface lit by a uniform white sky reflects exactly its albedo.
+        let mut rng = Rng::new(3);
+        let samples = 20000;
+        let mean = (0..samples)
+            .map(|_| integrator.trace_path(&scene, &options, &ray, &mut rng).r)
+            .sum::<f32>() / samples as f32;
+        assert!((mean - 0.18).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_debug_integrators() {
+        let scene = test_scene();
+        let options = RenderOptions::default();
+        let mut rng = Rng::new(0);
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 1.0, z: 5.0 },
+            Vector3 { x: 0.0, y: 0.0, z: -1.0 },
+        );
+        let normal = DebugIntegrator::Normal.radiance(&scene, &options, &ray, &mut rng);
+        assert!((normal.r - 0.5).abs() < 1e-3);
+        assert!((normal.g - 0.5).abs() < 1e-3);
+        assert!((normal.b - 1.0).abs() < 1e-3);
+
+        let depth = DebugIntegrator::Depth { max_depth: 8.0 }
+            .radiance(&scene, &options, &ray, &mut rng);
+        assert!((depth.r - 0.5).abs() < 1e-3);
+
+        let sphere = DebugIntegrator::ObjectId.radiance(&scene, &options, &ray, &mut rng);
+        let floor_ray = Ray3::new(
+            Point3 { x: 3.0, y: 1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        let floor = DebugIntegrator::ObjectId.radiance(&scene, &options, &floor_ray, &mut rng);
+        assert_eq!((sphere.r, sphere.g, sphere.b), {
+            let c = object_id_color(0);
+            (c.r, c.g, c.b)
+        });
+        assert!((sphere.r, sphere.g, sphere.b) != (floor.r, floor.g, floor.b));
+    }
+
+    #[test]
+    fn test_ambient_occlusion() {
+        let mut scene = test_scene();
+        scene.remove_object(0);
+        let options = RenderOptions::default();
+        let integrator = AmbientOcclusionIntegrator {
+            max_distance: 1000.0,
+            ..AmbientOcclusionIntegrator::default()
+        };
+        let mut rng = Rng::new(0);
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        // Nothing but the floor itself, so nothing is occluded.
+        assert_eq!(integrator.radiance(&scene, &options, &ray, &mut rng).r, 1.0);
+
+        // A ceiling just above the floor occludes everything.
+        scene.add_object(Box::from(SimpleObject {
+            solid: Box::from(Plane {
+                n: Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+                d: 0.5,
+            }),
+            material: Material::new(),
+        }));
+        let ray = Ray3::new(
+            Point3 { x: 0.0, y: 0.25, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        assert_eq!(integrator.radiance(&scene, &options, &ray, &mut rng).r, 0.0);
+    }
+}
@@ -1,12 +1,24 @@
 extern crate cgmath;
 extern crate collision;
+extern crate png;
 extern crate sdl2;
 
+pub mod bvh;
 pub mod camera;
 pub mod color;
+pub mod csg;
 pub mod draw;
 pub mod frame;
+pub mod instance;
+pub mod integrator;
 pub mod light;
 pub mod material;
+pub mod mesh;
+pub mod obj;
+pub mod primitives;
+pub mod procedural;
+pub mod sampling;
+pub mod sdf;
 pub mod sdl;
+pub mod texture;
 pub mod trace;
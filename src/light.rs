@@ -1,5 +1,10 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
 use cgmath::*;
 use color::*;
+use texture::*;
+use trace::*;
 
 pub struct Lighting {
     pub lights: Vec<Light>,
@@ -22,12 +27,37 @@ impl Light {
         }
     }
 
-    pub fn directional_light(dir: Vector3<f32>) -> Self {
+    /// Creates a light infinitely far away whose rays all travel in direction `dir`, like the
+    /// sun. Unlike a point light, its `intensity` doesn't fall off with distance.
+    pub fn directional_light(dir: Vector3<f32>, intensity: Color) -> Self {
         Light{
             light_type: LightType::Directional(DirectionalLight{
                 direction: dir / dir.magnitude(),
             }),
-            intensity: Color::from_rgb(1.0, 1.0, 1.0),
+            intensity,
+        }
+    }
+
+    /// Creates a point light at `pos` that only shines within a cone around `dir`, like a stage
+    /// light. It's at full intensity within `inner_angle` of `dir`, and falls off smoothly to
+    /// nothing at `outer_angle`. Within the cone, it's as bright as a point light with the same
+    /// `intensity`. To project a gobo, set `gobo` on the `SpotLight`.
+    pub fn spot_light(
+        pos: Point3<f32>,
+        dir: Vector3<f32>,
+        inner_angle: Deg<f32>,
+        outer_angle: Deg<f32>,
+        intensity: Color,
+    ) -> Self {
+        Light{
+            light_type: LightType::Spot(SpotLight{
+                position: pos,
+                direction: dir / dir.magnitude(),
+                inner_angle,
+                outer_angle,
+                gobo: None,
+            }),
+            intensity,
         }
     }
 }
@@ -35,6 +65,7 @@ impl Light {
 pub enum LightType {
     Point(PointLight),
     Directional(DirectionalLight),
+    Spot(SpotLight),
 }
 
 pub struct PointLight {
@@ -42,5 +73,138 @@ pub struct PointLight {
 }
 
 pub struct DirectionalLight {
+    /// The normalized direction in which the light travels, i.e. pointing away from the light.
     pub direction: Vector3<f32>,
-}
\ No newline at end of file
+}
+
+pub struct SpotLight {
+    pub position: Point3<f32>,
+
+    /// The normalized direction that the light points in.
+    pub direction: Vector3<f32>,
+
+    /// The angle from `direction` within which the light is at full intensity.
+    pub inner_angle: Deg<f32>,
+
+    /// The angle from `direction` beyond which there's no light. Between `inner_angle` and
+    /// `outer_angle` the light falls off smoothly.
+    pub outer_angle: Deg<f32>,
+
+    /// An image that the light shines through, like a gobo (or cookie) in front of a stage light,
+    /// which tints the light in each direction. It covers the square that just contains the outer
+    /// cone, with `v` pointing as close to world up (+y) as possible, and is centered on `(0.5,
+    /// 0.5)`.
+    pub gobo: Option<Arc<Texture>>,
+}
+
+impl SpotLight {
+    /// Returns the fraction of the light's intensity, per channel, that it emits in the
+    /// normalized direction `direction`.
+    pub fn attenuation(&self, direction: Vector3<f32>) -> Color {
+        let black = Color::from_rgb(0.0, 0.0, 0.0);
+        let cos_angle = direction.dot(self.direction);
+        let cos_outer = Rad::from(self.outer_angle).0.cos();
+        let cos_inner = Rad::from(self.inner_angle).0.cos();
+        if cos_angle <= cos_outer {
+            return black;
+        }
+        let falloff = if cos_angle >= cos_inner {
+            1.0
+        } else {
+            let x = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+            x * x * (3.0 - 2.0 * x)
+        };
+        let gobo_color = match self.gobo {
+            Some(ref gobo) => gobo.color(&self.gobo_context(direction)),
+            None => Color::from_rgb(1.0, 1.0, 1.0),
+        };
+        gobo_color * falloff
+    }
+
+    /// Returns the context for looking up the gobo in the normalized direction `direction`, which
+    /// must be less than 90 degrees from `self.direction`.
+    fn gobo_context(&self, direction: Vector3<f32>) -> ShadingContext {
+        let up = if self.direction.y.abs() < 0.999 {
+            Vector3::unit_y()
+        } else {
+            Vector3::unit_z()
+        };
+        let right = self.direction.cross(up).normalize();
+        let up = right.cross(self.direction);
+        // Project the direction onto the plane a unit distance in front of the light.
+        let forward = direction.dot(self.direction);
+        let half_width = Rad::from(self.outer_angle).0.min(PI / 2.0 - 1e-3).tan();
+        let scale = 1.0 / (2.0 * half_width * forward);
+        ShadingContext::new(SolidHit {
+            t: 1.0,
+            point: self.position + direction,
+            normal: -direction,
+            uv: Vector2 {
+                x: 0.5 + direction.dot(right) * scale,
+                y: 0.5 + direction.dot(up) * scale,
+            },
+            dpdu: right * (2.0 * half_width),
+            dpdv: up * (2.0 * half_width),
+            front_face: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spot_light() -> SpotLight {
+        SpotLight {
+            position: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 },
+            inner_angle: Deg(20.0),
+            outer_angle: Deg(40.0),
+            gobo: None,
+        }
+    }
+
+    fn direction(angle: Deg<f32>) -> Vector3<f32> {
+        let (sin, cos) = Rad::from(angle).0.sin_cos();
+        Vector3 { x: sin, y: 0.0, z: -cos }
+    }
+
+    #[test]
+    fn test_spot_light_falloff() {
+        let light = spot_light();
+        assert_eq!(light.attenuation(direction(Deg(0.0))).r, 1.0);
+        assert_eq!(light.attenuation(direction(Deg(19.0))).r, 1.0);
+        assert_eq!(light.attenuation(direction(Deg(41.0))).r, 0.0);
+        assert_eq!(light.attenuation(direction(Deg(120.0))).r, 0.0);
+        // The falloff is smooth and decreasing between the inner and outer angles.
+        let mut previous = 1.0;
+        for i in 1..20 {
+            let attenuation = light.attenuation(direction(Deg(20.0 + i as f32))).r;
+            assert!(attenuation < previous && attenuation > 0.0);
+            assert!(previous - attenuation < 0.15);
+            previous = attenuation;
+        }
+        // Equal angles give a hard edge.
+        let hard = SpotLight { inner_angle: Deg(40.0), ..spot_light() };
+        assert_eq!(hard.attenuation(direction(Deg(39.0))).r, 1.0);
+        assert_eq!(hard.attenuation(direction(Deg(41.0))).r, 0.0);
+    }
+
+    #[test]
+    fn test_spot_light_gobo() {
+        // A gobo that's red on the left half and green on the right.
+        let image = Image::new(2, 1, vec![
+            Color::from_rgb(1.0, 0.0, 0.0),
+            Color::from_rgb(0.0, 1.0, 0.0),
+        ]);
+        let gobo = ImageTexture::new(image, TextureFilter::Nearest, WrapMode::Clamp);
+        let light = SpotLight { gobo: Some(Arc::new(gobo)), ..spot_light() };
+        // The light points along -z with +y up, so +x is on the right.
+        let left = light.attenuation(direction(Deg(-10.0)));
+        let right = light.attenuation(direction(Deg(10.0)));
+        assert_eq!((left.r, left.g), (1.0, 0.0));
+        assert_eq!((right.r, right.g), (0.0, 1.0));
+        // The gobo doesn't extend the cone.
+        assert_eq!(light.attenuation(direction(Deg(50.0))).g, 0.0);
+    }
+}
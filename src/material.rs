@@ -1,32 +1,130 @@
 use std::f32::consts::PI;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use color::*;
+use sampling::*;
+use texture::*;
 use cgmath::{
     Vector3,
     InnerSpace,
 };
 
-pub trait Shading {
+pub trait Shading: Send + Sync {
+    /// Returns the light reflected along `ray` from light arriving along `light` with the given
+    /// intensity. `context` is the point being shaded, at which any textures are evaluated.
     fn brdf(
         &self,
+        context: &ShadingContext,
         ray: &Vector3<f32>,
         light: &Vector3<f32>,
         intensity: &Color,
-        normal:
-        &Vector3<f32>
+        normal: &Vector3<f32>
     ) -> Color;
+
+    /// Returns the unlit color of the surface at `context`. The default implementation returns
+    /// the fraction of light arriving along the normal that's reflected back along the normal,
+    /// scaled so that it's exact for diffuse surfaces.
+    fn albedo(&self, context: &ShadingContext) -> Color {
+        let normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        self.brdf(context, &normal, &normal, &Color::from_rgb(1.0, 1.0, 1.0), &normal) * PI
+    }
+
+    /// Draws a direction for light arriving at the surface at `context` to be reflected along
+    /// `ray`, for Monte Carlo integration of the reflected light. Directions that reflect more
+    /// light should be drawn more often. Returns `None` if the drawn direction is below the
+    /// surface, which `pdf` must account for. The default implementation draws directions with
+    /// density proportional to the cosine of the angle to `normal`, which is exact for diffuse
+    /// surfaces.
+    fn sample(
+        &self,
+        context: &ShadingContext,
+        ray: &Vector3<f32>,
+        normal: &Vector3<f32>,
+        rng: &mut Rng,
+    ) -> Option<ShadingSample> {
+        let light = cosine_sample_hemisphere(*normal, rng.next_f32(), rng.next_f32());
+        shading_sample(self, context, ray, light, normal)
+    }
+
+    /// Returns the probability density, with respect to solid angle, with which `sample` draws
+    /// `light`. This must match `sample`.
+    fn pdf(
+        &self,
+        _context: &ShadingContext,
+        _ray: &Vector3<f32>,
+        light: &Vector3<f32>,
+        normal: &Vector3<f32>,
+    ) -> f32 {
+        light.dot(*normal).max(0.0) / PI
+    }
+
+    /// Returns the light that passes through the surface at `context` rather than being
+    /// reflected, if any. The default implementation returns `None`: the surface is opaque.
+    fn transmission(&self, _context: &ShadingContext) -> Option<Transmission> {
+        None
+    }
+}
+
+/// Returns the sample for the direction `light`, as drawn by `shading.sample`, or `None` if it's
+/// below the surface.
+fn shading_sample<S: Shading + ?Sized>(
+    shading: &S,
+    context: &ShadingContext,
+    ray: &Vector3<f32>,
+    light: Vector3<f32>,
+    normal: &Vector3<f32>,
+) -> Option<ShadingSample> {
+    let pdf = shading.pdf(context, ray, &light, normal);
+    if light.dot(*normal) <= 0.0 || pdf <= 0.0 {
+        return None;
+    }
+    let white = Color::from_rgb(1.0, 1.0, 1.0);
+    Some(ShadingSample {
+        light,
+        pdf,
+        value: shading.brdf(context, ray, &light, &white, normal),
+    })
+}
+
+/// A direction drawn by `Shading::sample`.
+#[derive(Copy, Clone, Debug)]
+pub struct ShadingSample {
+    /// The direction that light arrives from, pointing away from the surface.
+    pub light: Vector3<f32>,
+
+    /// The probability density, with respect to solid angle, of drawing `light`.
+    pub pdf: f32,
+
+    /// The light reflected along the ray from white light arriving along `light`, as returned by
+    /// `Shading::brdf`. A Monte Carlo estimate of the reflected light weights the light arriving
+    /// along `light` by `value / pdf`.
+    pub value: Color,
+}
+
+/// Light that refracts through a partly transparent surface, in addition to the light that its
+/// `Shading::brdf` reflects.
+#[derive(Copy, Clone, Debug)]
+pub struct Transmission {
+    /// The fraction of light, per channel, that is transmitted, already evaluated at the point
+    /// being shaded, so shadings can texture it however they like. Integrators split this light
+    /// between the reflected and refracted directions according to `dielectric`, like they do for
+    /// `Material::dielectric`.
+    pub color: Color,
+
+    /// The boundary that the light refracts through.
+    pub dielectric: Dielectric,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct SimpleDiffuseShading {
-    pub diffuse_color: Color,
+    pub diffuse_color: Arc<Texture>,
     pub albedo: f32,
 }
 
 impl Shading for SimpleDiffuseShading {
     fn brdf(
         &self,
+        context: &ShadingContext,
         ray: &Vector3<f32>,
         light: &Vector3<f32>,
         intensity: &Color,
@@ -36,20 +134,21 @@ impl Shading for SimpleDiffuseShading {
         if z < 0.0 {
             z = 0.0;
         }
-        *intensity * self.diffuse_color * self.albedo / PI * z
+        *intensity * self.diffuse_color.color(context) * self.albedo / PI * z
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct PhongShading {
-    pub diffuse_color: Color,
-    pub specular_color: Color,
+    pub diffuse_color: Arc<Texture>,
+    pub specular_color: Arc<Texture>,
     pub specular_exponent: i32,
 }
 
 impl Shading for PhongShading {
     fn brdf(
         &self,
+        context: &ShadingContext,
         ray: &Vector3<f32>,
         light: &Vector3<f32>,
         intensity: &Color,
@@ -59,28 +158,723 @@ impl Shading for PhongShading {
         if z < 0.0 {
             z = 0.0;
         }
-        let diffuse = self.diffuse_color / PI * z;
+        let diffuse = self.diffuse_color.color(context) / PI * z;
+        if z == 0.0 {
+            return *intensity * diffuse;
+        }
 
-        let v = -*ray;
         let r = 2.0 * (normal.dot(*light)) * normal - light;
-        let specular = self.specular_color * (v.dot(r).powi(self.specular_exponent));
+        let specular = self.specular_color.color(context)
+            * (ray.dot(r).max(0.0).powi(self.specular_exponent));
 
         *intensity * (diffuse + specular)
     }
+
+    fn albedo(&self, context: &ShadingContext) -> Color {
+        self.diffuse_color.color(context)
+    }
+
+    /// Draws directions from either the diffuse lobe or the specular lobe around the mirror
+    /// direction, choosing between them according to roughly how much light each reflects.
+    fn sample(
+        &self,
+        context: &ShadingContext,
+        ray: &Vector3<f32>,
+        normal: &Vector3<f32>,
+        rng: &mut Rng,
+    ) -> Option<ShadingSample> {
+        let specular_probability = self.specular_probability(context);
+        let (u1, u2) = (rng.next_f32(), rng.next_f32());
+        let light = if rng.next_f32() < specular_probability {
+            let mirror = 2.0 * normal.dot(*ray) * normal - ray;
+            sample_phong_lobe(mirror, self.specular_exponent as f32, u1, u2)
+        } else {
+            cosine_sample_hemisphere(*normal, u1, u2)
+        };
+        shading_sample(self, context, ray, light, normal)
+    }
+
+    fn pdf(
+        &self,
+        context: &ShadingContext,
+        ray: &Vector3<f32>,
+        light: &Vector3<f32>,
+        normal: &Vector3<f32>,
+    ) -> f32 {
+        let cos_theta = light.dot(*normal);
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let specular_probability = self.specular_probability(context);
+        let exponent = self.specular_exponent as f32;
+        let mirror = 2.0 * normal.dot(*ray) * normal - ray;
+        let specular_pdf = (exponent + 1.0) / (2.0 * PI)
+            * mirror.dot(*light).max(0.0).powf(exponent);
+        (1.0 - specular_probability) * cos_theta / PI + specular_probability * specular_pdf
+    }
+}
+
+impl PhongShading {
+    /// Returns the probability with which `sample` draws from the specular lobe: the fraction of
+    /// light reflected by the specular lobe, roughly, for a direction near the normal.
+    fn specular_probability(&self, context: &ShadingContext) -> f32 {
+        let max_component = |color: Color| color.r.max(color.g).max(color.b).max(0.0);
+        let diffuse = max_component(self.diffuse_color.color(context));
+        let exponent = self.specular_exponent as f32;
+        let specular =
+            max_component(self.specular_color.color(context)) * 2.0 * PI / (exponent + 1.0);
+        if diffuse + specular > 0.0 {
+            specular / (diffuse + specular)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A physically based shading model: a diffuse base plus specular reflection from a rough
+/// surface, modeled as microscopic mirror facets oriented according to the GGX distribution, with
+/// Smith masking-shadowing and Schlick's approximation to the Fresnel equations. This is the
+/// "metallic/roughness" model used by most PBR tools and asset formats.
+#[derive(Clone)]
+pub struct GgxShading {
+    /// The diffuse color of dielectrics, or the specular color of metals.
+    pub base_color: Arc<Texture>,
+
+    /// The perceptual roughness, from 0 (a perfect mirror) to 1. The GGX width parameter alpha is
+    /// its square.
+    pub roughness: f32,
+
+    /// How metallic the surface is, from 0 (a dielectric) to 1 (a metal). Values in between blend
+    /// the two.
+    pub metallic: f32,
+
+    /// The fraction of light reflected at normal incidence (F0) by the dielectric part of the
+    /// surface. 0.04 suits most dielectrics.
+    pub dielectric_reflectance: f32,
+}
+
+impl GgxShading {
+    pub fn new(base_color: Arc<Texture>, roughness: f32, metallic: f32) -> Self {
+        GgxShading {
+            base_color,
+            roughness,
+            metallic,
+            dielectric_reflectance: 0.04,
+        }
+    }
+
+    /// Returns the GGX width parameter, kept away from 0 so that the distribution stays finite.
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-3)
+    }
+}
+
+/// Returns the GGX (Trowbridge-Reitz) density of microfacets with normals at an angle with cosine
+/// `cos_theta` to the surface normal.
+pub fn ggx_distribution(cos_theta: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let denominator = cos_theta * cos_theta * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * denominator * denominator)
+}
+
+/// Returns the fraction of microfacets visible from a direction at an angle with cosine `cos_theta`
+/// to the surface normal, according to Smith's GGX masking function.
+pub fn smith_ggx_masking(cos_theta: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    2.0 * cos_theta / (cos_theta + (alpha2 + (1.0 - alpha2) * cos_theta * cos_theta).sqrt())
+}
+
+/// Returns Schlick's approximation to the fraction of light reflected at an angle with cosine
+/// `cos_theta`, given the fraction `f0` reflected at normal incidence.
+pub fn fresnel_schlick(cos_theta: f32, f0: Color) -> Color {
+    let white = Color::from_rgb(1.0, 1.0, 1.0);
+    f0 + (white - f0) * (1.0 - cos_theta.max(0.0).min(1.0)).powi(5)
+}
+
+impl Shading for GgxShading {
+    fn brdf(
+        &self,
+        context: &ShadingContext,
+        ray: &Vector3<f32>,
+        light: &Vector3<f32>,
+        intensity: &Color,
+        normal: &Vector3<f32>
+    ) -> Color {
+        let (cos_view, cos_light) = (ray.dot(*normal), light.dot(*normal));
+        if cos_view <= 0.0 || cos_light <= 0.0 {
+            return Color::from_rgb(0.0, 0.0, 0.0);
+        }
+        let half = (*ray + *light).normalize();
+        let alpha = self.alpha();
+        let base_color = self.base_color.color(context);
+        let dielectric_f0 = Color::from_rgb(
+            self.dielectric_reflectance,
+            self.dielectric_reflectance,
+            self.dielectric_reflectance,
+        );
+        let f0 = dielectric_f0 * (1.0 - self.metallic) + base_color * self.metallic;
+        let fresnel = fresnel_schlick(half.dot(*light), f0);
+
+        let specular = fresnel
+            * (ggx_distribution(half.dot(*normal), alpha)
+                * smith_ggx_masking(cos_view, alpha)
+                * smith_ggx_masking(cos_light, alpha)
+                / (4.0 * cos_view * cos_light));
+        // Light that isn't reflected by the facets enters the surface, where metals absorb it, and
+        // must leave it again through the boundary in order to be seen.
+        let white = Color::from_rgb(1.0, 1.0, 1.0);
+        let diffuse = (white - fresnel_schlick(cos_light, f0))
+            * (white - fresnel_schlick(cos_view, f0))
+            * base_color
+            * ((1.0 - self.metallic) / PI);
+        *intensity * (diffuse + specular) * cos_light
+    }
+
+    fn albedo(&self, context: &ShadingContext) -> Color {
+        self.base_color.color(context)
+    }
+}
+
+/// Returns the fraction of light reflected at an angle with cosine `cos_theta` according to
+/// Schlick's approximation, minus the fraction reflected at normal incidence: the weight with which
+/// Schlick's approximation blends from F0 to white.
+fn schlick_weight(cos_theta: f32) -> f32 {
+    (1.0 - cos_theta.max(0.0).min(1.0)).powi(5)
+}
+
+/// Returns the GTR1 (Berry) density of microfacets with normals at an angle with cosine
+/// `cos_theta` to the surface normal, which has a longer tail than GGX.
+fn gtr1_distribution(cos_theta: f32, alpha: f32) -> f32 {
+    if alpha >= 1.0 {
+        return 1.0 / PI;
+    }
+    let alpha2 = alpha * alpha;
+    let denominator = 1.0 + (alpha2 - 1.0) * cos_theta * cos_theta;
+    (alpha2 - 1.0) / (PI * alpha2.ln() * denominator)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn lerp_colors(a: Color, b: Color, t: f32) -> Color {
+    a * (1.0 - t) + b * t
+}
+
+/// The "principled" shading model published by Disney (Burley 2012, extended with transmission in
+/// 2015), which covers most real-world materials with a few intuitive parameters. Apart from
+/// `index_of_refraction`, all of the parameters range from 0 to 1; `Default` gives a plastic-like
+/// light gray dielectric.
+#[derive(Clone)]
+pub struct PrincipledShading {
+    /// The diffuse color of dielectrics, or the specular color of metals.
+    pub base_color: Arc<Texture>,
+
+    /// Blends the diffuse lobe towards an approximation of subsurface scattering, which flattens
+    /// it, and brightens its edges, like skin, wax or milk.
+    pub subsurface: f32,
+
+    /// How metallic the surface is. Metals have no diffuse, sheen or transmission lobes.
+    pub metallic: f32,
+
+    /// The specular reflectance of the dielectric part of the surface at normal incidence, mapped
+    /// linearly to 0-8%. The default of 0.5 (4%) suits most dielectrics.
+    pub specular: f32,
+
+    /// Tints the dielectric specular reflection towards the base color.
+    pub specular_tint: f32,
+
+    /// The perceptual roughness of the specular lobe. Transmitted light is always refracted as
+    /// if by a smooth surface.
+    pub roughness: f32,
+
+    /// The strength of an additional grazing reflection, like that of cloth.
+    pub sheen: f32,
+
+    /// Tints the sheen towards the base color.
+    pub sheen_tint: f32,
+
+    /// The strength of a second, colorless specular layer, like a varnish, over the surface.
+    pub clearcoat: f32,
+
+    /// The glossiness of the clearcoat, from satin (0) to gloss (1).
+    pub clearcoat_gloss: f32,
+
+    /// How much of the light that enters the dielectric part of the surface passes through it,
+    /// like glass, instead of being scattered back out by the diffuse lobe.
+    pub transmission: f32,
+
+    /// The index of refraction of the surface's interior, used for transmission.
+    pub index_of_refraction: f32,
+}
+
+impl Default for PrincipledShading {
+    fn default() -> Self {
+        PrincipledShading {
+            base_color: Arc::new(Color::from_rgb(0.8, 0.8, 0.8)),
+            subsurface: 0.0,
+            metallic: 0.0,
+            specular: 0.5,
+            specular_tint: 0.0,
+            roughness: 0.5,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            index_of_refraction: 1.5,
+        }
+    }
+}
+
+impl PrincipledShading {
+    /// Returns the base color with its luminance normalized, which isolates its hue and
+    /// saturation for tinting.
+    fn tint(base_color: Color) -> Color {
+        let luminance = 0.3 * base_color.r + 0.6 * base_color.g + 0.1 * base_color.b;
+        if luminance > 0.0 {
+            base_color / luminance
+        } else {
+            Color::from_rgb(1.0, 1.0, 1.0)
+        }
+    }
+}
+
+impl Shading for PrincipledShading {
+    fn brdf(
+        &self,
+        context: &ShadingContext,
+        ray: &Vector3<f32>,
+        light: &Vector3<f32>,
+        intensity: &Color,
+        normal: &Vector3<f32>
+    ) -> Color {
+        let (cos_view, cos_light) = (ray.dot(*normal), light.dot(*normal));
+        if cos_view <= 0.0 || cos_light <= 0.0 {
+            return Color::from_rgb(0.0, 0.0, 0.0);
+        }
+        let white = Color::from_rgb(1.0, 1.0, 1.0);
+        let half = (*ray + *light).normalize();
+        let (cos_half, cos_difference) = (half.dot(*normal), half.dot(*light));
+        let base_color = self.base_color.color(context);
+        let tint = PrincipledShading::tint(base_color);
+
+        // The diffuse lobe gets darker at grazing angles on smooth surfaces and brighter on rough
+        // ones, as light is reflected from the boundary or retroreflected by its bumps.
+        let (weight_light, weight_view) = (schlick_weight(cos_light), schlick_weight(cos_view));
+        let roughness_term = self.roughness * cos_difference * cos_difference;
+        let diffuse_90 = 0.5 + 2.0 * roughness_term;
+        let diffuse = lerp(1.0, diffuse_90, weight_light) * lerp(1.0, diffuse_90, weight_view);
+        // Hanrahan-Krueger-like subsurface approximation.
+        let subsurface = 1.25
+            * (lerp(1.0, roughness_term, weight_light) * lerp(1.0, roughness_term, weight_view)
+                * (1.0 / (cos_light + cos_view) - 0.5)
+                + 0.5);
+        let weight_difference = schlick_weight(cos_difference);
+        let sheen = lerp_colors(white, tint, self.sheen_tint) * (self.sheen * weight_difference);
+        let diffuse = base_color * (lerp(diffuse, subsurface, self.subsurface) / PI) + sheen;
+
+        let alpha = (self.roughness * self.roughness).max(1e-3);
+        let dielectric_f0 = lerp_colors(white, tint, self.specular_tint) * (self.specular * 0.08);
+        let f0 = lerp_colors(dielectric_f0, base_color, self.metallic);
+        let specular = lerp_colors(f0, white, weight_difference)
+            * (ggx_distribution(cos_half, alpha)
+                * smith_ggx_masking(cos_view, alpha)
+                * smith_ggx_masking(cos_light, alpha)
+                / (4.0 * cos_view * cos_light));
+
+        let clearcoat_alpha = lerp(0.1, 0.001, self.clearcoat_gloss);
+        let clearcoat = 0.25
+            * self.clearcoat
+            * lerp(0.04, 1.0, weight_difference)
+            * gtr1_distribution(cos_half, clearcoat_alpha)
+            * smith_ggx_masking(cos_view, 0.25)
+            * smith_ggx_masking(cos_light, 0.25)
+            / (4.0 * cos_view * cos_light);
+
+        let diffuse_weight = (1.0 - self.metallic) * (1.0 - self.transmission);
+        *intensity * (diffuse * diffuse_weight + specular + white * clearcoat) * cos_light
+    }
+
+    fn albedo(&self, context: &ShadingContext) -> Color {
+        self.base_color.color(context)
+    }
+
+    fn transmission(&self, context: &ShadingContext) -> Option<Transmission> {
+        let weight = (1.0 - self.metallic) * self.transmission;
+        if weight <= 0.0 {
+            return None;
+        }
+        Some(Transmission {
+            color: self.base_color.color(context) * weight,
+            dielectric: Dielectric { index_of_refraction: self.index_of_refraction },
+        })
+    }
+}
+
+/// A transparent material such as glass or water, which refracts the light that it doesn't
+/// reflect.
+#[derive(Copy, Clone, Debug)]
+pub struct Dielectric {
+    /// The index of refraction of the material's interior relative to its exterior, which is
+    /// assumed to be air. Typical values are 1.33 for water and 1.5 for glass.
+    pub index_of_refraction: f32,
+}
+
+/// Returns the fraction of light reflected at a smooth boundary between two dielectrics, using the
+/// exact Fresnel equations for unpolarized light. `cos_incident` is the cosine of the angle
+/// between the incident direction and the surface normal; `eta_incident` and `eta_transmitted`
+/// are the indices of refraction on the incident and transmitted sides of the boundary. Returns
+/// 1.0 in the case of total internal reflection.
+pub fn fresnel_dielectric(cos_incident: f32, eta_incident: f32, eta_transmitted: f32) -> f32 {
+    let cos_incident = cos_incident.max(0.0).min(1.0);
+    let sin_incident = (1.0 - cos_incident * cos_incident).max(0.0).sqrt();
+    let sin_transmitted = eta_incident / eta_transmitted * sin_incident;
+    if sin_transmitted >= 1.0 {
+        return 1.0;
+    }
+    let cos_transmitted = (1.0 - sin_transmitted * sin_transmitted).max(0.0).sqrt();
+    let parallel = (eta_transmitted * cos_incident - eta_incident * cos_transmitted)
+        / (eta_transmitted * cos_incident + eta_incident * cos_transmitted);
+    let perpendicular = (eta_incident * cos_incident - eta_transmitted * cos_transmitted)
+        / (eta_incident * cos_incident + eta_transmitted * cos_transmitted);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
 }
 
 #[derive(Clone)]
 pub struct Material {
-    pub shading: Rc<Shading>,
+    pub shading: Arc<Shading>,
+
+    /// The fraction of light, per channel, that is mirrored by the surface. The color of a hit is
+    /// `reflectance` times the color seen along the reflected ray, plus `1 - reflectance` times the
+    /// color computed from `shading`.
+    pub reflectance: Arc<Texture>,
+
+    /// If set, the surface is transparent: `shading` and `reflectance` are ignored, and the color
+    /// of a hit is made up of reflected and refracted light, split according to the Fresnel
+    /// equations.
+    pub dielectric: Option<Dielectric>,
+
+    /// The light emitted by the surface, if any, from the side that its normal points out of, in
+    /// addition to the light that it reflects. Objects with emissive materials act as area lights.
+    pub emission: Option<Arc<Texture>>,
 }
 
 impl Material {
     pub fn new() -> Self {
         Material {
-            shading: Rc::from(SimpleDiffuseShading {
-                diffuse_color: Color::from_rgb(1.0, 1.0, 1.0),
+            shading: Arc::from(SimpleDiffuseShading {
+                diffuse_color: Arc::new(Color::from_rgb(1.0, 1.0, 1.0)),
                 albedo: 0.18,
             }),
+            reflectance: Arc::new(Color::from_rgb(0.0, 0.0, 0.0)),
+            dielectric: None,
+            emission: None,
+        }
+    }
+
+    /// Creates a material that emits `emission` and is otherwise black.
+    pub fn emissive(emission: Arc<Texture>) -> Self {
+        Material {
+            shading: Arc::from(SimpleDiffuseShading {
+                diffuse_color: Arc::new(Color::from_rgb(0.0, 0.0, 0.0)),
+                albedo: 0.0,
+            }),
+            emission: Some(emission),
+            ..Material::new()
+        }
+    }
+
+    /// Returns `true` if the material emits light.
+    pub fn is_emissive(&self) -> bool {
+        self.emission.is_some()
+    }
+
+    /// Creates a transparent material with the given index of refraction.
+    pub fn dielectric(index_of_refraction: f32) -> Self {
+        Material {
+            dielectric: Some(Dielectric { index_of_refraction }),
+            ..Material::new()
+        }
+    }
+
+    /// Returns the unlit color of the material at `context`, taking its reflectance into account.
+    /// Transparent materials are white.
+    pub fn albedo(&self, context: &ShadingContext) -> Color {
+        if self.dielectric.is_some() {
+            return Color::from_rgb(1.0, 1.0, 1.0);
+        }
+        let reflectance = self.reflectance.color(context);
+        self.shading.albedo(context) * (Color::from_rgb(1.0, 1.0, 1.0) - reflectance) + reflectance
+    }
+
+    /// Returns a copy of this material that reflects the same fraction `reflectance` of light in
+    /// every channel.
+    pub fn with_reflectance(&self, reflectance: f32) -> Self {
+        Material {
+            reflectance: Arc::new(Color::from_rgb(reflectance, reflectance, reflectance)),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::{
+        Point3,
+        Vector2,
+    };
+    use trace::*;
+
+    #[test]
+    fn test_fresnel_dielectric() {
+        // At normal incidence the reflectance is ((n1 - n2) / (n1 + n2))^2.
+        assert!((fresnel_dielectric(1.0, 1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert!((fresnel_dielectric(1.0, 1.5, 1.0) - 0.04).abs() < 1e-6);
+        // At grazing incidence all light is reflected.
+        assert!((fresnel_dielectric(0.0, 1.0, 1.5) - 1.0).abs() < 1e-6);
+        // Beyond the critical angle (about 41.8 degrees for glass) light is totally internally
+        // reflected.
+        assert_eq!(fresnel_dielectric(0.5, 1.5, 1.0), 1.0);
+        assert!(fresnel_dielectric(0.8, 1.5, 1.0) < 1.0);
+    }
+
+    fn shading_context() -> ShadingContext {
+        ShadingContext::new(SolidHit {
+            t: 1.0,
+            point: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            normal: Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            uv: Vector2 { x: 0.0, y: 0.0 },
+            dpdu: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            dpdv: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            front_face: true,
+        })
+    }
+
+    /// Returns the direction at polar angle `theta` and azimuth `phi` around +z.
+    fn direction(theta: f32, phi: f32) -> Vector3<f32> {
+        Vector3 { x: theta.sin() * phi.cos(), y: theta.sin() * phi.sin(), z: theta.cos() }
+    }
+
+    /// Returns the fraction of light arriving along `view` that `shading` reflects in any
+    /// direction, integrating over the hemisphere with the midpoint rule.
+    fn reflected_fraction(shading: &Shading, view: Vector3<f32>) -> Color {
+        let (context, white) = (shading_context(), Color::from_rgb(1.0, 1.0, 1.0));
+        let normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        let steps = 200;
+        let (d_theta, d_phi) = (PI / 2.0 / steps as f32, 2.0 * PI / steps as f32);
+        let mut total = Color::from_rgb(0.0, 0.0, 0.0);
+        for i in 0..steps {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..steps {
+                let light = direction(theta, (j as f32 + 0.5) * d_phi);
+                // By reciprocity, this is also the light reflected towards `light`.
+                total = total + shading.brdf(&context, &view, &light, &white, &normal)
+                    * (theta.sin() * d_theta * d_phi);
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn test_ggx_conserves_energy() {
+        let white: Arc<Texture> = Arc::new(Color::from_rgb(1.0, 1.0, 1.0));
+        for &roughness in &[0.3, 0.6, 1.0] {
+            for &metallic in &[0.0, 1.0] {
+                let shading = GgxShading::new(white.clone(), roughness, metallic);
+                for &theta in &[0.0, 1.0, 1.4] {
+                    let reflected = reflected_fraction(&shading, direction(theta, 0.3)).r;
+                    assert!(reflected <= 1.0 + 1e-2, "{} {} {}", roughness, metallic, reflected);
+                    // Microfacet models lose some energy to multiple scattering between facets, but
+                    // not much for a moderately rough white surface seen head on.
+                    if theta == 0.0 && roughness < 1.0 {
+                        assert!(reflected > 0.75, "{} {} {}", roughness, metallic, reflected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ggx_reciprocity_and_highlight() {
+        let shading = GgxShading::new(Arc::new(Color::from_rgb(1.0, 0.5, 0.0)), 0.4, 1.0);
+        let (context, white) = (shading_context(), Color::from_rgb(1.0, 1.0, 1.0));
+        let normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        let (a, b) = (direction(0.3, 0.0), direction(0.9, 2.0));
+        // `brdf` includes the cosine of the light direction.
+        let forward = shading.brdf(&context, &a, &b, &white, &normal) / b.z;
+        let backward = shading.brdf(&context, &b, &a, &white, &normal) / a.z;
+        assert!((forward.r - backward.r).abs() < 1e-4 * forward.r.max(1.0));
+
+        // A metal's highlight, in the mirror direction, takes on its color, and is much brighter
+        // than light reflected elsewhere.
+        let (view, mirror) = (direction(0.5, 0.0), direction(0.5, PI));
+        let highlight = shading.brdf(&context, &view, &mirror, &white, &normal);
+        assert!((highlight.g / highlight.r - 0.5).abs() < 0.1);
+        assert!(highlight.b < 0.01 * highlight.r);
+        let elsewhere = shading.brdf(&context, &view, &direction(0.5, 1.0), &white, &normal);
+        assert!(highlight.r > 10.0 * elsewhere.r);
+        // Nothing is reflected from below the surface.
+        let below = Vector3 { x: 0.0, y: 0.0, z: -1.0 };
+        assert_eq!(shading.brdf(&context, &view, &below, &white, &normal).r, 0.0);
+    }
+
+    #[test]
+    fn test_principled_shading() {
+        let context = shading_context();
+        let white = Color::from_rgb(1.0, 1.0, 1.0);
+        let normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        let base_color: Arc<Texture> = Arc::new(Color::from_rgb(0.9, 0.5, 0.2));
+        let variants = vec![
+            PrincipledShading { base_color: base_color.clone(), ..Default::default() },
+            PrincipledShading { metallic: 1.0, roughness: 0.3, ..Default::default() },
+            PrincipledShading { subsurface: 1.0, sheen: 1.0, ..Default::default() },
+            PrincipledShading { clearcoat: 1.0, clearcoat_gloss: 0.5, ..Default::default() },
+            PrincipledShading { transmission: 1.0, roughness: 0.1, ..Default::default() },
+        ];
+        for shading in &variants {
+            // The model isn't exactly energy conserving at grazing angles, where its diffuse
+            // retroreflection and sheen are strongest, so only check it elsewhere.
+            for &theta in &[0.0, 1.0] {
+                let reflected = reflected_fraction(shading, direction(theta, 0.3));
+                assert!(reflected.r <= 1.0 + 1e-2 && reflected.r > 0.0);
+            }
+            let (a, b) = (direction(0.3, 0.0), direction(0.9, 2.0));
+            let forward = shading.brdf(&context, &a, &b, &white, &normal) / b.z;
+            let backward = shading.brdf(&context, &b, &a, &white, &normal) / a.z;
+            assert!((forward.g - backward.g).abs() < 1e-4 * forward.g.max(1.0));
+        }
+
+        // The default diffuse lobe takes on the base color, but the specular highlight of a
+        // dielectric doesn't.
+        let (view, mirror) = (direction(0.5, 0.0), direction(0.5, PI));
+        let diffuse = variants[0].brdf(&context, &view, &direction(0.5, 1.5), &white, &normal);
+        assert!((diffuse.b / diffuse.r - 0.2 / 0.9).abs() < 0.05);
+        let smooth = PrincipledShading { roughness: 0.05, ..variants[0].clone() };
+        let highlight = smooth.brdf(&context, &view, &mirror, &white, &normal) - diffuse;
+        assert!((highlight.b / highlight.r - 1.0).abs() < 0.05);
+
+        // Only the dielectric parts of the surface transmit light.
+        assert!(variants[0].transmission(&context).is_none());
+        let transmission = variants[4].transmission(&context).unwrap();
+        assert_eq!(transmission.dielectric.index_of_refraction, 1.5);
+        assert!((transmission.color.r - 0.8).abs() < 1e-6);
+        let metal = PrincipledShading { metallic: 1.0, ..variants[4].clone() };
+        assert!(metal.transmission(&context).is_none());
+    }
+
+    /// Checks that the directions drawn by `shading.sample` are distributed according to
+    /// `shading.pdf`, using a chi-squared test on a histogram over the hemisphere, and that
+    /// weighting them by `value / pdf` gives an unbiased estimate of the reflected light.
+    fn check_sampling(shading: &Shading, view: Vector3<f32>) {
+        let context = shading_context();
+        let normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        // Bins of equal solid angle, uniform in cos(theta) and phi.
+        let (cos_bins, phi_bins) = (10, 16);
+        let bin = |direction: Vector3<f32>| {
+            let cos_bin = ((direction.z * cos_bins as f32) as usize).min(cos_bins - 1);
+            let phi = direction.y.atan2(direction.x) + PI;
+            let phi_bin = ((phi / (2.0 * PI) * phi_bins as f32) as usize).min(phi_bins - 1);
+            cos_bin * phi_bins + phi_bin
+        };
+
+        let mut rng = Rng::new(7);
+        let samples = 200000;
+        let mut observed = vec![0.0; cos_bins * phi_bins];
+        let mut estimate = Color::from_rgb(0.0, 0.0, 0.0);
+        for _ in 0..samples {
+            if let Some(sample) = shading.sample(&context, &view, &normal, &mut rng) {
+                assert!(sample.light.z > 0.0);
+                assert!((sample.light.magnitude() - 1.0).abs() < 1e-4);
+                assert!((sample.pdf - shading.pdf(&context, &view, &sample.light, &normal)).abs()
+                    < 1e-4 * sample.pdf);
+                observed[bin(sample.light)] += 1.0;
+                estimate = estimate + sample.value / sample.pdf;
+            }
+        }
+
+        // Integrate the pdf over each bin with the midpoint rule.
+        let steps = 16;
+        let mut expected = vec![0.0; cos_bins * phi_bins];
+        let d_cos = 1.0 / (cos_bins * steps) as f32;
+        let d_phi = 2.0 * PI / (phi_bins * steps) as f32;
+        for i in 0..cos_bins * steps {
+            let cos_theta = (i as f32 + 0.5) * d_cos;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            for j in 0..phi_bins * steps {
+                let phi = (j as f32 + 0.5) * d_phi - PI;
+                let light = Vector3 {
+                    x: sin_theta * phi.cos(),
+                    y: sin_theta * phi.sin(),
+                    z: cos_theta,
+                };
+                expected[bin(light)] +=
+                    shading.pdf(&context, &view, &light, &normal) * d_cos * d_phi * samples as f32;
+            }
+        }
+
+        let (mut chi_squared, mut degrees_of_freedom) = (0.0, 0);
+        for (observed, expected) in observed.iter().zip(expected.iter()) {
+            if *expected >= 5.0 {
+                chi_squared += (observed - expected) * (observed - expected) / expected;
+                degrees_of_freedom += 1;
+            } else {
+                assert!(*observed < 20.0);
+            }
+        }
+        // The chi-squared statistic has mean `degrees_of_freedom` and standard deviation
+        // `sqrt(2 * degrees_of_freedom)`.
+        let limit = degrees_of_freedom as f32 + 5.0 * (2.0 * degrees_of_freedom as f32).sqrt();
+        assert!(chi_squared < limit, "{} >= {}", chi_squared, limit);
+
+        let estimate = estimate / samples as f32;
+        let reference = reflected_fraction(shading, view);
+        assert!((estimate.r - reference.r).abs() < 0.02 * reference.r.max(0.1));
+        assert!((estimate.b - reference.b).abs() < 0.02 * reference.b.max(0.1));
+    }
+
+    #[test]
+    fn test_diffuse_sampling() {
+        let shading = SimpleDiffuseShading {
+            diffuse_color: Arc::new(Color::from_rgb(1.0, 0.5, 0.2)),
+            albedo: 0.8,
+        };
+        check_sampling(&shading, direction(0.7, 0.4));
+    }
+
+    #[test]
+    fn test_phong_sampling() {
+        let shading = PhongShading {
+            diffuse_color: Arc::new(Color::from_rgb(0.5, 0.3, 0.1)),
+            specular_color: Arc::new(Color::from_rgb(0.3, 0.3, 0.3)),
+            specular_exponent: 10,
+        };
+        for &theta in &[0.0, 0.7, 1.3] {
+            check_sampling(&shading, direction(theta, 0.4));
+        }
+        // Mostly specular surfaces draw most directions near the mirror direction.
+        let shiny = PhongShading {
+            diffuse_color: Arc::new(Color::from_rgb(0.05, 0.05, 0.05)),
+            specular_exponent: 50,
+            ..shading.clone()
+        };
+        check_sampling(&shiny, direction(0.5, 0.4));
+        check_sampling(&shiny, direction(1.45, 0.4));
+        // Odd exponents must not give a negative lobe opposite the mirror direction.
+        for &specular_exponent in &[1, 3] {
+            let odd = PhongShading { specular_exponent, ..shading.clone() };
+            for &theta in &[0.3, 1.2] {
+                // Light arriving along the view is far from the mirror direction at grazing
+                // angles.
+                let view = direction(theta, 0.4);
+                let normal = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+                let white = Color::from_rgb(1.0, 1.0, 1.0);
+                let value = odd.brdf(&shading_context(), &view, &view, &white, &normal);
+                assert!(value.r >= 0.0 && value.g >= 0.0 && value.b >= 0.0);
+                check_sampling(&odd, view);
+            }
         }
     }
 }
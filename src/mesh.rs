@@ -0,0 +1,510 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use cgmath::{
+    EuclideanSpace,
+    InnerSpace,
+    Point3,
+    Vector2,
+    Vector3,
+};
+use collision::{
+    Aabb3,
+    Ray3,
+};
+use bvh::*;
+use trace::*;
+
+/// A triangle mesh. The vertex and index buffers are reference counted, so several meshes (e.g.
+/// the parts of a model that use different materials) can share the same vertices without
+/// copying them.
+///
+/// The positions and indices can't be changed once the mesh is created, because the areas of
+/// its triangles are computed up front for sampling points on its surface.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    /// The position of each vertex.
+    positions: Arc<Vec<Point3<f32>>>,
+
+    /// The normal of each vertex, which is interpolated across each triangle to give smooth
+    /// shading. If this is empty, the triangles are shaded flat.
+    pub normals: Arc<Vec<Vector3<f32>>>,
+
+    /// The texture coordinates of each vertex, or empty if the mesh has none.
+    pub texcoords: Arc<Vec<Vector2<f32>>>,
+
+    /// The indices into `positions` (and `normals` and `texcoords`) of the vertices of each
+    /// triangle. Triangles whose vertices are in counterclockwise order when viewed from outside
+    /// the mesh face outwards.
+    indices: Arc<Vec<[usize; 3]>>,
+
+    /// The total area of each triangle and the ones before it.
+    cumulative_areas: Arc<Vec<f32>>,
+}
+
+/// The point at which a ray hits a single triangle of a mesh.
+#[derive(Copy, Clone, Debug)]
+pub struct TriangleHit {
+    /// The distance along the ray to the point of intersection, in units of the ray direction's
+    /// length.
+    pub t: f32,
+
+    /// The barycentric coordinates of the point of intersection, i.e. the weight of each of the
+    /// triangle's vertices.
+    pub barycentrics: [f32; 3],
+}
+
+impl Mesh {
+    /// Creates a mesh with flat shaded triangles.
+    pub fn new(positions: Vec<Point3<f32>>, indices: Vec<[usize; 3]>) -> Self {
+        Mesh::shared(
+            Arc::new(positions),
+            Arc::new(vec![]),
+            Arc::new(vec![]),
+            Arc::new(indices),
+        )
+    }
+
+    /// Creates a mesh from vertex and index buffers that may be shared with other meshes.
+    /// `normals` and `texcoords` may be empty; otherwise they have an element for each of the
+    /// `positions`.
+    pub fn shared(
+        positions: Arc<Vec<Point3<f32>>>,
+        normals: Arc<Vec<Vector3<f32>>>,
+        texcoords: Arc<Vec<Vector2<f32>>>,
+        indices: Arc<Vec<[usize; 3]>>,
+    ) -> Self {
+        let mut mesh = Mesh {
+            positions,
+            normals,
+            texcoords,
+            indices,
+            cumulative_areas: Arc::new(vec![]),
+        };
+        let mut total = 0.0;
+        mesh.cumulative_areas = Arc::new((0..mesh.triangle_count()).map(|index| {
+            total += mesh.triangle_area(index);
+            total
+        }).collect());
+        mesh
+    }
+
+    /// Creates a mesh with smooth shaded triangles, using the given per-vertex normals.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `normals` doesn't have one normal for each of the `positions`.
+    pub fn with_normals(
+        positions: Vec<Point3<f32>>,
+        normals: Vec<Vector3<f32>>,
+        indices: Vec<[usize; 3]>,
+    ) -> Self {
+        assert_eq!(positions.len(), normals.len(), "mesh must have one normal per vertex");
+        Mesh::shared(
+            Arc::new(positions),
+            Arc::new(normals),
+            Arc::new(vec![]),
+            Arc::new(indices),
+        )
+    }
+
+    /// Returns a copy of this mesh that shares its vertex positions and indices, with smooth
+    /// shading normals computed by averaging the normals of the triangles around each vertex,
+    /// weighted by their area.
+    pub fn smooth(&self) -> Self {
+        let mut normals = vec![Vector3 { x: 0.0, y: 0.0, z: 0.0 }; self.positions.len()];
+        for triangle in self.indices.iter() {
+            let [a, b, c] = *triangle;
+            // The length of the cross product is twice the triangle's area.
+            let normal = (self.positions[b] - self.positions[a])
+                .cross(self.positions[c] - self.positions[a]);
+            for &vertex in &[a, b, c] {
+                normals[vertex] += normal;
+            }
+        }
+        for normal in &mut normals {
+            if normal.magnitude2() > 0.0 {
+                *normal = normal.normalize();
+            }
+        }
+        Mesh {
+            normals: Arc::new(normals),
+            ..self.clone()
+        }
+    }
+
+    pub fn positions(&self) -> &Arc<Vec<Point3<f32>>> {
+        &self.positions
+    }
+
+    pub fn indices(&self) -> &Arc<Vec<[usize; 3]>> {
+        &self.indices
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Returns the positions of the vertices of the triangle with the given index.
+    pub fn triangle(&self, index: usize) -> [Point3<f32>; 3] {
+        let [a, b, c] = self.indices[index];
+        [self.positions[a], self.positions[b], self.positions[c]]
+    }
+
+    /// Returns the area of the triangle with the given index.
+    pub fn triangle_area(&self, index: usize) -> f32 {
+        let [a, b, c] = self.triangle(index);
+        (b - a).cross(c - a).magnitude() / 2.0
+    }
+
+    /// Intersects `ray` with the triangle with the given index, using Woop, Benthin and Wald's
+    /// watertight algorithm: rays that pass exactly through an edge or vertex shared by several
+    /// triangles hit at least one of them, so there are no cracks between adjacent triangles.
+    pub fn intersect_triangle(&self, index: usize, ray: &Ray3<f32>) -> Option<TriangleHit> {
+        self.intersect_triangle_line(index, ray).and_then(|hit| {
+            if hit.t < 0.0 {
+                None
+            } else {
+                Some(hit)
+            }
+        })
+    }
+
+    /// Like `intersect_triangle`, but also returns hits behind the ray's origin.
+    fn intersect_triangle_line(&self, index: usize, ray: &Ray3<f32>) -> Option<TriangleHit> {
+        let dir = ray.direction;
+        // Permute the axes so that the ray travels mostly along z, preserving winding.
+        let kz = if dir.x.abs() > dir.y.abs() {
+            if dir.x.abs() > dir.z.abs() { 0 } else { 2 }
+        } else {
+            if dir.y.abs() > dir.z.abs() { 1 } else { 2 }
+        };
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        if dir[kz] < 0.0 {
+            ::std::mem::swap(&mut kx, &mut ky);
+        }
+        // Shear the vertices so that the ray runs along the z axis from the origin.
+        let (shear_x, shear_y, shear_z) = (dir[kx] / dir[kz], dir[ky] / dir[kz], 1.0 / dir[kz]);
+        let vertices = self.triangle(index);
+        let [a, b, c] = [
+            vertices[0] - ray.origin,
+            vertices[1] - ray.origin,
+            vertices[2] - ray.origin,
+        ];
+        let (ax, ay) = (a[kx] - shear_x * a[kz], a[ky] - shear_y * a[kz]);
+        let (bx, by) = (b[kx] - shear_x * b[kz], b[ky] - shear_y * b[kz]);
+        let (cx, cy) = (c[kx] - shear_x * c[kz], c[ky] - shear_y * c[kz]);
+
+        // The scaled barycentric coordinates are the edge functions of the sheared triangle.
+        let (mut u, mut v, mut w) = (cx * by - cy * bx, ax * cy - ay * cx, bx * ay - by * ax);
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            // Recompute in double precision, so that rays through an edge aren't misclassified.
+            let (ax, ay, bx, by, cx, cy) =
+                (ax as f64, ay as f64, bx as f64, by as f64, cx as f64, cy as f64);
+            u = (cx * by - cy * bx) as f32;
+            v = (ax * cy - ay * cx) as f32;
+            w = (bx * ay - by * ax) as f32;
+        }
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+        let determinant = u + v + w;
+        if determinant == 0.0 {
+            return None;
+        }
+        let (az, bz, cz) = (shear_z * a[kz], shear_z * b[kz], shear_z * c[kz]);
+        Some(TriangleHit {
+            t: (u * az + v * bz + w * cz) / determinant,
+            barycentrics: [u / determinant, v / determinant, w / determinant],
+        })
+    }
+
+    /// Returns the `SolidHit` for a ray that hit the triangle with the given index.
+    pub fn solid_hit(&self, index: usize, ray: &Ray3<f32>, hit: &TriangleHit) -> SolidHit {
+        let vertices = self.triangle(index);
+        let [a, b, c] = self.indices[index];
+        let [w0, w1, w2] = hit.barycentrics;
+        let point = Point3::from_vec(
+            vertices[0].to_vec() * w0 + vertices[1].to_vec() * w1 + vertices[2].to_vec() * w2,
+        );
+        let geometric_normal = (vertices[1] - vertices[0])
+            .cross(vertices[2] - vertices[0])
+            .normalize();
+        let normal = if self.normals.is_empty() {
+            geometric_normal
+        } else {
+            let interpolated =
+                self.normals[a] * w0 + self.normals[b] * w1 + self.normals[c] * w2;
+            if interpolated.magnitude2() > 0.0 {
+                interpolated.normalize()
+            } else {
+                geometric_normal
+            }
+        };
+        // Without texture coordinates, use the barycentric coordinates instead.
+        let texcoords = if self.texcoords.is_empty() {
+            [Vector2 { x: 0.0, y: 0.0 }, Vector2 { x: 1.0, y: 0.0 }, Vector2 { x: 0.0, y: 1.0 }]
+        } else {
+            [self.texcoords[a], self.texcoords[b], self.texcoords[c]]
+        };
+        let uv = texcoords[0] * w0 + texcoords[1] * w1 + texcoords[2] * w2;
+
+        // Solve for the tangents that map the texture coordinate differences along two edges to
+        // their position differences.
+        let (dp1, dp2) = (vertices[1] - vertices[0], vertices[2] - vertices[0]);
+        let (duv1, duv2) = (texcoords[1] - texcoords[0], texcoords[2] - texcoords[0]);
+        let determinant = duv1.x * duv2.y - duv1.y * duv2.x;
+        let (dpdu, dpdv) = if determinant.abs() > 1e-12 {
+            (
+                (dp1 * duv2.y - dp2 * duv1.y) / determinant,
+                (dp2 * duv1.x - dp1 * duv2.x) / determinant,
+            )
+        } else {
+            let (_, dpdu, dpdv) = planar_mapping(point, geometric_normal);
+            (dpdu, dpdv)
+        };
+        SolidHit {
+            t: hit.t,
+            point,
+            normal,
+            uv,
+            dpdu,
+            dpdv,
+            // Which side was hit depends on the actual surface, not the interpolated normal.
+            front_face: ray.direction.dot(geometric_normal) < 0.0,
+        }
+    }
+}
+
+impl Solid for Mesh {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        let mut closest: Option<(usize, TriangleHit)> = None;
+        for index in 0..self.triangle_count() {
+            if let Some(hit) = self.intersect_triangle(index, ray) {
+                if closest.map_or(true, |(_, closest_hit)| hit.t < closest_hit.t) {
+                    closest = Some((index, hit));
+                }
+            }
+        }
+        closest.map(|(index, hit)| self.solid_hit(index, ray, &hit))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        // The vertex buffers may be shared with other meshes, so only count the vertices that
+        // this mesh's triangles use.
+        bounds_of(self.indices.iter()
+            .flat_map(|triangle| triangle.iter())
+            .map(|&index| self.positions[index]))
+    }
+
+    /// Treats the mesh as the surface of a closed solid: the ray enters the solid where it hits
+    /// the front of a triangle, and leaves where it hits the back of one.
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let mut crossings: Vec<SolidCrossing> = (0..self.triangle_count())
+            .filter_map(|index| {
+                self.intersect_triangle_line(index, ray).map(|hit| SolidCrossing {
+                    t: hit.t,
+                    hit: Some(self.solid_hit(index, ray, &hit)),
+                })
+            })
+            .collect();
+        crossings.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap_or(Ordering::Equal));
+
+        let mut intervals = vec![];
+        let mut enter: Option<SolidCrossing> = None;
+        for crossing in crossings {
+            let front_face = crossing.hit.map_or(false, |hit| hit.front_face);
+            match enter {
+                None if front_face => enter = Some(crossing),
+                Some(enter_crossing) if !front_face => {
+                    intervals.push(SolidInterval { enter: enter_crossing, exit: crossing });
+                    enter = None;
+                },
+                // Rays through an edge can cross both of the triangles that share it.
+                _ => {},
+            }
+        }
+        intervals
+    }
+
+    fn surface_area(&self) -> Option<f32> {
+        self.cumulative_areas.last().cloned().filter(|&area| area > 0.0)
+    }
+
+    /// Chooses a triangle with probability proportional to its area, by binary searching the
+    /// mesh's table of cumulative areas, and then a uniformly distributed point on it.
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SolidHit> {
+        let area = self.surface_area()?;
+        let target = u1 * area;
+        // Triangles without area are skipped, even if `target` rounds up to the whole area.
+        let index = self.cumulative_areas.partition_point(|&total| total <= target)
+            .min(self.cumulative_areas.partition_point(|&total| total < area));
+        let start = if index == 0 { 0.0 } else { self.cumulative_areas[index - 1] };
+        // Reuse what's left of `u1` to place the point within the triangle.
+        let u1 = ((target - start) / (self.cumulative_areas[index] - start)).min(1.0);
+        let [a, b, c] = self.triangle(index);
+        let root = u1.sqrt();
+        let (w1, w2) = (root * (1.0 - u2), root * u2);
+        // Arrive at the point along the triangle's normal, from one unit in front of it.
+        let normal = (b - a).cross(c - a).normalize();
+        let point = a + (b - a) * w1 + (c - a) * w2;
+        let ray = Ray3::new(point + normal, -normal);
+        let hit = TriangleHit { t: 1.0, barycentrics: [1.0 - w1 - w2, w1, w2] };
+        Some(SolidHit { t: 0.0, ..self.solid_hit(index, &ray, &hit) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::*;
+
+    /// A unit square in the z = 0 plane facing +z, made of two triangles sharing the diagonal
+    /// from (0, 0) to (1, 1).
+    fn square() -> Mesh {
+        Mesh::new(
+            vec![
+                Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                Point3 { x: 1.0, y: 0.0, z: 0.0 },
+                Point3 { x: 1.0, y: 1.0, z: 0.0 },
+                Point3 { x: 0.0, y: 1.0, z: 0.0 },
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+        )
+    }
+
+    fn down_ray(x: f32, y: f32) -> Ray3<f32> {
+        Ray3::new(Point3 { x, y, z: 1.0 }, Vector3 { x: 0.0, y: 0.0, z: -1.0 })
+    }
+
+    #[test]
+    fn test_trace() {
+        let hit = square().trace(&down_ray(0.25, 0.75)).unwrap();
+        assert!((hit.point - Point3 { x: 0.25, y: 0.75, z: 0.0 }).magnitude() < 1e-6);
+        assert_eq!(hit.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        assert!(hit.front_face);
+        assert!(square().trace(&down_ray(1.5, 0.5)).is_none());
+
+        let from_below = Ray3::new(
+            Point3 { x: 0.5, y: 0.25, z: -1.0 },
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+        );
+        assert!(!square().trace(&from_below).unwrap().front_face);
+    }
+
+    #[test]
+    fn test_watertight() {
+        let mesh = square();
+        // Rays through the shared diagonal, including through the shared vertices.
+        for i in 0..=10 {
+            let along = i as f32 / 10.0;
+            assert!(mesh.trace(&down_ray(along, along)).is_some(), "{}", along);
+        }
+    }
+
+    #[test]
+    fn test_intervals() {
+        let tetrahedron = Mesh::new(
+            vec![
+                Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                Point3 { x: 1.0, y: 0.0, z: 0.0 },
+                Point3 { x: 0.0, y: 1.0, z: 0.0 },
+                Point3 { x: 0.0, y: 0.0, z: 1.0 },
+            ],
+            vec![[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]],
+        );
+        let ray = Ray3::new(Point3 { x: 0.2, y: 0.2, z: -1.0 }, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        let intervals = tetrahedron.intervals(&ray);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].enter.t - 1.0).abs() < 1e-5);
+        assert!((intervals[0].exit.t - 1.6).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_smooth_normals() {
+        let normals = vec![
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+        ];
+        let square = square();
+        let mesh = Mesh::with_normals(
+            (**square.positions()).clone(),
+            normals,
+            (**square.indices()).clone(),
+        );
+        let at_vertex = mesh.trace(&down_ray(1.0, 0.0)).unwrap();
+        assert!((at_vertex.normal - Vector3 { x: 1.0, y: 0.0, z: 0.0 }).magnitude() < 1e-5);
+        let between = mesh.trace(&down_ray(0.5, 0.0)).unwrap();
+        let expected = Vector3 { x: 1.0, y: 0.0, z: 1.0 }.normalize();
+        assert!((between.normal - expected).magnitude() < 1e-5);
+        assert!(Arc::ptr_eq(square.smooth().positions(), square.positions()));
+    }
+
+    #[test]
+    fn test_texcoords() {
+        // Without texture coordinates, the barycentric coordinates are used.
+        let hit = square().trace(&down_ray(0.75, 0.25)).unwrap();
+        assert!((hit.uv - Vector2 { x: 0.5, y: 0.25 }).magnitude() < 1e-5);
+
+        let mut mesh = square();
+        mesh.texcoords = Arc::new(mesh.positions().iter()
+            .map(|position| Vector2 { x: position.x * 2.0, y: position.y * 4.0 })
+            .collect());
+        let hit = mesh.trace(&down_ray(0.25, 0.75)).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-6);
+        assert!((hit.uv - Vector2 { x: 0.5, y: 3.0 }).magnitude() < 1e-5);
+        assert!((hit.dpdu - Vector3 { x: 0.5, y: 0.0, z: 0.0 }).magnitude() < 1e-5);
+        assert!((hit.dpdv - Vector3 { x: 0.0, y: 0.25, z: 0.0 }).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn test_sample_surface() {
+        // A unit square and a triangle of area 2 facing the other way.
+        let mesh = Mesh::new(
+            vec![
+                Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                Point3 { x: 1.0, y: 0.0, z: 0.0 },
+                Point3 { x: 1.0, y: 1.0, z: 0.0 },
+                Point3 { x: 0.0, y: 1.0, z: 0.0 },
+                Point3 { x: 0.0, y: 0.0, z: 5.0 },
+                Point3 { x: 0.0, y: 2.0, z: 5.0 },
+                Point3 { x: 2.0, y: 0.0, z: 5.0 },
+            ],
+            vec![[0, 1, 2], [0, 2, 3], [4, 5, 6]],
+        );
+        assert!((mesh.surface_area().unwrap() - 3.0).abs() < 1e-6);
+        let mut rng = Rng::new(2);
+        let samples = 3000;
+        let mut on_triangle = 0;
+        for _ in 0..samples {
+            let sample = mesh.sample_surface(rng.next_f32(), rng.next_f32()).unwrap();
+            assert!(sample.front_face);
+            if (sample.point.z - 5.0).abs() < 1e-5 {
+                on_triangle += 1;
+                assert_eq!(sample.normal, Vector3 { x: 0.0, y: 0.0, z: -1.0 });
+                assert!(sample.point.x >= -1e-5 && sample.point.y >= -1e-5);
+                assert!(sample.point.x + sample.point.y <= 2.0 + 1e-5);
+            } else {
+                assert!(sample.point.z.abs() < 1e-5);
+                assert_eq!(sample.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+                assert!(sample.point.x >= -1e-5 && sample.point.x <= 1.0 + 1e-5);
+                assert!(sample.point.y >= -1e-5 && sample.point.y <= 1.0 + 1e-5);
+            }
+        }
+        // Triangles are chosen in proportion to their area.
+        let fraction = on_triangle as f32 / samples as f32;
+        assert!((fraction - 2.0 / 3.0).abs() < 0.03);
+
+        // Triangles without area are never chosen.
+        let mut indices = (**mesh.indices()).clone();
+        indices.insert(0, [0, 1, 1]);
+        indices.push([4, 5, 5]);
+        let mesh = Mesh::new((**mesh.positions()).clone(), indices);
+        for &u1 in &[0.0, 0.5, 1.0] {
+            let sample = mesh.sample_surface(u1, 0.5).unwrap();
+            assert!(sample.normal.magnitude2() > 0.5);
+        }
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+};
+use std::path::Path;
+use std::sync::Arc;
+
+use cgmath::{
+    InnerSpace,
+    Point3,
+    Vector2,
+    Vector3,
+};
+use color::*;
+use material::*;
+use mesh::*;
+use trace::*;
+
+/// An error encountered while loading a Wavefront OBJ file or MTL material library.
+#[derive(Debug)]
+pub enum ObjError {
+    /// A file couldn't be read.
+    Io(io::Error),
+
+    /// A line of a file is malformed. Line numbers start at 1.
+    Parse { line: usize, message: String },
+
+    /// An error in a material library referenced by an OBJ file.
+    MaterialLibrary { name: String, error: Box<ObjError> },
+
+    /// A material used by an OBJ file isn't defined by any of its material libraries.
+    UnknownMaterial(String),
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjError::Io(ref error) => write!(f, "{}", error),
+            ObjError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+            ObjError::MaterialLibrary { ref name, ref error } => {
+                write!(f, "in material library {}: {}", name, error)
+            },
+            ObjError::UnknownMaterial(ref name) => write!(f, "unknown material {}", name),
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn description(&self) -> &str {
+        match *self {
+            ObjError::Io(_) => "I/O error",
+            ObjError::Parse { .. } => "malformed line",
+            ObjError::MaterialLibrary { .. } => "error in material library",
+            ObjError::UnknownMaterial(_) => "unknown material",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ObjError::Io(ref error) => Some(error),
+            ObjError::MaterialLibrary { ref error, .. } => Some(&**error),
+            ObjError::Parse { .. } | ObjError::UnknownMaterial(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ObjError {
+    fn from(error: io::Error) -> Self {
+        ObjError::Io(error)
+    }
+}
+
+/// A vertex of a face, as 0-based indices into the position, texture coordinate and normal lists
+/// of an `ObjModel`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ObjVertex {
+    pub position: usize,
+    pub texcoord: Option<usize>,
+    pub normal: Option<usize>,
+}
+
+/// A run of faces that belong to the same group and use the same material.
+#[derive(Clone, Debug)]
+pub struct ObjGroup {
+    /// The name given by the most recent `g` or `o` statement, if any.
+    pub name: Option<String>,
+
+    /// The material given by the most recent `usemtl` statement, if any.
+    pub material: Option<String>,
+
+    /// Each face is a polygon with at least three vertices.
+    pub faces: Vec<Vec<ObjVertex>>,
+}
+
+/// The contents of an OBJ file.
+#[derive(Clone, Debug, Default)]
+pub struct ObjModel {
+    pub positions: Vec<Point3<f32>>,
+    pub texcoords: Vec<Vector2<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub groups: Vec<ObjGroup>,
+
+    /// The names of the material libraries referenced by `mtllib` statements.
+    pub material_libraries: Vec<String>,
+}
+
+impl ObjModel {
+    /// Returns a `SimpleObject` with a `Mesh` for each group that has faces. Polygons are split
+    /// into triangle fans. All of the meshes share the same vertex buffers. Materials are looked
+    /// up in `materials`, with `Material::new()` used for groups without one.
+    ///
+    /// If any face specifies normals, the meshes are smooth shaded. Vertices that don't specify
+    /// a normal use the geometric normal of their face, so faces without normals look flat.
+    pub fn scene_objects(
+        &self,
+        materials: &HashMap<String, Material>,
+    ) -> Result<Vec<Box<SceneObject>>, ObjError> {
+        let has_normals = self.faces().any(|vertex| vertex.normal.is_some());
+        let has_texcoords = self.faces().any(|vertex| vertex.texcoord.is_some());
+
+        // Each distinct combination of indices becomes a vertex of the meshes. Vertices that
+        // fall back to a face normal can't be shared with other faces, so the face is part of
+        // their key.
+        let mut vertex_indices = HashMap::new();
+        let mut positions = vec![];
+        let mut normals = vec![];
+        let mut texcoords = vec![];
+        let mut group_indices = vec![];
+        let mut face_count = 0;
+        for group in &self.groups {
+            let mut indices = vec![];
+            for face in &group.faces {
+                face_count += 1;
+                let face_normal = self.face_normal(face);
+                let face_indices: Vec<usize> = face.iter().map(|vertex| {
+                    let owner = if has_normals && vertex.normal.is_none() {
+                        Some(face_count)
+                    } else {
+                        None
+                    };
+                    *vertex_indices.entry((*vertex, owner)).or_insert_with(|| {
+                        positions.push(self.positions[vertex.position]);
+                        if has_normals {
+                            normals.push(vertex.normal.map_or(
+                                face_normal,
+                                |normal| self.normals[normal],
+                            ));
+                        }
+                        if has_texcoords {
+                            texcoords.push(vertex.texcoord.map_or(
+                                Vector2 { x: 0.0, y: 0.0 },
+                                |texcoord| self.texcoords[texcoord],
+                            ));
+                        }
+                        positions.len() - 1
+                    })
+                }).collect();
+                for i in 1..face_indices.len() - 1 {
+                    indices.push([face_indices[0], face_indices[i], face_indices[i + 1]]);
+                }
+            }
+            group_indices.push(indices);
+        }
+
+        let (positions, normals, texcoords) =
+            (Arc::new(positions), Arc::new(normals), Arc::new(texcoords));
+        let mut objects: Vec<Box<SceneObject>> = vec![];
+        for (group, indices) in self.groups.iter().zip(group_indices) {
+            if indices.is_empty() {
+                continue;
+            }
+            let material = match group.material {
+                Some(ref name) => match materials.get(name) {
+                    Some(material) => material.clone(),
+                    None => return Err(ObjError::UnknownMaterial(name.clone())),
+                },
+                None => Material::new(),
+            };
+            objects.push(Box::from(SimpleObject {
+                solid: Box::from(Mesh::shared(
+                    positions.clone(),
+                    normals.clone(),
+                    texcoords.clone(),
+                    Arc::new(indices),
+                )),
+                material,
+            }));
+        }
+        Ok(objects)
+    }
+
+    /// Returns the unit normal of `face`, computed with Newell's method so that it's also
+    /// reasonable for polygons that aren't quite planar. Faces with no area get a zero normal.
+    fn face_normal(&self, face: &[ObjVertex]) -> Vector3<f32> {
+        let mut normal = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+        for (i, vertex) in face.iter().enumerate() {
+            let a = self.positions[vertex.position];
+            let b = self.positions[face[(i + 1) % face.len()].position];
+            normal.x += (a.y - b.y) * (a.z + b.z);
+            normal.y += (a.z - b.z) * (a.x + b.x);
+            normal.z += (a.x - b.x) * (a.y + b.y);
+        }
+        if normal.magnitude2() > 0.0 {
+            normal.normalize()
+        } else {
+            normal
+        }
+    }
+
+    fn faces<'a>(&'a self) -> Box<Iterator<Item = &'a ObjVertex> + 'a> {
+        Box::new(self.groups.iter().flat_map(|group| group.faces.iter()).flat_map(|face| face))
+    }
+}
+
+/// Loads the OBJ file at `path`, along with the material libraries it references, which are
+/// looked for relative to the directory containing it.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Vec<Box<SceneObject>>, ObjError> {
+    let path = path.as_ref();
+    let model = parse_obj(BufReader::new(File::open(path)?))?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut materials = HashMap::new();
+    for name in &model.material_libraries {
+        let library = File::open(directory.join(name))
+            .map_err(ObjError::from)
+            .and_then(|file| parse_mtl(BufReader::new(file)))
+            .map_err(|error| ObjError::MaterialLibrary {
+                name: name.clone(),
+                error: Box::new(error),
+            })?;
+        materials.extend(library);
+    }
+    model.scene_objects(&materials)
+}
+
+/// Parses the statements of an OBJ file that describe polygonal geometry. Statements for other
+/// kinds of geometry, and for smoothing groups, are ignored.
+pub fn parse_obj<R: BufRead>(reader: R) -> Result<ObjModel, ObjError> {
+    let mut model = ObjModel::default();
+    let mut name = None;
+    let mut material = None;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut parser = LineParser::new(index + 1, &line);
+        let keyword = match parser.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match keyword {
+            "v" => {
+                let [x, y, z] = parser.floats3()?;
+                model.positions.push(Point3 { x, y, z });
+            },
+            "vn" => {
+                let [x, y, z] = parser.floats3()?;
+                model.normals.push(Vector3 { x, y, z });
+            },
+            "vt" => {
+                let x = parser.float()?;
+                let y = parser.optional_float()?.unwrap_or(0.0);
+                parser.optional_float()?;
+                model.texcoords.push(Vector2 { x, y });
+            },
+            "f" => {
+                let mut face = vec![];
+                while let Some(vertex) = parser.next() {
+                    face.push(parser.face_vertex(vertex, &model)?);
+                }
+                if face.len() < 3 {
+                    return Err(parser.error("a face needs at least 3 vertices".to_string()));
+                }
+                let starts_group = model.groups.last().map_or(true, |group| {
+                    group.name != name || group.material != material
+                });
+                if starts_group {
+                    model.groups.push(ObjGroup {
+                        name: name.clone(),
+                        material: material.clone(),
+                        faces: vec![],
+                    });
+                }
+                model.groups.last_mut().unwrap().faces.push(face);
+            },
+            "g" | "o" => name = Some(parser.rest()),
+            "usemtl" => material = Some(parser.name()?),
+            "mtllib" => {
+                while let Some(library) = parser.next() {
+                    model.material_libraries.push(library.to_string());
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(model)
+}
+
+/// Parses an MTL material library, returning its materials by name. Each material uses
+/// `PhongShading`, with `Kd`, `Ks` and `Ns` giving its diffuse color, specular color and
+/// specular exponent. Other statements are ignored.
+pub fn parse_mtl<R: BufRead>(reader: R) -> Result<HashMap<String, Material>, ObjError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, PhongShading)> = None;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut parser = LineParser::new(index + 1, &line);
+        let keyword = match parser.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            if let Some((name, shading)) = current.take() {
+                materials.insert(name, mtl_material(shading));
+            }
+            current = Some((parser.name()?, PhongShading {
+                diffuse_color: Arc::new(Color::from_rgb(0.18, 0.18, 0.18)),
+                specular_color: Arc::new(Color::from_rgb(0.0, 0.0, 0.0)),
+                specular_exponent: 1,
+            }));
+            continue;
+        }
+        let shading = match (keyword, current.as_mut()) {
+            ("Kd", Some(&mut (_, ref mut shading)))
+                | ("Ks", Some(&mut (_, ref mut shading)))
+                | ("Ns", Some(&mut (_, ref mut shading))) => shading,
+            ("Kd", None) | ("Ks", None) | ("Ns", None) => {
+                return Err(parser.error(format!("{} before newmtl", keyword)));
+            },
+            _ => continue,
+        };
+        match keyword {
+            "Kd" => shading.diffuse_color = Arc::new(parser.color()?),
+            "Ks" => shading.specular_color = Arc::new(parser.color()?),
+            _ => shading.specular_exponent = parser.float()?.round() as i32,
+        }
+    }
+    if let Some((name, shading)) = current {
+        materials.insert(name, mtl_material(shading));
+    }
+    Ok(materials)
+}
+
+fn mtl_material(shading: PhongShading) -> Material {
+    Material {
+        shading: Arc::from(shading),
+        ..Material::new()
+    }
+}
+
+/// Splits a line of an OBJ or MTL file into whitespace separated arguments, and reports errors
+/// with its line number.
+struct LineParser<'a> {
+    line_number: usize,
+    arguments: ::std::str::SplitWhitespace<'a>,
+}
+
+impl<'a> LineParser<'a> {
+    fn new(line_number: usize, line: &'a str) -> Self {
+        let without_comment = line.split('#').next().unwrap_or("");
+        LineParser { line_number, arguments: without_comment.split_whitespace() }
+    }
+
+    fn error(&self, message: String) -> ObjError {
+        ObjError::Parse { line: self.line_number, message }
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.arguments.next()
+    }
+
+    /// Returns the remaining arguments, joined by spaces.
+    fn rest(&mut self) -> String {
+        self.arguments.by_ref().collect::<Vec<_>>().join(" ")
+    }
+
+    fn name(&mut self) -> Result<String, ObjError> {
+        let name = self.rest();
+        if name.is_empty() {
+            return Err(self.error("expected a name".to_string()));
+        }
+        Ok(name)
+    }
+
+    fn optional_float(&mut self) -> Result<Option<f32>, ObjError> {
+        match self.next() {
+            Some(argument) => argument.parse().map(Some).map_err(|_| {
+                self.error(format!("expected a number, found {}", argument))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    fn float(&mut self) -> Result<f32, ObjError> {
+        self.optional_float()?.ok_or_else(|| self.error("expected a number".to_string()))
+    }
+
+    fn floats3(&mut self) -> Result<[f32; 3], ObjError> {
+        Ok([self.float()?, self.float()?, self.float()?])
+    }
+
+    fn color(&mut self) -> Result<Color, ObjError> {
+        let [r, g, b] = self.floats3()?;
+        Ok(Color::from_rgb(r, g, b))
+    }
+
+    /// Parses a face vertex of the form `v`, `v/vt`, `v//vn` or `v/vt/vn`.
+    fn face_vertex(&self, argument: &str, model: &ObjModel) -> Result<ObjVertex, ObjError> {
+        let mut parts = argument.split('/');
+        let mut index = |count: usize, kind: &str| -> Result<Option<usize>, ObjError> {
+            match parts.next() {
+                None | Some("") => Ok(None),
+                Some(part) => {
+                    let index: i64 = part.parse().map_err(|_| {
+                        self.error(format!("invalid {} index {}", kind, part))
+                    })?;
+                    // Indices start at 1; negative indices count back from the last element.
+                    let resolved = if index > 0 { index - 1 } else { count as i64 + index };
+                    if index == 0 || resolved < 0 || resolved >= count as i64 {
+                        return Err(self.error(format!("{} index {} out of range", kind, part)));
+                    }
+                    Ok(Some(resolved as usize))
+                },
+            }
+        };
+        let position = index(model.positions.len(), "vertex")?
+            .ok_or_else(|| self.error(format!("face vertex {} has no position", argument)))?;
+        let texcoord = index(model.texcoords.len(), "texture coordinate")?;
+        let normal = index(model.normals.len(), "normal")?;
+        if parts.next().is_some() {
+            return Err(self.error(format!("face vertex {} has too many indices", argument)));
+        }
+        Ok(ObjVertex { position, texcoord, normal })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use collision::Ray3;
+    use texture::*;
+
+    const SQUARE: &str = "
+        # A unit square in the z = 0 plane, facing +z.
+        mtllib square.mtl
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0 1 0
+        vt 0 0
+        vt 1 0
+        vt 1 1
+        vt 0 1
+        vn 0 0 1
+        g square
+        usemtl red
+        f 1/1/1 2/2/1 3/3/1 -1/-1/-1
+    ";
+
+    #[test]
+    fn test_parse_obj() {
+        let model = parse_obj(SQUARE.as_bytes()).unwrap();
+        assert_eq!(model.positions.len(), 4);
+        assert_eq!(model.texcoords.len(), 4);
+        assert_eq!(model.normals.len(), 1);
+        assert_eq!(model.material_libraries, vec!["square.mtl".to_string()]);
+        assert_eq!(model.groups.len(), 1);
+        assert_eq!(model.groups[0].name, Some("square".to_string()));
+        assert_eq!(model.groups[0].material, Some("red".to_string()));
+        assert_eq!(model.groups[0].faces[0][3], ObjVertex {
+            position: 3,
+            texcoord: Some(3),
+            normal: Some(0),
+        });
+    }
+
+    #[test]
+    fn test_scene_objects() {
+        let model = parse_obj(SQUARE.as_bytes()).unwrap();
+        let materials = parse_mtl("newmtl red\nKd 1 0 0\nKs 0.5 0.5 0.5\nNs 10".as_bytes())
+            .unwrap();
+        let objects = model.scene_objects(&materials).unwrap();
+        assert_eq!(objects.len(), 1);
+        let ray = Ray3::new(
+            Point3 { x: 0.25, y: 0.75, z: 1.0 },
+            Vector3 { x: 0.0, y: 0.0, z: -1.0 },
+        );
+        let hit = objects[0].trace(&ray).unwrap();
+        assert!((hit.solid.point - Point3 { x: 0.25, y: 0.75, z: 0.0 }).magnitude() < 1e-6);
+        assert_eq!(hit.solid.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        let albedo = hit.material.albedo(&ShadingContext::new(hit.solid));
+        assert_eq!((albedo.r, albedo.g), (1.0, 0.0));
+
+        assert!(model.scene_objects(&HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_missing_normals_use_face_normal() {
+        // Two faces share vertices 1 and 3; the second one has no normals and faces -z.
+        let source = "
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vn 0 0 1
+            f 1//1 2//1 3//1
+            f 1 4 3
+        ";
+        let objects = parse_obj(source.as_bytes()).unwrap()
+            .scene_objects(&HashMap::new()).unwrap();
+        let ray = Ray3::new(
+            Point3 { x: 0.25, y: 0.75, z: -1.0 },
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+        );
+        let hit = objects[0].trace(&ray).unwrap();
+        assert!((hit.solid.normal - Vector3 { x: 0.0, y: 0.0, z: -1.0 }).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error_line = |source: &str| match parse_obj(source.as_bytes()) {
+            Err(ObjError::Parse { line, .. }) => line,
+            _ => panic!("expected a parse error for {:?}", source),
+        };
+        assert_eq!(error_line("v 0 0 0\nv 1 0"), 2);
+        assert_eq!(error_line("v 0 0 x"), 1);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\n\nf 1 2"), 4);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 4"), 4);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 0"), 4);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 3/1/1/1"), 4);
+        assert_eq!(error_line("usemtl"), 1);
+        match parse_mtl("Kd 1 1 1".as_bytes()) {
+            Err(ObjError::Parse { line: 1, .. }) => {},
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
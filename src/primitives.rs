@@ -0,0 +1,1128 @@
+use std::f32;
+use std::f32::consts::PI;
+
+use cgmath::{
+    EuclideanSpace,
+    InnerSpace,
+    Point3,
+    Quaternion,
+    Rotation,
+    Vector2,
+    Vector3,
+};
+use collision::{
+    Aabb3,
+    Ray3,
+};
+use bvh::*;
+use sampling::*;
+use trace::*;
+
+/// A box oriented arbitrarily in space.
+#[derive(Copy, Clone, Debug)]
+pub struct OrientedBox {
+    pub center: Point3<f32>,
+
+    /// Half of the box's size along each of its own axes.
+    pub half_extents: Vector3<f32>,
+
+    /// The rotation from the box's axes to world space. It must be a unit quaternion.
+    pub orientation: Quaternion<f32>,
+}
+
+/// A cylinder capped at both ends.
+#[derive(Copy, Clone, Debug)]
+pub struct Cylinder {
+    /// The center of the bottom cap.
+    pub base: Point3<f32>,
+
+    /// The normalized direction from the bottom cap to the top cap.
+    pub axis: Vector3<f32>,
+
+    pub radius: f32,
+    pub height: f32,
+}
+
+/// A cone capped at its base.
+#[derive(Copy, Clone, Debug)]
+pub struct Cone {
+    /// The center of the base.
+    pub base: Point3<f32>,
+
+    /// The normalized direction from the base to the apex.
+    pub axis: Vector3<f32>,
+
+    /// The radius of the base.
+    pub radius: f32,
+
+    /// The distance from the base to the apex.
+    pub height: f32,
+}
+
+/// A flat, circular disk. It's a surface rather than a solid, so it has no intervals and can't
+/// be used in CSG.
+#[derive(Copy, Clone, Debug)]
+pub struct Disk {
+    pub center: Point3<f32>,
+
+    /// The normalized normal of the side of the disk that faces front.
+    pub normal: Vector3<f32>,
+
+    pub radius: f32,
+}
+
+/// A flat parallelogram with corners at `corner`, `corner + edge1`, `corner + edge2` and
+/// `corner + edge1 + edge2`; it's a rectangle if the edges are perpendicular. The front of the
+/// quad faces in the direction of `edge1.cross(edge2)`. Like `Disk`, it has no intervals.
+#[derive(Copy, Clone, Debug)]
+pub struct Quad {
+    pub corner: Point3<f32>,
+    pub edge1: Vector3<f32>,
+    pub edge2: Vector3<f32>,
+}
+
+/// A ring-shaped solid: the points within `minor_radius` of a circle of radius `major_radius`.
+#[derive(Copy, Clone, Debug)]
+pub struct Torus {
+    pub center: Point3<f32>,
+
+    /// The normalized normal of the plane of the torus's circle.
+    pub axis: Vector3<f32>,
+
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+impl Solid for Aabb3<f32> {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        first_crossing(&self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        Some(*self)
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let mapping = |point, normal| box_mapping(self.min, self.max, point, normal);
+        box_span(self.min, self.max, ray)
+            .map(|span| vec![span.interval(ray, ray, |normal| normal, mapping)])
+            .unwrap_or_default()
+    }
+}
+
+impl Solid for OrientedBox {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        first_crossing(&self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let corners = (0..8).map(|corner| {
+            let offset = Vector3 {
+                x: if corner & 1 == 0 { -self.half_extents.x } else { self.half_extents.x },
+                y: if corner & 2 == 0 { -self.half_extents.y } else { self.half_extents.y },
+                z: if corner & 4 == 0 { -self.half_extents.z } else { self.half_extents.z },
+            };
+            self.center + self.orientation.rotate_vector(offset)
+        });
+        bounds_of(corners)
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let inverse = self.orientation.invert();
+        let local_ray = Ray3::new(
+            Point3::from_vec(inverse.rotate_vector(ray.origin - self.center)),
+            inverse.rotate_vector(ray.direction),
+        );
+        let min = Point3::from_vec(-self.half_extents);
+        let max = Point3::from_vec(self.half_extents);
+        let to_world = |vector| self.orientation.rotate_vector(vector);
+        let mapping = |point, normal| box_mapping(min, max, point, normal);
+        box_span(min, max, &local_ray)
+            .map(|span| vec![span.interval(ray, &local_ray, to_world, mapping)])
+            .unwrap_or_default()
+    }
+}
+
+impl Solid for Cylinder {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        first_crossing(&self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let extent = Vector3 { x: self.radius, y: self.radius, z: self.height };
+        LocalFrame::new(self.base, self.axis)
+            .bounds(Point3 { x: -extent.x, y: -extent.y, z: 0.0 }, Point3::from_vec(extent))
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let frame = LocalFrame::new(self.base, self.axis);
+        let local = frame.ray_to_local(ray);
+        let (o, d) = (local.origin, local.direction);
+        let side = quadric_spans(
+            d.x * d.x + d.y * d.y,
+            2.0 * (o.x * d.x + o.y * d.y),
+            o.x * o.x + o.y * o.y - self.radius * self.radius,
+            |point| Vector3 { x: point.x, y: point.y, z: 0.0 },
+            &local,
+        );
+        let caps = slab_span(o.z, d.z, 0.0, self.height, Vector3::unit_z());
+        let (radius, height) = (self.radius, self.height);
+        frame.intervals(ray, &local, side, caps, |point, normal| {
+            let (u, dpdu) = longitude(point);
+            if is_cap(normal) {
+                return cap_mapping(point, normal, u, dpdu, radius);
+            }
+            (Vector2 { x: u, y: point.z / height }, dpdu, Vector3 { x: 0.0, y: 0.0, z: height })
+        })
+    }
+}
+
+impl Solid for Cone {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        first_crossing(&self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let extent = Vector3 { x: self.radius, y: self.radius, z: self.height };
+        LocalFrame::new(self.base, self.axis)
+            .bounds(Point3 { x: -extent.x, y: -extent.y, z: 0.0 }, Point3::from_vec(extent))
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let frame = LocalFrame::new(self.base, self.axis);
+        let local = frame.ray_to_local(ray);
+        let (o, d) = (local.origin, local.direction);
+        // The inside of the (double) cone is where x^2 + y^2 <= (k * (height - z))^2.
+        let k2 = (self.radius / self.height).powi(2);
+        let height = self.height;
+        let above_origin = height - o.z;
+        let side = quadric_spans(
+            d.x * d.x + d.y * d.y - k2 * d.z * d.z,
+            2.0 * (o.x * d.x + o.y * d.y + k2 * above_origin * d.z),
+            o.x * o.x + o.y * o.y - k2 * above_origin * above_origin,
+            |point| Vector3 { x: point.x, y: point.y, z: k2 * (height - point.z) },
+            &local,
+        );
+        // Limiting the double cone to the space between the base and the apex leaves one nappe.
+        let caps = slab_span(o.z, d.z, 0.0, self.height, Vector3::unit_z());
+        let radius = self.radius;
+        frame.intervals(ray, &local, side, caps, |point, normal| {
+            let (u, dpdu) = longitude(point);
+            if is_cap(normal) {
+                return cap_mapping(point, normal, u, dpdu, radius);
+            }
+            // The side is parameterized by height, shrinking from the base to the apex.
+            let (sin, cos) = (2.0 * PI * u).sin_cos();
+            let dpdv = Vector3 { x: -radius * cos, y: -radius * sin, z: height };
+            (Vector2 { x: u, y: point.z / height }, dpdu, dpdv)
+        })
+    }
+}
+
+impl Disk {
+    /// Returns the hit at `point`, which is on the disk.
+    fn hit_at(&self, point: Point3<f32>, t: f32, front_face: bool) -> SolidHit {
+        let frame = LocalFrame::new(self.center, self.normal);
+        let local_point = frame.point_to_local(point);
+        let (u, dpdu) = longitude(local_point);
+        let (uv, dpdu, dpdv) = cap_mapping(local_point, Vector3::unit_z(), u, dpdu, self.radius);
+        SolidHit {
+            t,
+            point,
+            normal: self.normal,
+            uv,
+            dpdu: frame.vector_to_world(dpdu),
+            dpdv: frame.vector_to_world(dpdv),
+            front_face,
+        }
+    }
+}
+
+impl Solid for Disk {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        let approach = ray.direction.dot(self.normal);
+        if approach == 0.0 {
+            return None;
+        }
+        let t = (self.center - ray.origin).dot(self.normal) / approach;
+        if t < 0.0 {
+            return None;
+        }
+        let point = ray.origin + ray.direction * t;
+        if (point - self.center).magnitude2() > self.radius * self.radius {
+            return None;
+        }
+        Some(self.hit_at(point, t, approach < 0.0))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        // The disk extends furthest along the axes that are most parallel to it.
+        let extent = |component: f32| self.radius * (1.0 - component * component).max(0.0).sqrt();
+        let extent = Vector3 {
+            x: extent(self.normal.x),
+            y: extent(self.normal.y),
+            z: extent(self.normal.z),
+        };
+        Some(Aabb3::new(self.center - extent, self.center + extent))
+    }
+
+    fn intervals(&self, _ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        vec![]
+    }
+
+    fn surface_area(&self) -> Option<f32> {
+        Some(PI * self.radius * self.radius)
+    }
+
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SolidHit> {
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let (x, y) = concentric_sample_disk(u1, u2);
+        let point = self.center + (tangent * x + bitangent * y) * self.radius;
+        Some(self.hit_at(point, 0.0, true))
+    }
+}
+
+impl Solid for Quad {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        let unnormalized_normal = self.edge1.cross(self.edge2);
+        let normal = unnormalized_normal.normalize();
+        let approach = ray.direction.dot(normal);
+        if approach == 0.0 {
+            return None;
+        }
+        let t = (self.corner - ray.origin).dot(normal) / approach;
+        if t < 0.0 {
+            return None;
+        }
+        let point = ray.origin + ray.direction * t;
+        // Find the coordinates of the point along each edge.
+        let offset = point - self.corner;
+        let scaled_normal = unnormalized_normal / unnormalized_normal.magnitude2();
+        let u = scaled_normal.dot(offset.cross(self.edge2));
+        let v = scaled_normal.dot(self.edge1.cross(offset));
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            return None;
+        }
+        Some(SolidHit {
+            t,
+            point,
+            normal,
+            uv: Vector2 { x: u, y: v },
+            dpdu: self.edge1,
+            dpdv: self.edge2,
+            front_face: approach < 0.0,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        bounds_of(vec![
+            self.corner,
+            self.corner + self.edge1,
+            self.corner + self.edge2,
+            self.corner + self.edge1 + self.edge2,
+        ])
+    }
+
+    fn intervals(&self, _ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        vec![]
+    }
+
+    fn surface_area(&self) -> Option<f32> {
+        Some(self.edge1.cross(self.edge2).magnitude())
+    }
+
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SolidHit> {
+        Some(SolidHit {
+            t: 0.0,
+            point: self.corner + self.edge1 * u1 + self.edge2 * u2,
+            normal: self.edge1.cross(self.edge2).normalize(),
+            uv: Vector2 { x: u1, y: u2 },
+            dpdu: self.edge1,
+            dpdv: self.edge2,
+            front_face: true,
+        })
+    }
+}
+
+impl Solid for Torus {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        first_crossing(&self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let extent = Vector3 {
+            x: self.major_radius + self.minor_radius,
+            y: self.major_radius + self.minor_radius,
+            z: self.minor_radius,
+        };
+        LocalFrame::new(self.center, self.axis)
+            .bounds(Point3::from_vec(-extent), Point3::from_vec(extent))
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let frame = LocalFrame::new(self.center, self.axis);
+        let local = frame.ray_to_local(ray);
+        let (major, minor) = (self.major_radius as f64, self.minor_radius as f64);
+
+        // All crossings are within the torus's bounding sphere. Solving from where the ray enters
+        // it, rather than from its origin, keeps the polynomial's coefficients small.
+        let bounding_radius = self.major_radius + self.minor_radius;
+        let bounding_span = sphere_span(local.origin.to_vec(), local.direction, bounding_radius);
+        let (start, end) = match bounding_span {
+            Some(span) => span,
+            None => return vec![],
+        };
+        let o = local.origin + local.direction * start;
+        let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+        let (dx, dy, dz) = (
+            local.direction.x as f64,
+            local.direction.y as f64,
+            local.direction.z as f64,
+        );
+
+        // Substitute the ray into (x^2 + y^2 + z^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2).
+        let b = 2.0 * (ox * dx + oy * dy + oz * dz);
+        let c = ox * ox + oy * oy + oz * oz + major * major - minor * minor;
+        let e = dx * dx + dy * dy;
+        let f = 2.0 * (ox * dx + oy * dy);
+        let g = ox * ox + oy * oy;
+        let four_r2 = 4.0 * major * major;
+        let coefficients = [
+            c * c - four_r2 * g,
+            2.0 * b * c - four_r2 * f,
+            b * b + 2.0 * c - four_r2 * e,
+            2.0 * b,
+            1.0,
+        ];
+        let roots = polynomial_roots(&coefficients, 0.0, (end - start) as f64);
+
+        let crossing = |root: f64| {
+            let t = start + root as f32;
+            let point = local.origin + local.direction * t;
+            let ring = Vector3 { x: point.x, y: point.y, z: 0.0 };
+            let ring_direction = if ring.magnitude2() > 0.0 {
+                ring.normalize()
+            } else {
+                Vector3::unit_x()
+            };
+            let tube = point.to_vec() - ring_direction * self.major_radius;
+
+            // The second texture coordinate is the angle around the tube, measured from the
+            // outer equator towards the top.
+            let (u, dpdu) = longitude(point);
+            let mut theta = tube.z.atan2(tube.dot(ring_direction));
+            if theta < 0.0 {
+                theta += 2.0 * PI;
+            }
+            let dpdv = (Vector3::unit_z() * theta.cos() - ring_direction * theta.sin())
+                * (2.0 * PI * self.minor_radius);
+            let mapping = (Vector2 { x: u, y: theta / (2.0 * PI) }, dpdu, dpdv);
+            frame.crossing(ray, t, tube, mapping)
+        };
+        // Tangent rays don't change sign at their crossings, so the roots pair up.
+        roots.chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| SolidInterval { enter: crossing(pair[0]), exit: crossing(pair[1]) })
+            .collect()
+    }
+}
+
+/// A section of a ray inside a convex shape, with the unnormalized normals of the shape's surface
+/// at each end, in the shape's local frame. An infinite end has a zero normal.
+#[derive(Copy, Clone, Debug)]
+struct Span {
+    enter: f32,
+    enter_normal: Vector3<f32>,
+    exit: f32,
+    exit_normal: Vector3<f32>,
+}
+
+impl Span {
+    fn everywhere() -> Self {
+        let zero = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+        Span { enter: -f32::INFINITY, enter_normal: zero, exit: f32::INFINITY, exit_normal: zero }
+    }
+
+    /// Returns the section of the ray inside both shapes, if any.
+    fn intersect(&self, other: &Span) -> Option<Span> {
+        let (enter, enter_normal) = if other.enter > self.enter {
+            (other.enter, other.enter_normal)
+        } else {
+            (self.enter, self.enter_normal)
+        };
+        let (exit, exit_normal) = if other.exit < self.exit {
+            (other.exit, other.exit_normal)
+        } else {
+            (self.exit, self.exit_normal)
+        };
+        if enter <= exit {
+            Some(Span { enter, enter_normal, exit, exit_normal })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the interval along the world space `ray` that this span describes. `local_ray` is
+    /// the same ray in the span's frame, `to_world` converts vectors from that frame to world
+    /// space, and `mapping` gives the surface's texture coordinates and tangents at a point in
+    /// that frame, given the surface's normal there.
+    fn interval<F, M>(
+        &self,
+        ray: &Ray3<f32>,
+        local_ray: &Ray3<f32>,
+        to_world: F,
+        mapping: M,
+    ) -> SolidInterval
+        where F: Fn(Vector3<f32>) -> Vector3<f32>,
+              M: Fn(Point3<f32>, Vector3<f32>) -> SurfaceMapping
+    {
+        let crossing = |t: f32, normal: Vector3<f32>| {
+            if t.is_infinite() {
+                return SolidCrossing { t, hit: None };
+            }
+            let (uv, dpdu, dpdv) = mapping(local_ray.origin + local_ray.direction * t, normal);
+            world_crossing(ray, t, to_world(normal), (uv, to_world(dpdu), to_world(dpdv)))
+        };
+        SolidInterval {
+            enter: crossing(self.enter, self.enter_normal),
+            exit: crossing(self.exit, self.exit_normal),
+        }
+    }
+}
+
+/// A surface's texture coordinates at a point, and its tangents `dpdu` and `dpdv` there.
+type SurfaceMapping = (Vector2<f32>, Vector3<f32>, Vector3<f32>);
+
+/// Returns the crossing at the finite distance `t` along `ray`, where the surface has the (not
+/// necessarily normalized) normal `normal` and the world space mapping `mapping`.
+fn world_crossing(
+    ray: &Ray3<f32>,
+    t: f32,
+    normal: Vector3<f32>,
+    mapping: SurfaceMapping,
+) -> SolidCrossing {
+    let normal = normal.normalize();
+    let (uv, dpdu, dpdv) = mapping;
+    SolidCrossing {
+        t,
+        hit: Some(SolidHit {
+            t,
+            point: ray.origin + ray.direction * t,
+            normal,
+            uv,
+            dpdu,
+            dpdv,
+            front_face: ray.direction.dot(normal) < 0.0,
+        }),
+    }
+}
+
+/// Maps each face of the box from `min` to `max` to the unit square, given a point on the face
+/// and the face's normal.
+fn box_mapping(
+    min: Point3<f32>,
+    max: Point3<f32>,
+    point: Point3<f32>,
+    normal: Vector3<f32>,
+) -> SurfaceMapping {
+    let axis = if normal.x.abs() >= normal.y.abs() && normal.x.abs() >= normal.z.abs() {
+        0
+    } else if normal.y.abs() >= normal.z.abs() {
+        1
+    } else {
+        2
+    };
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    let size = max - min;
+    let (mut dpdu, mut dpdv) = (Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0));
+    dpdv[b] = size[b];
+    let v = (point[b] - min[b]) / size[b];
+    // Mirror the faces at the minimum end of the axis, so that every face is mapped as seen
+    // from outside the box.
+    if normal[axis] > 0.0 {
+        dpdu[a] = size[a];
+        (Vector2 { x: (point[a] - min[a]) / size[a], y: v }, dpdu, dpdv)
+    } else {
+        dpdu[a] = -size[a];
+        (Vector2 { x: (max[a] - point[a]) / size[a], y: v }, dpdu, dpdv)
+    }
+}
+
+/// Returns the angle of a local point around the z axis as a fraction of a turn, and the rate of
+/// change of the point with respect to it.
+fn longitude(point: Point3<f32>) -> (f32, Vector3<f32>) {
+    let mut phi = point.y.atan2(point.x);
+    if phi < 0.0 {
+        phi += 2.0 * PI;
+    }
+    (phi / (2.0 * PI), Vector3 { x: -point.y, y: point.x, z: 0.0 } * (2.0 * PI))
+}
+
+/// Returns whether a local normal belongs to a flat cap perpendicular to the z axis.
+fn is_cap(normal: Vector3<f32>) -> bool {
+    normal.x == 0.0 && normal.y == 0.0
+}
+
+/// Maps a point on a circular cap of the given radius, centered on the z axis and facing along
+/// `normal`, by its longitude `u` and its distance from the center as a fraction of `radius`.
+/// Caps facing +z are mapped from the rim inwards, so that they're mapped as seen from outside.
+fn cap_mapping(
+    point: Point3<f32>,
+    normal: Vector3<f32>,
+    u: f32,
+    dpdu: Vector3<f32>,
+    radius: f32,
+) -> SurfaceMapping {
+    let distance = (point.x * point.x + point.y * point.y).sqrt() / radius;
+    let (sin, cos) = (2.0 * PI * u).sin_cos();
+    let outwards = Vector3 { x: cos, y: sin, z: 0.0 } * radius;
+    if normal.z > 0.0 {
+        (Vector2 { x: u, y: 1.0 - distance }, dpdu, -outwards)
+    } else {
+        (Vector2 { x: u, y: distance }, dpdu, outwards)
+    }
+}
+
+/// Returns the section of a ray between the planes perpendicular to `normal` at `min` and `max`,
+/// given the components of the ray's origin and direction along `normal`.
+fn slab_span(
+    origin: f32,
+    direction: f32,
+    min: f32,
+    max: f32,
+    normal: Vector3<f32>,
+) -> Option<Span> {
+    if direction == 0.0 {
+        return if origin >= min && origin <= max { Some(Span::everywhere()) } else { None };
+    }
+    let (t_min, t_max) = ((min - origin) / direction, (max - origin) / direction);
+    Some(if t_min < t_max {
+        Span { enter: t_min, enter_normal: -normal, exit: t_max, exit_normal: normal }
+    } else {
+        Span { enter: t_max, enter_normal: normal, exit: t_min, exit_normal: -normal }
+    })
+}
+
+/// Returns the section of `ray` inside the axis-aligned box from `min` to `max`.
+fn box_span(min: Point3<f32>, max: Point3<f32>, ray: &Ray3<f32>) -> Option<Span> {
+    let mut span = Span::everywhere();
+    for axis in 0..3 {
+        let mut normal = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+        normal[axis] = 1.0;
+        let slab = slab_span(ray.origin[axis], ray.direction[axis], min[axis], max[axis], normal)?;
+        span = span.intersect(&slab)?;
+    }
+    Some(span)
+}
+
+/// Returns the sections of `ray` where `a t^2 + b t + c <= 0`, which is the inside of a quadric
+/// surface, using `normal` to compute the surface's normal at a point on it.
+fn quadric_spans<F>(a: f32, b: f32, c: f32, normal: F, ray: &Ray3<f32>) -> Vec<Span>
+    where F: Fn(Point3<f32>) -> Vector3<f32>
+{
+    let span = |enter: f32, exit: f32| {
+        let normal_at = |t: f32| if t.is_infinite() {
+            Vector3 { x: 0.0, y: 0.0, z: 0.0 }
+        } else {
+            normal(ray.origin + ray.direction * t)
+        };
+        Span { enter, enter_normal: normal_at(enter), exit, exit_normal: normal_at(exit) }
+    };
+    if a == 0.0 {
+        if b == 0.0 {
+            return if c <= 0.0 { vec![Span::everywhere()] } else { vec![] };
+        }
+        let t = -c / b;
+        return vec![if b > 0.0 { span(-f32::INFINITY, t) } else { span(t, f32::INFINITY) }];
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return if a > 0.0 { vec![] } else { vec![Span::everywhere()] };
+    }
+    // This form avoids cancellation when one root is much smaller than the other.
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    let (t0, t1) = (t0.min(t1), t0.max(t1));
+    if a > 0.0 {
+        vec![span(t0, t1)]
+    } else {
+        vec![span(-f32::INFINITY, t0), span(t1, f32::INFINITY)]
+    }
+}
+
+/// Returns the distances along the ray from `origin` in the normalized direction `direction` at
+/// which it enters and leaves the sphere of radius `radius` centered at the origin.
+fn sphere_span(origin: Vector3<f32>, direction: Vector3<f32>, radius: f32) -> Option<(f32, f32)> {
+    let b = origin.dot(direction);
+    let discriminant = b * b - (origin.magnitude2() - radius * radius);
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    Some((-b - root, -b + root))
+}
+
+/// Returns the roots of the polynomial with the given coefficients, constant term first, that lie
+/// between `min` and `max`, in increasing order. Only roots where the polynomial changes sign are
+/// found.
+fn polynomial_roots(coefficients: &[f64], min: f64, max: f64) -> Vec<f64> {
+    if coefficients.len() < 2 {
+        return vec![];
+    }
+    let evaluate = |x: f64| coefficients.iter().rev().fold(0.0, |sum, c| sum * x + c);
+
+    // The polynomial is monotonic between the roots of its derivative, so each of those
+    // sections contains at most one root, which we find by bisection.
+    let derivative: Vec<f64> = coefficients[1..].iter()
+        .enumerate()
+        .map(|(power, c)| c * (power + 1) as f64)
+        .collect();
+    let mut bounds = vec![min];
+    bounds.extend(polynomial_roots(&derivative, min, max));
+    bounds.push(max);
+
+    let mut roots = vec![];
+    for section in bounds.windows(2) {
+        let (mut low, mut high) = (section[0], section[1]);
+        let low_negative = evaluate(low) < 0.0;
+        if low_negative == (evaluate(high) < 0.0) {
+            continue;
+        }
+        for _ in 0..64 {
+            let middle = 0.5 * (low + high);
+            if (evaluate(middle) < 0.0) == low_negative {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        roots.push(0.5 * (low + high));
+    }
+    roots
+}
+
+/// An orthonormal frame whose z axis is the axis of a primitive.
+#[derive(Copy, Clone, Debug)]
+struct LocalFrame {
+    origin: Point3<f32>,
+    x: Vector3<f32>,
+    y: Vector3<f32>,
+    z: Vector3<f32>,
+}
+
+impl LocalFrame {
+    fn new(origin: Point3<f32>, axis: Vector3<f32>) -> Self {
+        let (x, y) = orthonormal_basis(axis);
+        LocalFrame { origin, x, y, z: axis }
+    }
+
+    fn point_to_local(&self, point: Point3<f32>) -> Point3<f32> {
+        let offset = point - self.origin;
+        Point3 { x: offset.dot(self.x), y: offset.dot(self.y), z: offset.dot(self.z) }
+    }
+
+    fn ray_to_local(&self, ray: &Ray3<f32>) -> Ray3<f32> {
+        Ray3::new(
+            self.point_to_local(ray.origin),
+            Vector3 {
+                x: ray.direction.dot(self.x),
+                y: ray.direction.dot(self.y),
+                z: ray.direction.dot(self.z),
+            },
+        )
+    }
+
+    fn vector_to_world(&self, vector: Vector3<f32>) -> Vector3<f32> {
+        self.x * vector.x + self.y * vector.y + self.z * vector.z
+    }
+
+    /// Returns the world space bounds of the local box from `min` to `max`.
+    fn bounds(&self, min: Point3<f32>, max: Point3<f32>) -> Option<Aabb3<f32>> {
+        bounds_of((0..8).map(|corner| {
+            self.origin + self.vector_to_world(Vector3 {
+                x: if corner & 1 == 0 { min.x } else { max.x },
+                y: if corner & 2 == 0 { min.y } else { max.y },
+                z: if corner & 4 == 0 { min.z } else { max.z },
+            })
+        }))
+    }
+
+    /// Returns the crossing at distance `t` along `ray`, given the surface's normal and mapping in
+    /// this frame.
+    fn crossing(
+        &self,
+        ray: &Ray3<f32>,
+        t: f32,
+        local_normal: Vector3<f32>,
+        local_mapping: SurfaceMapping,
+    ) -> SolidCrossing {
+        let (uv, dpdu, dpdv) = local_mapping;
+        let mapping = (uv, self.vector_to_world(dpdu), self.vector_to_world(dpdv));
+        world_crossing(ray, t, self.vector_to_world(local_normal), mapping)
+    }
+
+    /// Returns the world space intervals of a convex primitive made by cutting each of the local
+    /// spans `sides` of `local_ray`, which is `ray` in this frame, with the span `caps`.
+    /// `mapping` gives the surface's texture coordinates and tangents, as for `Span::interval`.
+    fn intervals<M>(
+        &self,
+        ray: &Ray3<f32>,
+        local_ray: &Ray3<f32>,
+        sides: Vec<Span>,
+        caps: Option<Span>,
+        mapping: M,
+    ) -> Vec<SolidInterval>
+        where M: Fn(Point3<f32>, Vector3<f32>) -> SurfaceMapping
+    {
+        let caps = match caps {
+            Some(caps) => caps,
+            None => return vec![],
+        };
+        sides.iter()
+            .filter_map(|side| side.intersect(&caps))
+            // Sections that only touch the surface, e.g. at the apex of a cone, aren't inside it.
+            .filter(|span| span.enter < span.exit)
+            .map(|span| {
+                span.interval(ray, local_ray, |vector| self.vector_to_world(vector), &mapping)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::{
+        Deg,
+        Rotation3,
+    };
+    use collision::Sphere;
+
+    fn ray(origin: (f32, f32, f32), direction: (f32, f32, f32)) -> Ray3<f32> {
+        Ray3::new(
+            Point3 { x: origin.0, y: origin.1, z: origin.2 },
+            Vector3 { x: direction.0, y: direction.1, z: direction.2 }.normalize(),
+        )
+    }
+
+    fn assert_hit(
+        solid: &Solid,
+        ray: &Ray3<f32>,
+        point: (f32, f32, f32),
+        normal: (f32, f32, f32),
+        front_face: bool,
+    ) {
+        let hit = solid.trace(ray).expect("expected a hit");
+        let point = Point3 { x: point.0, y: point.1, z: point.2 };
+        let normal = Vector3 { x: normal.0, y: normal.1, z: normal.2 }.normalize();
+        assert!((hit.point - point).magnitude() < 1e-4, "{:?} != {:?}", hit.point, point);
+        assert!((hit.normal - normal).magnitude() < 1e-4, "{:?} != {:?}", hit.normal, normal);
+        assert_eq!(hit.front_face, front_face);
+    }
+
+    #[test]
+    fn test_aabb() {
+        let aabb = Aabb3::new(
+            Point3 { x: -1.0, y: -1.0, z: -1.0 },
+            Point3 { x: 1.0, y: 1.0, z: 1.0 },
+        );
+        assert_hit(
+            &aabb,
+            &ray((-5.0, 0.5, 0.0), (1.0, 0.0, 0.0)),
+            (-1.0, 0.5, 0.0),
+            (-1.0, 0.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &aabb,
+            &ray((0.0, 0.0, 0.0), (0.0, 0.0, 1.0)),
+            (0.0, 0.0, 1.0),
+            (0.0, 0.0, 1.0),
+            false,
+        );
+        assert_hit(
+            &aabb,
+            &ray((0.5, 5.0, 0.5), (0.0, -1.0, 0.0)),
+            (0.5, 1.0, 0.5),
+            (0.0, 1.0, 0.0),
+            true,
+        );
+        assert!(aabb.trace(&ray((-5.0, 1.5, 0.0), (1.0, 0.0, 0.0))).is_none());
+        assert!(aabb.trace(&ray((5.0, 0.0, 0.0), (1.0, 0.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn test_oriented_box() {
+        // A 4 x 2 x 2 box, turned so that its long side runs along y.
+        let oriented_box = OrientedBox {
+            center: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            half_extents: Vector3 { x: 2.0, y: 1.0, z: 1.0 },
+            orientation: Quaternion::from_angle_z(Deg(90.0)),
+        };
+        assert_hit(
+            &oriented_box,
+            &ray((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0)),
+            (-1.0, 0.0, 0.0),
+            (-1.0, 0.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &oriented_box,
+            &ray((0.5, 5.0, 0.0), (0.0, -1.0, 0.0)),
+            (0.5, 2.0, 0.0),
+            (0.0, 1.0, 0.0),
+            true,
+        );
+        let bounds = oriented_box.bounds().unwrap();
+        assert!((bounds.max - Point3 { x: 1.0, y: 2.0, z: 1.0 }).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn test_cylinder() {
+        let cylinder = Cylinder {
+            base: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            axis: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            radius: 1.0,
+            height: 2.0,
+        };
+        assert_hit(
+            &cylinder,
+            &ray((-5.0, 1.0, 0.0), (1.0, 0.0, 0.0)),
+            (-1.0, 1.0, 0.0),
+            (-1.0, 0.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &cylinder,
+            &ray((0.5, 5.0, 0.0), (0.0, -1.0, 0.0)),
+            (0.5, 2.0, 0.0),
+            (0.0, 1.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &cylinder,
+            &ray((0.0, -5.0, 0.5), (0.0, 1.0, 0.0)),
+            (0.0, 0.0, 0.5),
+            (0.0, -1.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &cylinder,
+            &ray((0.0, 1.0, 0.0), (0.0, 0.0, 1.0)),
+            (0.0, 1.0, 1.0),
+            (0.0, 0.0, 1.0),
+            false,
+        );
+        // Above the top cap.
+        assert!(cylinder.trace(&ray((-5.0, 3.0, 0.0), (1.0, 0.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn test_cone() {
+        let cone = Cone {
+            base: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            axis: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            radius: 1.0,
+            height: 1.0,
+        };
+        // Halfway up, the cone's radius is 0.5 and its side slopes at 45 degrees.
+        assert_hit(
+            &cone,
+            &ray((-5.0, 0.5, 0.0), (1.0, 0.0, 0.0)),
+            (-0.5, 0.5, 0.0),
+            (-1.0, 1.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &cone,
+            &ray((0.25, -5.0, 0.0), (0.0, 1.0, 0.0)),
+            (0.25, 0.0, 0.0),
+            (0.0, -1.0, 0.0),
+            true,
+        );
+        // Above the apex, the ray would hit the other nappe of the double cone.
+        assert!(cone.trace(&ray((-5.0, 1.5, 0.0), (1.0, 0.0, 0.0))).is_none());
+        assert_eq!(cone.intervals(&ray((0.0, 5.0, 0.0), (0.0, -1.0, 0.0))).len(), 1);
+    }
+
+    #[test]
+    fn test_disk() {
+        let disk = Disk {
+            center: Point3 { x: 0.0, y: 1.0, z: 0.0 },
+            normal: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            radius: 1.0,
+        };
+        assert_hit(
+            &disk,
+            &ray((0.5, 5.0, 0.0), (0.0, -1.0, 0.0)),
+            (0.5, 1.0, 0.0),
+            (0.0, 1.0, 0.0),
+            true,
+        );
+        assert_hit(
+            &disk,
+            &ray((0.0, -5.0, -0.5), (0.0, 1.0, 0.0)),
+            (0.0, 1.0, -0.5),
+            (0.0, 1.0, 0.0),
+            false,
+        );
+        assert!(disk.trace(&ray((1.5, 5.0, 0.0), (0.0, -1.0, 0.0))).is_none());
+        let bounds = disk.bounds().unwrap();
+        assert_eq!(bounds.min, Point3 { x: -1.0, y: 1.0, z: -1.0 });
+        assert_eq!(bounds.max, Point3 { x: 1.0, y: 1.0, z: 1.0 });
+    }
+
+    #[test]
+    fn test_quad() {
+        // A 2 x 1 rectangle in the y = 0 plane, facing +y.
+        let quad = Quad {
+            corner: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            edge1: Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            edge2: Vector3 { x: 2.0, y: 0.0, z: 0.0 },
+        };
+        assert_hit(
+            &quad,
+            &ray((1.5, 5.0, 0.5), (0.0, -1.0, 0.0)),
+            (1.5, 0.0, 0.5),
+            (0.0, 1.0, 0.0),
+            true,
+        );
+        let hit = quad.trace(&ray((1.5, 5.0, 0.5), (0.0, -1.0, 0.0))).unwrap();
+        assert!((hit.uv - Vector2 { x: 0.5, y: 0.75 }).magnitude() < 1e-5);
+        assert_eq!((hit.dpdu, hit.dpdv), (quad.edge1, quad.edge2));
+        assert!(quad.trace(&ray((2.5, 5.0, 0.5), (0.0, -1.0, 0.0))).is_none());
+        assert!(quad.trace(&ray((1.5, 5.0, -0.5), (0.0, -1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn test_torus() {
+        let torus = Torus {
+            center: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            axis: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            major_radius: 2.0,
+            minor_radius: 0.5,
+        };
+        let through_ring = ray((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        assert_hit(&torus, &through_ring, (-2.5, 0.0, 0.0), (-1.0, 0.0, 0.0), true);
+        let intervals = torus.intervals(&through_ring);
+        let distances: Vec<(f32, f32)> = intervals.iter()
+            .map(|interval| (interval.enter.t, interval.exit.t))
+            .collect();
+        assert_eq!(distances.len(), 2);
+        for (actual, expected) in distances.iter().zip(&[(2.5, 3.5), (6.5, 7.5)]) {
+            assert!((actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4);
+        }
+        assert_hit(
+            &torus,
+            &ray((2.0, 5.0, 0.0), (0.0, -1.0, 0.0)),
+            (2.0, 0.5, 0.0),
+            (0.0, 1.0, 0.0),
+            true,
+        );
+        assert!(torus.trace(&ray((0.0, 5.0, 0.0), (0.0, -1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn test_surface_mapping() {
+        let solids: Vec<Box<Solid>> = vec![
+            Box::new(Sphere { center: Point3 { x: 0.0, y: 0.0, z: 0.0 }, radius: 1.0 }),
+            Box::new(Aabb3::new(
+                Point3 { x: -1.0, y: -1.0, z: -1.0 },
+                Point3 { x: 1.0, y: 1.0, z: 1.0 },
+            )),
+            Box::new(Cylinder {
+                base: Point3 { x: 0.0, y: -1.0, z: 0.0 },
+                axis: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+                radius: 1.0,
+                height: 2.0,
+            }),
+            Box::new(Cone {
+                base: Point3 { x: 0.0, y: -1.0, z: 0.0 },
+                axis: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+                radius: 1.0,
+                height: 2.0,
+            }),
+            Box::new(Disk {
+                center: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                normal: Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+                radius: 1.0,
+            }),
+            Box::new(Torus {
+                center: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                axis: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+                major_radius: 0.7,
+                minor_radius: 0.3,
+            }),
+        ];
+        let rays = [
+            ray((0.3, 0.2, 5.0), (0.0, 0.0, -1.0)),
+            ray((-5.0, 0.1, 0.6), (1.0, 0.0, 0.0)),
+            ray((0.2, 5.0, -0.7), (0.0, -1.0, 0.0)),
+            ray((0.8, -5.0, 0.1), (0.0, 1.0, 0.0)),
+        ];
+        for (i, solid) in solids.iter().enumerate() {
+            for ray in &rays {
+                let hit = match solid.trace(ray) {
+                    Some(hit) => hit,
+                    None => continue,
+                };
+                assert!((hit.point - (ray.origin + ray.direction * hit.t)).magnitude() < 1e-5);
+                assert!(hit.uv.x >= 0.0 && hit.uv.x <= 1.0, "{}: {:?}", i, hit.uv);
+                assert!(hit.uv.y >= -1e-5 && hit.uv.y <= 1.0 + 1e-5, "{}: {:?}", i, hit.uv);
+                // The tangents lie in the surface, and are oriented so that their cross product
+                // points out of it.
+                assert!(hit.dpdu.dot(hit.normal).abs() < 1e-4, "{}: {:?}", i, hit);
+                assert!(hit.dpdv.dot(hit.normal).abs() < 1e-4, "{}: {:?}", i, hit);
+                assert!(hit.dpdu.cross(hit.dpdv).dot(hit.normal) > 0.0, "{}: {:?}", i, hit);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_surface() {
+        let center = Point3 { x: 1.0, y: 2.0, z: 3.0 };
+        let normal = Vector3 { x: 1.0, y: 1.0, z: 0.0 }.normalize();
+        let solids: Vec<(Box<Solid>, f32)> = vec![
+            (Box::new(Sphere { center, radius: 2.0 }), 16.0 * PI),
+            (Box::new(Disk { center, normal, radius: 2.0 }), 4.0 * PI),
+            (
+                Box::new(Quad {
+                    corner: center + Vector3 { x: -1.0, y: 0.0, z: -2.0 },
+                    edge1: Vector3 { x: 2.0, y: 0.0, z: 0.0 },
+                    edge2: Vector3 { x: 0.0, y: 0.0, z: 4.0 },
+                }),
+                8.0,
+            ),
+        ];
+        let mut rng = Rng::new(1);
+        for (solid, area) in solids {
+            assert!((solid.surface_area().unwrap() - area).abs() < 1e-4);
+            let samples = 2000;
+            let mut mean = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+            for _ in 0..samples {
+                let sample = solid.sample_surface(rng.next_f32(), rng.next_f32()).unwrap();
+                // The point is on the surface, and the normal matches the one found by tracing.
+                let above = sample.point + sample.normal * 0.01;
+                let hit = solid.trace(&Ray3::new(above, -sample.normal)).unwrap();
+                assert!((hit.t - 0.01).abs() < 1e-3);
+                assert!((hit.normal - sample.normal).magnitude() < 1e-3);
+                assert!(sample.front_face && sample.t == 0.0);
+                // Points on the seam of the sphere may round to either side of it.
+                let du = (hit.uv.x - sample.uv.x).abs();
+                assert!(du.min(1.0 - du) < 1e-3 && (hit.uv.y - sample.uv.y).abs() < 1e-3);
+                mean = mean + (sample.point - center) / samples as f32;
+            }
+            // All of the solids are centered on `center`, so uniformly distributed points
+            // average out there.
+            assert!(mean.magnitude() < 0.1);
+        }
+        let torus = Torus {
+            center,
+            axis: normal,
+            major_radius: 0.7,
+            minor_radius: 0.3,
+        };
+        assert!(torus.sample_surface(0.5, 0.5).is_none());
+    }
+}
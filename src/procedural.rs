@@ -0,0 +1,417 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use cgmath::{
+    EuclideanSpace,
+    Matrix4,
+    Point3,
+    SquareMatrix,
+    Transform,
+};
+use color::*;
+use sampling::*;
+use texture::*;
+
+/// The space in which a procedural texture is evaluated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureSpace {
+    /// The hit's texture coordinates, as the point `(u, v, 0)`.
+    Uv,
+
+    /// The hit's point in world space.
+    World,
+}
+
+/// Determines the point at which a procedural texture is evaluated for a hit: a point in `space`,
+/// transformed by `transform`. The transform scales and places the pattern; to evaluate a
+/// texture in an object's own space, use `World` with the inverse of the object's transform.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TextureMapping {
+    pub space: TextureSpace,
+    pub transform: Matrix4<f32>,
+}
+
+impl TextureMapping {
+    pub fn uv() -> Self {
+        TextureMapping { space: TextureSpace::Uv, transform: Matrix4::identity() }
+    }
+
+    pub fn world() -> Self {
+        TextureMapping { space: TextureSpace::World, transform: Matrix4::identity() }
+    }
+
+    /// Returns this mapping with the pattern made `scale` times smaller, i.e. repeating `scale`
+    /// times as often.
+    pub fn scaled(&self, scale: f32) -> Self {
+        TextureMapping {
+            transform: Matrix4::from_scale(scale) * self.transform,
+            ..*self
+        }
+    }
+
+    pub fn point(&self, context: &ShadingContext) -> Point3<f32> {
+        let point = match self.space {
+            TextureSpace::Uv => Point3 { x: context.hit.uv.x, y: context.hit.uv.y, z: 0.0 },
+            TextureSpace::World => context.hit.point,
+        };
+        self.transform.transform_point(point)
+    }
+}
+
+impl Default for TextureMapping {
+    fn default() -> Self {
+        TextureMapping::uv()
+    }
+}
+
+/// Ken Perlin's improved gradient noise: a smooth, random function of a point with values in
+/// roughly [-1, 1], which varies over distances of about 1.
+#[derive(Clone, Debug)]
+pub struct PerlinNoise {
+    /// A random permutation of 0 to 255, repeated twice so that lookups don't need to wrap.
+    permutation: Vec<u8>,
+}
+
+impl PerlinNoise {
+    /// Creates noise with a pattern that's determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut permutation: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        for i in (1..256).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            permutation.swap(i, j);
+        }
+        let repeated = permutation.clone();
+        permutation.extend(repeated);
+        PerlinNoise { permutation }
+    }
+
+    pub fn noise(&self, point: Point3<f32>) -> f32 {
+        let (x0, y0, z0) = (point.x.floor(), point.y.floor(), point.z.floor());
+        let (x, y, z) = (point.x - x0, point.y - y0, point.z - z0);
+        let cell = |value: f32| (value as i64 & 255) as usize;
+        let (xi, yi, zi) = (cell(x0), cell(y0), cell(z0));
+        let p = &self.permutation;
+        let hash = |dx: usize, dy: usize, dz: usize| {
+            p[p[p[xi + dx] as usize + yi + dy] as usize + zi + dz]
+        };
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, gradient(hash(0, 0, 0), x, y, z), gradient(hash(1, 0, 0), x - 1.0, y, z)),
+                lerp(
+                    u,
+                    gradient(hash(0, 1, 0), x, y - 1.0, z),
+                    gradient(hash(1, 1, 0), x - 1.0, y - 1.0, z),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    gradient(hash(0, 0, 1), x, y, z - 1.0),
+                    gradient(hash(1, 0, 1), x - 1.0, y, z - 1.0),
+                ),
+                lerp(
+                    u,
+                    gradient(hash(0, 1, 1), x, y - 1.0, z - 1.0),
+                    gradient(hash(1, 1, 1), x - 1.0, y - 1.0, z - 1.0),
+                ),
+            ),
+        )
+    }
+
+    /// Fractional Brownian motion: the sum of `octaves` layers of noise, each with twice the
+    /// frequency and half the amplitude of the last. The result is in roughly [-1, 1].
+    pub fn fbm(&self, point: Point3<f32>, octaves: usize) -> f32 {
+        self.octaves(point, octaves, |noise| noise)
+    }
+
+    /// Like `fbm`, but sums the absolute values of the layers, which gives sharp creases where
+    /// the noise crosses zero. The result is in roughly [0, 1].
+    pub fn turbulence(&self, point: Point3<f32>, octaves: usize) -> f32 {
+        self.octaves(point, octaves, f32::abs)
+    }
+
+    fn octaves<F>(&self, point: Point3<f32>, octaves: usize, layer: F) -> f32
+        where F: Fn(f32) -> f32
+    {
+        let (mut sum, mut total_amplitude) = (0.0, 0.0);
+        let (mut frequency, mut amplitude) = (1.0, 1.0);
+        for _ in 0..octaves.max(1) {
+            sum += amplitude * layer(self.noise(Point3::from_vec(point.to_vec() * frequency)));
+            total_amplitude += amplitude;
+            frequency *= 2.0;
+            amplitude *= 0.5;
+        }
+        sum / total_amplitude
+    }
+}
+
+impl Default for PerlinNoise {
+    fn default() -> Self {
+        PerlinNoise::new(0)
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// Returns the dot product of `(x, y, z)` with one of 12 gradients, chosen by `hash`.
+fn gradient(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// Returns `a` blended towards `b` by `t`, which is clamped to [0, 1].
+fn mix(a: Color, b: Color, t: f32) -> Color {
+    let t = t.max(0.0).min(1.0);
+    a * (1.0 - t) + b * t
+}
+
+/// Alternating squares (or cubes) of two textures, one unit across in the mapping's space.
+#[derive(Clone)]
+pub struct Checkerboard {
+    pub even: Arc<Texture>,
+    pub odd: Arc<Texture>,
+    pub mapping: TextureMapping,
+
+    /// If set, the pattern is a 3D lattice of cubes, so that objects look carved out of it.
+    /// Otherwise it's a 2D pattern in the mapping's x and y coordinates.
+    pub solid: bool,
+}
+
+impl Texture for Checkerboard {
+    fn color(&self, context: &ShadingContext) -> Color {
+        let point = self.mapping.point(context);
+        let mut sum = point.x.floor() as i64 + point.y.floor() as i64;
+        if self.solid {
+            sum += point.z.floor() as i64;
+        }
+        if sum % 2 == 0 {
+            self.even.color(context)
+        } else {
+            self.odd.color(context)
+        }
+    }
+}
+
+/// The kinds of pattern a `NoiseTexture` can show.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NoisePattern {
+    /// Smooth, cloudy variation, from `PerlinNoise::fbm`.
+    Fbm,
+
+    /// Billowy variation with sharp creases, from `PerlinNoise::turbulence`.
+    Turbulence,
+}
+
+/// A blend between two textures controlled by noise.
+#[derive(Clone)]
+pub struct NoiseTexture {
+    pub noise: PerlinNoise,
+    pub pattern: NoisePattern,
+    pub octaves: usize,
+    pub mapping: TextureMapping,
+
+    /// The texture shown where the noise is lowest.
+    pub low: Arc<Texture>,
+
+    /// The texture shown where the noise is highest.
+    pub high: Arc<Texture>,
+}
+
+impl Texture for NoiseTexture {
+    fn color(&self, context: &ShadingContext) -> Color {
+        let point = self.mapping.point(context);
+        let value = match self.pattern {
+            NoisePattern::Fbm => 0.5 + 0.5 * self.noise.fbm(point, self.octaves),
+            NoisePattern::Turbulence => self.noise.turbulence(point, self.octaves),
+        };
+        mix(self.low.color(context), self.high.color(context), value)
+    }
+}
+
+/// Marble: parallel bands along the mapping's x axis, distorted by turbulence into veins.
+#[derive(Clone)]
+pub struct Marble {
+    pub noise: PerlinNoise,
+    pub octaves: usize,
+    pub mapping: TextureMapping,
+
+    /// The number of bands per unit.
+    pub frequency: f32,
+
+    /// How far the bands are distorted, in units of half a band.
+    pub distortion: f32,
+
+    pub base: Arc<Texture>,
+    pub veins: Arc<Texture>,
+}
+
+impl Texture for Marble {
+    fn color(&self, context: &ShadingContext) -> Color {
+        let point = self.mapping.point(context);
+        let phase = point.x * self.frequency
+            + self.distortion * self.noise.turbulence(point, self.octaves);
+        // The veins are the narrow troughs of a sine wave.
+        let band = 0.5 + 0.5 * (phase * PI).sin();
+        mix(self.veins.color(context), self.base.color(context), band.sqrt())
+    }
+}
+
+/// Wood: concentric growth rings around the mapping's y axis, made irregular by noise.
+#[derive(Clone)]
+pub struct Wood {
+    pub noise: PerlinNoise,
+    pub mapping: TextureMapping,
+
+    /// The number of rings per unit of distance from the axis.
+    pub ring_frequency: f32,
+
+    /// How far the rings are distorted, as a fraction of the distance between them.
+    pub distortion: f32,
+
+    /// The color of the wood between rings.
+    pub light: Arc<Texture>,
+
+    /// The color of the rings.
+    pub dark: Arc<Texture>,
+}
+
+impl Texture for Wood {
+    fn color(&self, context: &ShadingContext) -> Color {
+        let point = self.mapping.point(context);
+        let radius = (point.x * point.x + point.z * point.z).sqrt();
+        let rings = radius * self.ring_frequency + self.distortion * self.noise.noise(point);
+        // Each ring fades from light to dark, then ends sharply.
+        mix(self.light.color(context), self.dark.color(context), rings - rings.floor())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::{
+        Vector2,
+        Vector3,
+    };
+    use trace::*;
+
+    fn context(point: Point3<f32>, uv: Vector2<f32>) -> ShadingContext {
+        ShadingContext::new(SolidHit {
+            t: 1.0,
+            point,
+            normal: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            uv,
+            dpdu: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            dpdv: Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            front_face: true,
+        })
+    }
+
+    fn at_point(x: f32, y: f32, z: f32) -> ShadingContext {
+        context(Point3 { x, y, z }, Vector2 { x: 0.0, y: 0.0 })
+    }
+
+    fn black() -> Arc<Texture> {
+        Arc::new(Color::from_rgb(0.0, 0.0, 0.0))
+    }
+
+    fn white() -> Arc<Texture> {
+        Arc::new(Color::from_rgb(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn test_checkerboard() {
+        let checkerboard = |mapping, solid| Checkerboard {
+            even: black(),
+            odd: white(),
+            mapping,
+            solid,
+        };
+        let uv = checkerboard(TextureMapping::uv().scaled(2.0), false);
+        let at_uv = |u, v| context(Point3 { x: 0.0, y: 0.0, z: 0.0 }, Vector2 { x: u, y: v });
+        assert_eq!(uv.color(&at_uv(0.25, 0.25)).r, 0.0);
+        assert_eq!(uv.color(&at_uv(0.75, 0.25)).r, 1.0);
+        assert_eq!(uv.color(&at_uv(0.75, 0.75)).r, 0.0);
+        assert_eq!(uv.color(&at_uv(-0.25, 0.25)).r, 1.0);
+
+        let flat = checkerboard(TextureMapping::world(), false);
+        let solid = checkerboard(TextureMapping::world(), true);
+        assert_eq!(flat.color(&at_point(0.5, 0.5, 1.5)).r, 0.0);
+        assert_eq!(solid.color(&at_point(0.5, 0.5, 1.5)).r, 1.0);
+    }
+
+    #[test]
+    fn test_noise() {
+        let noise = PerlinNoise::default();
+        // Noise is zero at the lattice points, and varies smoothly between them.
+        assert_eq!(noise.noise(Point3 { x: 3.0, y: -2.0, z: 7.0 }), 0.0);
+        let mut values = vec![];
+        for i in 0..1000 {
+            let point = Point3 { x: i as f32 * 0.37, y: i as f32 * 0.11, z: i as f32 * 0.07 };
+            let value = noise.noise(point);
+            assert!(value >= -1.0 && value <= 1.0);
+            let nearby = noise.noise(point + Vector3 { x: 1e-3, y: 0.0, z: 0.0 });
+            assert!((value - nearby).abs() < 1e-2);
+            let turbulence = noise.turbulence(point, 4);
+            assert!(turbulence >= 0.0 && turbulence <= 1.0);
+            values.push(value);
+        }
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!(mean.abs() < 0.1, "{}", mean);
+        assert!(values.iter().any(|&value| value > 0.2));
+        assert!(values.iter().any(|&value| value < -0.2));
+
+        // The pattern depends on the seed.
+        let point = Point3 { x: 0.5, y: 0.5, z: 0.5 };
+        assert_eq!(noise.fbm(point, 3), PerlinNoise::new(0).fbm(point, 3));
+        assert!(noise.fbm(point, 3) != PerlinNoise::new(1).fbm(point, 3));
+    }
+
+    #[test]
+    fn test_marble_and_wood() {
+        let marble = Marble {
+            noise: PerlinNoise::default(),
+            octaves: 4,
+            mapping: TextureMapping::world(),
+            frequency: 1.0,
+            distortion: 0.0,
+            base: white(),
+            veins: black(),
+        };
+        // Without distortion, the veins are at odd multiples of half a band.
+        assert!(marble.color(&at_point(-0.5, 0.3, 0.2)).r < 1e-3);
+        assert!(marble.color(&at_point(0.5, 0.3, 0.2)).r > 0.999);
+
+        let wood = Wood {
+            noise: PerlinNoise::default(),
+            mapping: TextureMapping::world(),
+            ring_frequency: 2.0,
+            distortion: 0.0,
+            light: white(),
+            dark: black(),
+        };
+        // The rings are circles around the y axis.
+        let on_ring = |radius: f32, angle: f32| {
+            wood.color(&at_point(radius * angle.cos(), 0.7, radius * angle.sin())).r
+        };
+        assert!((on_ring(0.3, 0.0) - 0.4).abs() < 1e-5);
+        assert!((on_ring(0.3, 2.0) - 0.4).abs() < 1e-5);
+        assert!((on_ring(0.55, 1.0) - 0.9).abs() < 1e-5);
+    }
+}
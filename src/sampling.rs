@@ -0,0 +1,251 @@
+use std::f32::consts::PI;
+
+use cgmath::{
+    InnerSpace,
+    Vector3,
+};
+
+/// A small, fast pseudo-random number generator (SplitMix64). It's deterministic given its seed,
+/// which keeps renders reproducible.
+#[derive(Copy, Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// Returns a generator for the pixel `(x, y)` of an image rendered with `seed`. Each pixel
+    /// gets its own independent stream, so the samples taken for a pixel don't depend on the
+    /// order in which pixels are rendered.
+    pub fn for_pixel(seed: u64, x: usize, y: usize) -> Self {
+        let pixel = ((x as u64) << 32) | y as u64;
+        let mut rng = Rng::new(seed ^ pixel.wrapping_mul(0x9E3779B97F4A7C15));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number uniformly distributed in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// The pattern used to place the samples taken within each pixel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelSampler {
+    /// Samples are placed at the centers of the cells of a grid covering the pixel.
+    Regular,
+
+    /// Samples are placed at random positions within the cells of a grid covering the pixel.
+    Stratified,
+
+    /// Samples are placed at random positions anywhere within the pixel.
+    Random,
+}
+
+impl PixelSampler {
+    /// Returns `count` sample positions within a pixel, each in `[0, 1) x [0, 1)`.
+    ///
+    /// The grid used by `Regular` and `Stratified` has `ceil(sqrt(count))` columns; if `count`
+    /// isn't a multiple of that, the cells of the last row are made wider so that the grid still
+    /// covers the whole pixel.
+    pub fn samples(&self, count: usize, rng: &mut Rng) -> Vec<(f32, f32)> {
+        if count == 0 {
+            return vec![];
+        }
+        let columns = (count as f32).sqrt().ceil() as usize;
+        let rows = (count + columns - 1) / columns;
+        (0..count).map(|i| {
+            let (row, column) = (i / columns, i % columns);
+            let row_columns = if row + 1 == rows { count - row * columns } else { columns };
+            let (cell_width, cell_height) = (1.0 / row_columns as f32, 1.0 / rows as f32);
+            match *self {
+                PixelSampler::Regular => (
+                    (column as f32 + 0.5) * cell_width,
+                    (row as f32 + 0.5) * cell_height,
+                ),
+                PixelSampler::Stratified => (
+                    (column as f32 + rng.next_f32()) * cell_width,
+                    (row as f32 + rng.next_f32()) * cell_height,
+                ),
+                PixelSampler::Random => (rng.next_f32(), rng.next_f32()),
+            }
+        }).collect()
+    }
+}
+
+/// Returns two unit vectors that together with the unit vector `normal` form an orthonormal
+/// basis.
+pub fn orthonormal_basis(normal: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vector3 { x: 0.0, y: 1.0, z: 0.0 }
+    } else {
+        Vector3 { x: 1.0, y: 0.0, z: 0.0 }
+    };
+    let tangent = normal.cross(helper).normalize();
+    let bitangent = normal.cross(tangent);
+    (tangent, bitangent)
+}
+
+/// Maps the uniformly distributed sample `(u1, u2)` to a direction in the hemisphere around the
+/// unit vector `normal`, distributed with density `cos(theta) / PI`.
+pub fn cosine_sample_hemisphere(normal: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    let radius = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    let z = (1.0 - u1).max(0.0).sqrt();
+    tangent * (radius * phi.cos()) + bitangent * (radius * phi.sin()) + normal * z
+}
+
+/// Maps the uniformly distributed sample `(u1, u2)` to a direction around the unit vector `axis`,
+/// distributed with density `(exponent + 1) / (2 * PI) * cos(alpha)^exponent`, where `alpha` is the
+/// angle to `axis`. This is the shape of a Phong specular lobe.
+pub fn sample_phong_lobe(axis: Vector3<f32>, exponent: f32, u1: f32, u2: f32) -> Vector3<f32> {
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    let z = u1.powf(1.0 / (exponent + 1.0));
+    let radius = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    tangent * (radius * phi.cos()) + bitangent * (radius * phi.sin()) + axis * z
+}
+
+/// Returns the weight for a sample drawn with probability density `pdf` when it could also have
+/// been drawn by another strategy with density `other_pdf`, according to Veach's power heuristic
+/// for multiple importance sampling. The weights of the two strategies add up to 1.
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// Maps the uniformly distributed sample `(u1, u2)` to a point uniformly distributed on the unit
+/// disk, using Shirley and Chiu's concentric mapping. `(0.5, 0.5)` maps to the center.
+pub fn concentric_sample_disk(u1: f32, u2: f32) -> (f32, f32) {
+    let (x, y) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, theta) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+    (radius * theta.cos(), radius * theta.sin())
+}
+
+/// Maps the uniformly distributed sample `(u1, u2)` to a point uniformly distributed on a
+/// regular polygon with `sides` sides inscribed in the unit circle, with one vertex at angle
+/// `rotation` (in radians) from the x axis.
+pub fn sample_regular_polygon(sides: usize, rotation: f32, u1: f32, u2: f32) -> (f32, f32) {
+    // Pick one of the triangles between the center and each side, then a point within it.
+    let sector_angle = 2.0 * PI / sides as f32;
+    let scaled = u1 * sides as f32;
+    let sector = (scaled as usize).min(sides - 1);
+    let across = scaled - sector as f32;
+    let start_angle = rotation + sector as f32 * sector_angle;
+    let end_angle = start_angle + sector_angle;
+    let (edge_x, edge_y) = (
+        start_angle.cos() + across * (end_angle.cos() - start_angle.cos()),
+        start_angle.sin() + across * (end_angle.sin() - start_angle.sin()),
+    );
+    // Area grows with the square of the distance from the center.
+    let along = u2.sqrt();
+    (along * edge_x, along * edge_y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_reproducible() {
+        let mut a = Rng::for_pixel(7, 3, 4);
+        let mut b = Rng::for_pixel(7, 3, 4);
+        let mut c = Rng::for_pixel(7, 4, 3);
+        for _ in 0..10 {
+            let x = a.next_f32();
+            assert!(x >= 0.0 && x < 1.0);
+            assert_eq!(x, b.next_f32());
+            assert!(x != c.next_f32());
+        }
+    }
+
+    #[test]
+    fn test_regular_samples() {
+        let mut rng = Rng::new(0);
+        assert_eq!(PixelSampler::Regular.samples(1, &mut rng), vec![(0.5, 0.5)]);
+        assert_eq!(
+            PixelSampler::Regular.samples(4, &mut rng),
+            vec![(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)],
+        );
+        assert_eq!(
+            PixelSampler::Regular.samples(3, &mut rng),
+            vec![(0.25, 0.25), (0.75, 0.25), (0.5, 0.75)],
+        );
+    }
+
+    #[test]
+    fn test_cosine_sample_hemisphere() {
+        let mut rng = Rng::new(1);
+        let normal = Vector3 { x: 0.0, y: 0.6, z: 0.8 };
+        let mut mean_cos = 0.0;
+        for _ in 0..10000 {
+            let direction = cosine_sample_hemisphere(normal, rng.next_f32(), rng.next_f32());
+            assert!((direction.magnitude() - 1.0).abs() < 1e-4);
+            assert!(direction.dot(normal) >= 0.0);
+            mean_cos += direction.dot(normal) / 10000.0;
+        }
+        // The expected value of cos(theta) under a cosine-weighted distribution is 2/3.
+        assert!((mean_cos - 2.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_concentric_sample_disk() {
+        assert_eq!(concentric_sample_disk(0.5, 0.5), (0.0, 0.0));
+        let (x, y) = concentric_sample_disk(1.0, 0.5);
+        assert!((x - 1.0).abs() < 1e-6 && y.abs() < 1e-6);
+        let mut rng = Rng::new(2);
+        let inside_half_radius = (0..10000)
+            .map(|_| concentric_sample_disk(rng.next_f32(), rng.next_f32()))
+            .inspect(|&(x, y)| assert!(x * x + y * y <= 1.0 + 1e-5))
+            .filter(|&(x, y)| x * x + y * y < 0.25)
+            .count();
+        // A quarter of the disk's area is within half of its radius.
+        assert!((inside_half_radius as f32 / 10000.0 - 0.25).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_sample_regular_polygon() {
+        let mut rng = Rng::new(3);
+        for _ in 0..1000 {
+            // A square with vertices on the axes is the set of points with |x| + |y| <= 1.
+            let (x, y) = sample_regular_polygon(4, 0.0, rng.next_f32(), rng.next_f32());
+            assert!(x.abs() + y.abs() <= 1.0 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_stratified_samples() {
+        let mut rng = Rng::new(0);
+        let samples = PixelSampler::Stratified.samples(9, &mut rng);
+        assert_eq!(samples.len(), 9);
+        for (i, &(x, y)) in samples.iter().enumerate() {
+            let (row, column) = ((i / 3) as f32, (i % 3) as f32);
+            assert!(x >= column / 3.0 && x < (column + 1.0) / 3.0);
+            assert!(y >= row / 3.0 && y < (row + 1.0) / 3.0);
+        }
+    }
+}
@@ -0,0 +1,456 @@
+use std::f32;
+
+use cgmath::{
+    EuclideanSpace,
+    InnerSpace,
+    Point3,
+    Vector3,
+};
+use collision::{
+    Aabb3,
+    Ray3,
+};
+use bvh::*;
+use trace::*;
+
+/// A signed distance function, which describes a surface implicitly.
+pub trait DistanceField: Send + Sync {
+    /// Returns the distance from `point` to the surface, which is negative inside it. The
+    /// distance may be underestimated, which makes sphere tracing slower, but mustn't be
+    /// overestimated, or it'll step through the surface.
+    fn distance(&self, point: Point3<f32>) -> f32;
+
+    /// Returns a box containing the whole surface, or `None` if it's unbounded. The default
+    /// implementation returns `None`.
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        None
+    }
+}
+
+/// Any function from a point to a distance is a distance field. This is handy for one-off
+/// surfaces such as fractals.
+impl<F> DistanceField for F where F: Fn(Point3<f32>) -> f32 + Send + Sync {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        self(point)
+    }
+}
+
+/// A `Solid` whose surface is where a distance field is zero, found by sphere tracing: stepping
+/// along the ray by the distance to the surface until it's within `epsilon`. Rays must have
+/// normalized directions.
+pub struct SdfSolid {
+    pub field: Box<DistanceField>,
+
+    /// The maximum number of steps taken along a ray before it's considered to have missed.
+    pub max_steps: usize,
+
+    /// How close to the surface a ray must get to hit it. This is also the step used to estimate
+    /// normals.
+    pub epsilon: f32,
+
+    /// How far along a ray to look for the surface, if the field is unbounded.
+    pub max_distance: f32,
+
+    /// Each step is the distance to the surface times `step_scale`. Fields that overestimate
+    /// distances, such as `Twist`, need a value below 1.
+    pub step_scale: f32,
+}
+
+impl SdfSolid {
+    pub fn new(field: Box<DistanceField>) -> Self {
+        SdfSolid {
+            field,
+            max_steps: 256,
+            epsilon: 1e-4,
+            max_distance: 1000.0,
+            step_scale: 1.0,
+        }
+    }
+
+    /// Returns the section of the line through `ray` in which to look for the surface.
+    fn search_span(&self, ray: &Ray3<f32>) -> Option<(f32, f32)> {
+        match self.field.bounds() {
+            Some(bounds) => bounds.intervals(ray).first().map(|interval| {
+                (interval.enter.t - self.epsilon, interval.exit.t + self.epsilon)
+            }),
+            None => Some((-self.max_distance, self.max_distance)),
+        }
+    }
+
+    /// Returns the distance along `ray` to the next point after `start`, and before `end`, at
+    /// which it's within `epsilon` of the surface. If `leave_surface` is set and `start` is
+    /// already that close, the ray must first move away from the surface, so that a ray leaving
+    /// the surface doesn't hit it again immediately.
+    fn march(&self, ray: &Ray3<f32>, start: f32, end: f32, leave_surface: bool) -> Option<f32> {
+        let mut t = start;
+        let mut left_surface = !leave_surface;
+        for _ in 0..self.max_steps {
+            if t > end {
+                return None;
+            }
+            let distance = self.field.distance(ray.origin + ray.direction * t).abs();
+            if distance < self.epsilon {
+                if left_surface {
+                    return Some(t);
+                }
+                t += self.epsilon;
+            } else {
+                left_surface = true;
+                t += distance * self.step_scale;
+            }
+        }
+        None
+    }
+
+    /// Returns the normal of the surface at `point`, estimated from the field's gradient.
+    fn normal(&self, point: Point3<f32>) -> Vector3<f32> {
+        let h = self.epsilon;
+        let difference = |offset: Vector3<f32>| {
+            self.field.distance(point + offset) - self.field.distance(point - offset)
+        };
+        let gradient = Vector3 {
+            x: difference(Vector3 { x: h, y: 0.0, z: 0.0 }),
+            y: difference(Vector3 { x: 0.0, y: h, z: 0.0 }),
+            z: difference(Vector3 { x: 0.0, y: 0.0, z: h }),
+        };
+        if gradient.magnitude2() > 0.0 {
+            gradient.normalize()
+        } else {
+            // The gradient vanishes at isolated points, e.g. the center of a sphere; any
+            // direction will do there.
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 }
+        }
+    }
+
+    fn crossing(&self, ray: &Ray3<f32>, t: f32) -> SolidCrossing {
+        let point = ray.origin + ray.direction * t;
+        let normal = self.normal(point);
+        let (uv, dpdu, dpdv) = planar_mapping(point, normal);
+        SolidCrossing {
+            t,
+            hit: Some(SolidHit {
+                t,
+                point,
+                normal,
+                uv,
+                dpdu,
+                dpdv,
+                front_face: ray.direction.dot(normal) < 0.0,
+            }),
+        }
+    }
+}
+
+impl Solid for SdfSolid {
+    fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
+        let (start, end) = self.search_span(ray)?;
+        // Only a ray that starts within the search span can be starting on the surface.
+        let leave_surface = start <= 0.0;
+        self.march(ray, start.max(0.0), end, leave_surface).and_then(|t| self.crossing(ray, t).hit)
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        self.field.bounds()
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let (start, end) = match self.search_span(ray) {
+            Some(span) => span,
+            None => return vec![],
+        };
+        let mut intervals = vec![];
+        let mut enter = if self.field.distance(ray.origin + ray.direction * start) < 0.0 {
+            Some(SolidCrossing { t: -f32::INFINITY, hit: None })
+        } else {
+            None
+        };
+        let (mut t, mut leave_surface) = (start, false);
+        while let Some(hit_t) = self.march(ray, t, end, leave_surface) {
+            let crossing = self.crossing(ray, hit_t);
+            match enter.take() {
+                Some(enter) => intervals.push(SolidInterval { enter, exit: crossing }),
+                None => enter = Some(crossing),
+            }
+            t = hit_t;
+            leave_surface = true;
+        }
+        if let Some(enter) = enter {
+            intervals.push(SolidInterval {
+                enter,
+                exit: SolidCrossing { t: f32::INFINITY, hit: None },
+            });
+        }
+        intervals
+    }
+}
+
+/// A sphere, as a distance field.
+#[derive(Copy, Clone, Debug)]
+pub struct SdfSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl DistanceField for SdfSphere {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        (point - self.center).magnitude() - self.radius
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let extent = Vector3 { x: self.radius, y: self.radius, z: self.radius };
+        Some(Aabb3::new(self.center - extent, self.center + extent))
+    }
+}
+
+/// An axis-aligned box with its edges and corners rounded off by `rounding`, which is included
+/// in `half_extents`.
+#[derive(Copy, Clone, Debug)]
+pub struct SdfRoundedBox {
+    pub center: Point3<f32>,
+    pub half_extents: Vector3<f32>,
+    pub rounding: f32,
+}
+
+impl DistanceField for SdfRoundedBox {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        let offset = point - self.center;
+        let rounding = Vector3 { x: self.rounding, y: self.rounding, z: self.rounding };
+        let q = Vector3 { x: offset.x.abs(), y: offset.y.abs(), z: offset.z.abs() }
+            - (self.half_extents - rounding);
+        let outside = Vector3 { x: q.x.max(0.0), y: q.y.max(0.0), z: q.z.max(0.0) };
+        outside.magnitude() + q.x.max(q.y).max(q.z).min(0.0) - self.rounding
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        Some(Aabb3::new(self.center - self.half_extents, self.center + self.half_extents))
+    }
+}
+
+/// The union of two fields, with the seam between them blended over a distance of about
+/// `smoothness`.
+pub struct SmoothUnion {
+    pub a: Box<DistanceField>,
+    pub b: Box<DistanceField>,
+    pub smoothness: f32,
+}
+
+impl DistanceField for SmoothUnion {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        let (a, b) = (self.a.distance(point), self.b.distance(point));
+        if self.smoothness <= 0.0 {
+            return a.min(b);
+        }
+        // The polynomial smooth minimum.
+        let h = (self.smoothness - (a - b).abs()).max(0.0) / self.smoothness;
+        a.min(b) - h * h * self.smoothness / 4.0
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        // The blend can bulge out by up to a quarter of `smoothness`.
+        let (a, b) = (self.a.bounds()?, self.b.bounds()?);
+        let bulge = self.smoothness.max(0.0) / 4.0;
+        let bulge = Vector3 { x: bulge, y: bulge, z: bulge };
+        let bounds = union(&a, &b);
+        Some(Aabb3::new(bounds.min - bulge, bounds.max + bulge))
+    }
+}
+
+/// A field twisted about the y axis by `rate` radians per unit of height. Twisting stretches
+/// distances, so the `SdfSolid` should use a `step_scale` below 1.
+pub struct Twist {
+    pub field: Box<DistanceField>,
+    pub rate: f32,
+}
+
+impl DistanceField for Twist {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        let (sin, cos) = (-self.rate * point.y).sin_cos();
+        self.field.distance(Point3 {
+            x: cos * point.x - sin * point.z,
+            y: point.y,
+            z: sin * point.x + cos * point.z,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        // Any rotation about the y axis stays within the cylinder around the field's bounds.
+        let bounds = self.field.bounds()?;
+        let x = bounds.min.x.abs().max(bounds.max.x.abs());
+        let z = bounds.min.z.abs().max(bounds.max.z.abs());
+        let radius = (x * x + z * z).sqrt();
+        Some(Aabb3::new(
+            Point3 { x: -radius, y: bounds.min.y, z: -radius },
+            Point3 { x: radius, y: bounds.max.y, z: radius },
+        ))
+    }
+}
+
+/// A field repeated infinitely, with copies `period` apart along each axis. A period of 0 along
+/// an axis means the field isn't repeated along it. The field should fit within the cell
+/// centered on the origin, or the copies will be cut off.
+pub struct Repeat {
+    pub field: Box<DistanceField>,
+    pub period: Vector3<f32>,
+}
+
+impl DistanceField for Repeat {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        let wrap = |x: f32, period: f32| {
+            if period > 0.0 {
+                x - period * (x / period).round()
+            } else {
+                x
+            }
+        };
+        self.field.distance(Point3 {
+            x: wrap(point.x, self.period.x),
+            y: wrap(point.y, self.period.y),
+            z: wrap(point.z, self.period.z),
+        })
+    }
+}
+
+/// The Mandelbulb fractal, centered on the origin, with a radius of a little over 1.
+#[derive(Copy, Clone, Debug)]
+pub struct Mandelbulb {
+    /// The power the point is raised to at each iteration. 8 gives the classic shape.
+    pub power: f32,
+
+    /// More iterations give more detail, at the cost of speed.
+    pub iterations: usize,
+}
+
+impl DistanceField for Mandelbulb {
+    fn distance(&self, point: Point3<f32>) -> f32 {
+        let c = point.to_vec();
+        let mut z = c;
+        let mut derivative = 1.0;
+        let mut radius = z.magnitude();
+        for _ in 0..self.iterations {
+            if radius > 2.0 {
+                break;
+            }
+            // Raise z to `power` in spherical coordinates.
+            let theta = (z.z / radius).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            derivative = radius.powf(self.power - 1.0) * self.power * derivative + 1.0;
+            let scale = radius.powf(self.power);
+            z = Vector3 {
+                x: theta.sin() * phi.cos(),
+                y: theta.sin() * phi.sin(),
+                z: theta.cos(),
+            } * scale + c;
+            radius = z.magnitude();
+        }
+        if radius == 0.0 {
+            return 0.0;
+        }
+        0.5 * radius.ln() * radius / derivative
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let extent = Vector3 { x: 1.5, y: 1.5, z: 1.5 };
+        Some(Aabb3::new(Point3::from_vec(-extent), Point3::from_vec(extent)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sphere(x: f32) -> Box<DistanceField> {
+        Box::new(SdfSphere { center: Point3 { x, y: 0.0, z: 0.0 }, radius: 1.0 })
+    }
+
+    fn x_ray(x: f32, y: f32) -> Ray3<f32> {
+        Ray3::new(Point3 { x, y, z: 0.0 }, Vector3 { x: 1.0, y: 0.0, z: 0.0 })
+    }
+
+    #[test]
+    fn test_sphere() {
+        let solid = SdfSolid::new(sphere(0.0));
+        let hit = solid.trace(&x_ray(-5.0, 0.0)).unwrap();
+        assert!((hit.point - Point3 { x: -1.0, y: 0.0, z: 0.0 }).magnitude() < 1e-3);
+        assert!((hit.normal - Vector3 { x: -1.0, y: 0.0, z: 0.0 }).magnitude() < 1e-3);
+        assert!(hit.front_face);
+        assert!(solid.trace(&x_ray(-5.0, 1.5)).is_none());
+
+        // A ray starting on the surface, as reflected and refracted rays do, doesn't hit it
+        // again there.
+        let hit = solid.trace(&x_ray(-1.0, 0.0)).unwrap();
+        assert!((hit.point.x - 1.0).abs() < 1e-3 && !hit.front_face);
+        let intervals = solid.intervals(&x_ray(-5.0, 0.0));
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].enter.t - 4.0).abs() < 1e-3);
+        assert!((intervals[0].exit.t - 6.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut solid = SdfSolid::new(sphere(0.0));
+        solid.max_steps = 1;
+        assert!(solid.trace(&Ray3::new(
+            Point3 { x: -5.0, y: 0.0, z: 0.0 },
+            Vector3 { x: 1.0, y: 0.9, z: 0.0 }.normalize(),
+        )).is_none());
+    }
+
+    #[test]
+    fn test_smooth_union() {
+        // Two spheres whose surfaces touch at the origin.
+        let union = |smoothness| SdfSolid::new(Box::new(SmoothUnion {
+            a: sphere(-1.0),
+            b: sphere(1.0),
+            smoothness,
+        }));
+        let (hard, smooth) = (union(0.0), union(1.0));
+        let down = |x: f32| Ray3::new(
+            Point3 { x, y: 5.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+        );
+        let hard_hit = hard.trace(&down(-0.5)).unwrap();
+        assert!((hard_hit.point.y - 0.75f32.sqrt()).abs() < 1e-3);
+        // The blend fills in the crease between them, up to where sqrt(1 + y^2) - 1 = 1 / 4.
+        let smooth_hit = smooth.trace(&down(0.0)).unwrap();
+        assert!((smooth_hit.point.y - 0.75).abs() < 1e-3);
+        assert!((smooth_hit.normal - Vector3 { x: 0.0, y: 1.0, z: 0.0 }).magnitude() < 1e-3);
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut solid = SdfSolid::new(Box::new(Repeat {
+            field: Box::new(SdfSphere { center: Point3 { x: 0.0, y: 0.0, z: 0.0 }, radius: 0.5 }),
+            period: Vector3 { x: 4.0, y: 0.0, z: 0.0 },
+        }));
+        solid.max_distance = 100.0;
+        let hit = solid.trace(&x_ray(1.0, 0.0)).unwrap();
+        assert!((hit.point.x - 3.5).abs() < 1e-3);
+        // The copies don't extend along y.
+        assert!(solid.trace(&x_ray(1.0, 4.0)).is_none());
+    }
+
+    #[test]
+    fn test_twist() {
+        // A box twisted by a quarter turn at y = 1.
+        let mut solid = SdfSolid::new(Box::new(Twist {
+            field: Box::new(SdfRoundedBox {
+                center: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                half_extents: Vector3 { x: 2.0, y: 2.0, z: 0.5 },
+                rounding: 0.0,
+            }),
+            rate: f32::consts::PI / 2.0,
+        }));
+        solid.step_scale = 0.5;
+        let hit = solid.trace(&x_ray(-5.0, 0.0)).unwrap();
+        assert!((hit.point.x + 2.0).abs() < 1e-3);
+        let hit = solid.trace(&x_ray(-5.0, 1.0)).unwrap();
+        assert!((hit.point.x + 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_mandelbulb() {
+        let solid = SdfSolid::new(Box::new(Mandelbulb { power: 8.0, iterations: 8 }));
+        let hit = solid.trace(&x_ray(-5.0, 0.0)).unwrap();
+        assert!(hit.point.x > -1.5 && hit.point.x < 0.0);
+    }
+}
@@ -0,0 +1,514 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+};
+use std::path::Path;
+use std::sync::Arc;
+
+use cgmath::InnerSpace;
+use png;
+use color::*;
+use trace::*;
+
+/// The point on a surface at which a `Shading`, and the textures bound to it, are evaluated.
+#[derive(Copy, Clone, Debug)]
+pub struct ShadingContext {
+    pub hit: SolidHit,
+
+    /// The approximate width, in world units, of the part of the surface covered by the pixel
+    /// being shaded. Filtered textures average over this area; 0 means no filtering.
+    pub footprint: f32,
+}
+
+impl ShadingContext {
+    /// Returns the context for evaluating a surface at `hit` without filtering.
+    pub fn new(hit: SolidHit) -> Self {
+        ShadingContext { hit, footprint: 0.0 }
+    }
+}
+
+/// A color that varies over a surface.
+pub trait Texture: Send + Sync {
+    fn color(&self, context: &ShadingContext) -> Color;
+}
+
+/// A constant color is a texture that's the same everywhere.
+impl Texture for Color {
+    fn color(&self, _context: &ShadingContext) -> Color {
+        *self
+    }
+}
+
+/// An error encountered while loading an image.
+#[derive(Debug)]
+pub enum ImageError {
+    /// The file couldn't be read.
+    Io(io::Error),
+
+    /// The file is a PNG file that couldn't be decoded.
+    Png(png::DecodingError),
+
+    /// The file is malformed, or isn't in a supported format.
+    Format(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Io(ref error) => write!(f, "{}", error),
+            ImageError::Png(ref error) => write!(f, "{}", error),
+            ImageError::Format(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ImageError {
+    fn description(&self) -> &str {
+        match *self {
+            ImageError::Io(_) => "I/O error",
+            ImageError::Png(_) => "PNG decoding error",
+            ImageError::Format(_) => "malformed image",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ImageError::Io(ref error) => Some(error),
+            ImageError::Png(ref error) => Some(error),
+            ImageError::Format(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(error: io::Error) -> Self {
+        ImageError::Io(error)
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(error: png::DecodingError) -> Self {
+        ImageError::Png(error)
+    }
+}
+
+/// A grid of colors, stored row by row from the top of the image.
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+impl Image {
+    /// # Panics
+    ///
+    /// Panics if the image is empty, or if `pixels` doesn't have `width * height` elements.
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        assert!(width > 0 && height > 0, "image must not be empty");
+        assert_eq!(pixels.len(), width * height);
+        Image { width, height, pixels }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Returns the image at half the size, rounded down, with each pixel the average of the
+    /// pixels it covers.
+    fn downsample(&self) -> Image {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                // Odd rows and columns at the edge are dropped.
+                let (x0, y0) = (2 * x, 2 * y);
+                let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+                let sum = self.pixel(x0, y0) + self.pixel(x1, y0)
+                    + self.pixel(x0, y1) + self.pixel(x1, y1);
+                pixels.push(sum / 4.0);
+            }
+        }
+        Image::new(width, height, pixels)
+    }
+}
+
+/// Loads a PNG or PPM image, telling them apart by their contents. Colors are used as they're
+/// stored, without gamma correction, like the rest of the renderer.
+pub fn load_image<P: AsRef<Path>>(path: P) -> Result<Image, ImageError> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.starts_with(b"\x89PNG") {
+        decode_png(&bytes[..])
+    } else if bytes.starts_with(b"P3") || bytes.starts_with(b"P6") {
+        parse_ppm(&bytes)
+    } else {
+        Err(ImageError::Format("unrecognized image format".to_string()))
+    }
+}
+
+/// Decodes a PNG image. The alpha channel, if any, is kept in the colors' `a` components.
+pub fn decode_png<R: Read>(reader: R) -> Result<Image, ImageError> {
+    let (info, mut reader) = png::Decoder::new(reader).read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buffer)?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width == 0 || height == 0 {
+        return Err(ImageError::Format("empty image".to_string()));
+    }
+    let channels = info.color_type.samples();
+    // Lines may be padded, so index them by the decoder's line size.
+    let line_size = buffer.len() / height;
+    let mut pixels = Vec::with_capacity(width * height);
+    for line in buffer.chunks(line_size) {
+        for sample in line[..width * channels].chunks(channels) {
+            pixels.push(match info.color_type {
+                png::ColorType::Grayscale => Color::from_rgb_u8s(sample[0], sample[0], sample[0]),
+                png::ColorType::GrayscaleAlpha => {
+                    Color::from_argb_u8s(sample[1], sample[0], sample[0], sample[0])
+                },
+                png::ColorType::RGB => Color::from_rgb_u8s(sample[0], sample[1], sample[2]),
+                png::ColorType::RGBA => {
+                    Color::from_argb_u8s(sample[3], sample[0], sample[1], sample[2])
+                },
+                png::ColorType::Indexed => {
+                    return Err(ImageError::Format("unexpanded indexed PNG".to_string()));
+                },
+            });
+        }
+    }
+    Ok(Image::new(width, height, pixels))
+}
+
+/// Parses a PPM image, in either the plain (`P3`) or the raw (`P6`) format.
+pub fn parse_ppm(bytes: &[u8]) -> Result<Image, ImageError> {
+    let format_error = |message: &str| ImageError::Format(format!("PPM: {}", message));
+
+    // Reads the next whitespace separated token of the header, skipping comments.
+    let mut position = 0;
+    let mut token = || -> Option<&[u8]> {
+        loop {
+            while position < bytes.len() && bytes[position].is_ascii_whitespace() {
+                position += 1;
+            }
+            if position < bytes.len() && bytes[position] == b'#' {
+                while position < bytes.len() && bytes[position] != b'\n' {
+                    position += 1;
+                }
+            } else {
+                break;
+            }
+        }
+        let start = position;
+        while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            None
+        } else {
+            Some(&bytes[start..position])
+        }
+    };
+    let magic = token().ok_or_else(|| format_error("missing magic number"))?;
+    let raw = match magic {
+        b"P3" => false,
+        b"P6" => true,
+        _ => return Err(format_error("unsupported magic number")),
+    };
+    let mut number = |name: &str| -> Result<usize, ImageError> {
+        token()
+            .and_then(|token| String::from_utf8_lossy(token).parse().ok())
+            .ok_or_else(|| format_error(&format!("invalid {}", name)))
+    };
+    let width = number("width")?;
+    let height = number("height")?;
+    let max_value = number("maximum value")?;
+    if width == 0 || height == 0 {
+        return Err(format_error("empty image"));
+    }
+    if max_value == 0 || max_value > 65535 {
+        return Err(format_error("invalid maximum value"));
+    }
+
+    let sample_count = width * height * 3;
+    let samples: Vec<usize> = if raw {
+        // A single whitespace character separates the header from the samples.
+        let data = &bytes[(position + 1).min(bytes.len())..];
+        let sample_size = if max_value < 256 { 1 } else { 2 };
+        if data.len() < sample_count * sample_size {
+            return Err(format_error("truncated pixel data"));
+        }
+        data.chunks(sample_size)
+            .take(sample_count)
+            .map(|sample| sample.iter().fold(0, |value, &byte| value * 256 + byte as usize))
+            .collect()
+    } else {
+        let mut samples = Vec::with_capacity(sample_count);
+        for _ in 0..sample_count {
+            samples.push(number("sample")?);
+        }
+        samples
+    };
+    let scale = 1.0 / max_value as f32;
+    let pixels = samples.chunks(3)
+        .map(|rgb| {
+            Color::from_rgb(rgb[0] as f32 * scale, rgb[1] as f32 * scale, rgb[2] as f32 * scale)
+        })
+        .collect();
+    Ok(Image::new(width, height, pixels))
+}
+
+/// How an `ImageTexture` combines the pixels near a lookup.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureFilter {
+    /// Uses the pixel that the lookup falls in.
+    Nearest,
+
+    /// Interpolates between the four pixels nearest the lookup.
+    Bilinear,
+
+    /// Interpolates bilinearly in the two mip-map levels whose pixels are closest in size to the
+    /// lookup's footprint, and between those levels. This avoids aliasing when the texture is
+    /// minified.
+    Trilinear,
+}
+
+/// How an `ImageTexture` treats texture coordinates outside of [0, 1].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    /// The image is tiled.
+    Repeat,
+
+    /// The pixels at the edges of the image are extended.
+    Clamp,
+
+    /// The image is tiled, with every other tile mirrored, so that there are no seams.
+    Mirror,
+}
+
+impl WrapMode {
+    /// Maps the (possibly out of range) pixel coordinate `i` into an image `size` pixels wide.
+    fn wrap(&self, i: isize, size: usize) -> usize {
+        let size = size as isize;
+        let wrapped = match *self {
+            WrapMode::Repeat => ((i % size) + size) % size,
+            WrapMode::Clamp => i.max(0).min(size - 1),
+            WrapMode::Mirror => {
+                let period = 2 * size;
+                let i = ((i % period) + period) % period;
+                if i < size { i } else { period - 1 - i }
+            },
+        };
+        wrapped as usize
+    }
+}
+
+/// A texture that maps an image over the unit square of texture coordinates, with (0, 0) at the
+/// bottom left of the image.
+#[derive(Clone, Debug)]
+pub struct ImageTexture {
+    /// The image followed by its successively halved mip-map levels, down to a single pixel. They
+    /// are shared between clones of the texture.
+    pub levels: Arc<Vec<Image>>,
+
+    pub filter: TextureFilter,
+    pub wrap: WrapMode,
+}
+
+impl ImageTexture {
+    pub fn new(image: Image, filter: TextureFilter, wrap: WrapMode) -> Self {
+        let mut levels = vec![image];
+        while {
+            let last = levels.last().unwrap();
+            last.width > 1 || last.height > 1
+        } {
+            let next = levels.last().unwrap().downsample();
+            levels.push(next);
+        }
+        ImageTexture { levels: Arc::new(levels), filter, wrap }
+    }
+
+    /// Loads the image at `path` with `load_image`.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        filter: TextureFilter,
+        wrap: WrapMode,
+    ) -> Result<Self, ImageError> {
+        Ok(ImageTexture::new(load_image(path)?, filter, wrap))
+    }
+
+    fn nearest(&self, level: &Image, u: f32, v: f32) -> Color {
+        let x = (u * level.width as f32).floor() as isize;
+        let y = ((1.0 - v) * level.height as f32).floor() as isize;
+        level.pixel(self.wrap.wrap(x, level.width), self.wrap.wrap(y, level.height))
+    }
+
+    fn bilinear(&self, level: &Image, u: f32, v: f32) -> Color {
+        // Pixel centers are at half-integer coordinates.
+        let x = u * level.width as f32 - 0.5;
+        let y = (1.0 - v) * level.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |dx: isize, dy: isize| level.pixel(
+            self.wrap.wrap(x0 as isize + dx, level.width),
+            self.wrap.wrap(y0 as isize + dy, level.height),
+        );
+        (pixel(0, 0) * (1.0 - fx) + pixel(1, 0) * fx) * (1.0 - fy)
+            + (pixel(0, 1) * (1.0 - fx) + pixel(1, 1) * fx) * fy
+    }
+
+    /// Returns the mip-map level, possibly fractional, whose pixels are about the size of the
+    /// footprint of `context`.
+    fn level_of_detail(&self, context: &ShadingContext) -> f32 {
+        let image = &self.levels[0];
+        // Convert the footprint to pixels along each texture axis.
+        let pixels = |tangent_length: f32, size: usize| if tangent_length > 0.0 {
+            context.footprint / tangent_length * size as f32
+        } else {
+            0.0
+        };
+        let width = pixels(context.hit.dpdu.magnitude(), image.width)
+            .max(pixels(context.hit.dpdv.magnitude(), image.height));
+        if width <= 1.0 {
+            0.0
+        } else {
+            width.log2().min((self.levels.len() - 1) as f32)
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn color(&self, context: &ShadingContext) -> Color {
+        let (u, v) = (context.hit.uv.x, context.hit.uv.y);
+        match self.filter {
+            TextureFilter::Nearest => self.nearest(&self.levels[0], u, v),
+            TextureFilter::Bilinear => self.bilinear(&self.levels[0], u, v),
+            TextureFilter::Trilinear => {
+                let level = self.level_of_detail(context);
+                let lower = level.floor() as usize;
+                let fraction = level - lower as f32;
+                let color = self.bilinear(&self.levels[lower], u, v);
+                if fraction == 0.0 {
+                    color
+                } else {
+                    let upper = self.bilinear(&self.levels[lower + 1], u, v);
+                    color * (1.0 - fraction) + upper * fraction
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::{
+        Point3,
+        Vector2,
+        Vector3,
+    };
+
+    /// A 2 x 2 image: black and red on top, green and blue below.
+    fn image() -> Image {
+        Image::new(2, 2, vec![
+            Color::from_rgb(0.0, 0.0, 0.0),
+            Color::from_rgb(1.0, 0.0, 0.0),
+            Color::from_rgb(0.0, 1.0, 0.0),
+            Color::from_rgb(0.0, 0.0, 1.0),
+        ])
+    }
+
+    fn context(u: f32, v: f32, footprint: f32) -> ShadingContext {
+        ShadingContext {
+            hit: SolidHit {
+                t: 1.0,
+                point: Point3 { x: 0.0, y: 0.0, z: 0.0 },
+                normal: Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+                uv: Vector2 { x: u, y: v },
+                dpdu: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+                dpdv: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+                front_face: true,
+            },
+            footprint,
+        }
+    }
+
+    fn assert_color(color: Color, r: f32, g: f32, b: f32) {
+        assert!(
+            (color.r - r).abs() < 1e-5 && (color.g - g).abs() < 1e-5 && (color.b - b).abs() < 1e-5,
+            "{:?} != ({}, {}, {})", color, r, g, b,
+        );
+    }
+
+    #[test]
+    fn test_nearest_and_wrap_modes() {
+        let texture = |wrap| ImageTexture::new(image(), TextureFilter::Nearest, wrap);
+        let repeat = texture(WrapMode::Repeat);
+        // (0, 0) is the bottom left of the image.
+        assert_color(repeat.color(&context(0.25, 0.25, 0.0)), 0.0, 1.0, 0.0);
+        assert_color(repeat.color(&context(0.75, 0.75, 0.0)), 1.0, 0.0, 0.0);
+        assert_color(repeat.color(&context(1.25, 0.25, 0.0)), 0.0, 1.0, 0.0);
+        assert_color(texture(WrapMode::Clamp).color(&context(1.25, 0.25, 0.0)), 0.0, 0.0, 1.0);
+        assert_color(texture(WrapMode::Mirror).color(&context(1.25, 0.25, 0.0)), 0.0, 0.0, 1.0);
+        assert_color(texture(WrapMode::Mirror).color(&context(-0.25, 0.25, 0.0)), 0.0, 1.0, 0.0);
+    }
+
+    #[test]
+    fn test_bilinear() {
+        let texture = ImageTexture::new(image(), TextureFilter::Bilinear, WrapMode::Clamp);
+        assert_color(texture.color(&context(0.5, 0.5, 0.0)), 0.25, 0.25, 0.25);
+        // Halfway between the centers of the bottom pixels.
+        assert_color(texture.color(&context(0.5, 0.25, 0.0)), 0.0, 0.5, 0.5);
+        assert_color(texture.color(&context(0.25, 0.25, 0.0)), 0.0, 1.0, 0.0);
+    }
+
+    #[test]
+    fn test_trilinear() {
+        let texture = ImageTexture::new(image(), TextureFilter::Trilinear, WrapMode::Repeat);
+        assert_eq!(texture.levels.len(), 2);
+        // A footprint smaller than a pixel uses the full image.
+        assert_color(texture.color(&context(0.25, 0.25, 0.1)), 0.0, 1.0, 0.0);
+        // A footprint covering the whole texture uses the average color.
+        assert_color(texture.color(&context(0.25, 0.25, 1.0)), 0.25, 0.25, 0.25);
+        // In between, the levels are blended.
+        let footprint = 2.0f32.sqrt() / 2.0;
+        assert_color(texture.color(&context(0.25, 0.25, footprint)), 0.125, 0.625, 0.125);
+    }
+
+    #[test]
+    fn test_parse_ppm() {
+        let plain = parse_ppm(b"P3\n# A comment\n2 1\n255\n255 0 0  0 0 255\n").unwrap();
+        assert_eq!((plain.width, plain.height), (2, 1));
+        assert_color(plain.pixel(0, 0), 1.0, 0.0, 0.0);
+        assert_color(plain.pixel(1, 0), 0.0, 0.0, 1.0);
+
+        let raw = parse_ppm(b"P6 1 2 255\n\xff\x00\x00\x00\xff\x00").unwrap();
+        assert_color(raw.pixel(0, 1), 0.0, 1.0, 0.0);
+
+        assert!(parse_ppm(b"P6 2 2 255\n\x00").is_err());
+        assert!(parse_ppm(b"P5 1 1 255\n\x00").is_err());
+    }
+
+    #[test]
+    fn test_decode_png() {
+        use png::HasParameters;
+
+        let mut bytes = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+            encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[255, 0, 0, 255, 0, 0, 255, 0]).unwrap();
+        }
+        let image = decode_png(&bytes[..]).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_color(image.pixel(0, 0), 1.0, 0.0, 0.0);
+        assert_color(image.pixel(1, 0), 0.0, 0.0, 1.0);
+        assert_eq!(image.pixel(1, 0).a, 0.0);
+        assert!(decode_png(&b"\x89PNG garbage"[..]).is_err());
+    }
+}
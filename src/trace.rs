@@ -1,12 +1,17 @@
 use std::clone::Clone;
+use std::f32;
+use std::f32::consts::PI;
 
 use cgmath::{
     BaseFloat,
+    EuclideanSpace,
     InnerSpace,
     Point3,
+    Vector2,
     Vector3,
 };
 use collision::{
+    Aabb3,
     Continuous,
     Ray3,
     Sphere,
@@ -14,6 +19,8 @@ use collision::{
 };
 use color::*;
 use material::*;
+use sampling::*;
+use texture::*;
 use collision;
 
 /// A `SolidHit` object describes the nature of the intersection between a `Ray` and
@@ -23,58 +30,304 @@ use collision;
 /// All points and vectors are in world space.
 #[derive(Copy, Debug, Clone)]
 pub struct SolidHit {
+    /// The distance along the ray to the point of intersection, in units of the ray direction's
+    /// length.
+    pub t: f32,
+
     /// The point of intersection.
     pub point: Point3<f32>,
 
-    /// The normal of the object's surface at the point of intersection.
+    /// The normal of the object's surface at the point of intersection. For closed solids this
+    /// always points outwards, regardless of which side the ray hit the surface from.
     pub normal: Vector3<f32>,
+
+    /// The surface's texture coordinates at the point of intersection.
+    pub uv: Vector2<f32>,
+
+    /// The rate of change of the point with respect to `uv.x`, which is tangent to the surface.
+    /// Surfaces without a natural parameterization use an arbitrary unit tangent.
+    pub dpdu: Vector3<f32>,
+
+    /// The rate of change of the point with respect to `uv.y`.
+    pub dpdv: Vector3<f32>,
+
+    /// `true` if the ray hit the side of the surface that `normal` points out of, i.e. if the ray
+    /// is entering the solid rather than leaving it.
+    pub front_face: bool,
 }
 
-pub trait Solid {
+impl SolidHit {
+    /// Returns the surface normal flipped, if necessary, to point towards the side of the
+    /// surface that the ray came from.
+    pub fn facing_normal(&self) -> Vector3<f32> {
+        if self.front_face {
+            self.normal
+        } else {
+            -self.normal
+        }
+    }
+}
+
+/// Returns texture coordinates and tangents for a surface with no natural parameterization: the
+/// point's coordinates along an arbitrary pair of unit tangents perpendicular to `normal`.
+pub fn planar_mapping(
+    point: Point3<f32>,
+    normal: Vector3<f32>,
+) -> (Vector2<f32>, Vector3<f32>, Vector3<f32>) {
+    let (dpdu, dpdv) = orthonormal_basis(normal);
+    let uv = Vector2 { x: point.to_vec().dot(dpdu), y: point.to_vec().dot(dpdv) };
+    (uv, dpdu, dpdv)
+}
+
+/// A point at which a ray crosses the surface of a solid.
+#[derive(Copy, Debug, Clone)]
+pub struct SolidCrossing {
+    /// The distance along the ray to the crossing, in units of the ray direction's length. It's
+    /// negative for crossings behind the ray's origin, and infinite at the ends of the intervals
+    /// of unbounded solids.
+    pub t: f32,
+
+    /// The hit at the crossing, or `None` if `t` is infinite.
+    pub hit: Option<SolidHit>,
+}
+
+impl SolidCrossing {
+    fn infinite(t: f32) -> Self {
+        SolidCrossing { t, hit: None }
+    }
+}
+
+/// A section of a ray that's inside a solid.
+#[derive(Copy, Debug, Clone)]
+pub struct SolidInterval {
+    /// Where the ray enters the solid.
+    pub enter: SolidCrossing,
+
+    /// Where the ray leaves the solid.
+    pub exit: SolidCrossing,
+}
+
+pub trait Solid: Send + Sync {
     /// If `ray` intersects this object, `trace` returns a `SolidHit` object detailing this
     /// intersection; otherwise it returns `None`.
     fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit>;
+
+    /// Returns a box that contains the whole object, or `None` if it's unbounded (e.g. a plane).
+    fn bounds(&self) -> Option<Aabb3<f32>>;
+
+    /// Returns the sections of the whole line through `ray`, including the part behind its
+    /// origin, that are inside the solid. The intervals are sorted and don't overlap.
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval>;
+
+    /// Returns the area of the solid's surface, or `None` if it's unbounded or can't be sampled
+    /// with `sample_surface`. The default implementation returns `None`.
+    fn surface_area(&self) -> Option<f32> {
+        None
+    }
+
+    /// Maps the uniformly distributed sample `(u1, u2)` to a point uniformly distributed over
+    /// the solid's surface, and returns the hit that a ray arriving there from outside would
+    /// have, with a `t` of 0. Returns `None` if the solid can't be sampled. The default
+    /// implementation returns `None`.
+    fn sample_surface(&self, _u1: f32, _u2: f32) -> Option<SolidHit> {
+        None
+    }
+}
+
+/// Returns the first hit in front of the ray that `intervals` were computed for, given the
+/// intervals of a solid along it.
+pub fn first_crossing(intervals: &[SolidInterval]) -> Option<SolidHit> {
+    for interval in intervals {
+        for crossing in &[interval.enter, interval.exit] {
+            if crossing.t >= 0.0 {
+                return crossing.hit;
+            }
+        }
+    }
+    None
+}
+
+/// Returns the distances along `ray`, which must have a normalized direction, at which it enters
+/// and leaves `sphere`, or `None` if it misses.
+fn sphere_roots(sphere: &Sphere<f32>, ray: &Ray3<f32>) -> Option<(f32, f32)> {
+    // `collision`'s sphere intersection doesn't handle rays that start inside the sphere,
+    // which refracted rays do, so we solve the quadratic ourselves.
+    let offset = ray.origin - sphere.center;
+    let b = offset.dot(ray.direction);
+    let c = offset.magnitude2() - sphere.radius * sphere.radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    Some((-b - root, -b + root))
+}
+
+/// Spheres are parameterized by longitude, which increases anticlockwise about the y axis when
+/// viewed from above, and by latitude, which increases from the bottom pole to the top one.
+fn sphere_hit(sphere: &Sphere<f32>, ray: &Ray3<f32>, t: f32) -> SolidHit {
+    let intersection = ray.origin + ray.direction * t;
+    let offset = intersection - sphere.center;
+    let normal = offset / offset.magnitude();
+    let mut phi = (-offset.z).atan2(offset.x);
+    if phi < 0.0 {
+        phi += 2.0 * PI;
+    }
+    let theta = normal.y.max(-1.0).min(1.0).acos();
+    let ring_radius = (offset.x * offset.x + offset.z * offset.z).sqrt();
+    // At the poles longitude is undefined, so any horizontal direction will do.
+    let (cos_phi, sin_phi) = if ring_radius > 0.0 {
+        (offset.x / ring_radius, -offset.z / ring_radius)
+    } else {
+        (1.0, 0.0)
+    };
+    SolidHit {
+        t,
+        point: intersection,
+        normal,
+        uv: Vector2 { x: phi / (2.0 * PI), y: 1.0 - theta / PI },
+        dpdu: Vector3 { x: offset.z, y: 0.0, z: -offset.x } * (2.0 * PI),
+        dpdv: Vector3 {
+            x: offset.y * cos_phi,
+            y: -ring_radius,
+            z: -offset.y * sin_phi,
+        } * -PI,
+        front_face: ray.direction.dot(normal) < 0.0,
+    }
 }
 
 impl Solid for Sphere<f32> {
     fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
-        match Sphere::intersection(self, ray) {
-            Some(intersection) => {
-                let unnormalized_normal = intersection - self.center;
-                let normal = unnormalized_normal / unnormalized_normal.magnitude();
-                Some(SolidHit {
-                    point: intersection,
-                    normal,
-                })
-            },
-            None => None
+        let (near, far) = sphere_roots(self, ray)?;
+        let t = if near >= 0.0 {
+            near
+        } else if far >= 0.0 {
+            far
+        } else {
+            return None;
+        };
+        Some(sphere_hit(self, ray, t))
+    }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        let extent = Vector3 { x: self.radius, y: self.radius, z: self.radius };
+        Some(Aabb3::new(self.center - extent, self.center + extent))
+    }
+
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        match sphere_roots(self, ray) {
+            Some((near, far)) => vec![SolidInterval {
+                enter: SolidCrossing { t: near, hit: Some(sphere_hit(self, ray, near)) },
+                exit: SolidCrossing { t: far, hit: Some(sphere_hit(self, ray, far)) },
+            }],
+            None => vec![],
         }
     }
+
+    fn surface_area(&self) -> Option<f32> {
+        Some(4.0 * PI * self.radius * self.radius)
+    }
+
+    fn sample_surface(&self, u1: f32, u2: f32) -> Option<SolidHit> {
+        // Archimedes: the height of a uniformly distributed point on a sphere is uniform too.
+        let z = 1.0 - 2.0 * u1;
+        let radius = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let normal = Vector3 { x: radius * phi.cos(), y: radius * phi.sin(), z };
+        // Arrive at the point along the normal, from one unit outside the sphere.
+        let point = self.center + normal * self.radius;
+        let hit = sphere_hit(self, &Ray3::new(point + normal, -normal), 1.0);
+        Some(SolidHit { t: 0.0, ..hit })
+    }
 }
 
 impl Solid for Plane<f32> {
     fn trace(&self, ray: &Ray3<f32>) -> Option<SolidHit> {
         match Plane::intersection(self, ray) {
             Some(intersection) => {
+                let (uv, dpdu, dpdv) = planar_mapping(intersection, self.n);
                 Some(SolidHit {
+                    t: (intersection - ray.origin).dot(ray.direction) / ray.direction.magnitude2(),
                     point: intersection,
                     normal: self.n,
+                    uv,
+                    dpdu,
+                    dpdv,
+                    front_face: ray.direction.dot(self.n) < 0.0,
                 })
             },
             None => None
         }
     }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        None
+    }
+
+    /// A plane bounds the half-space that its normal points out of.
+    fn intervals(&self, ray: &Ray3<f32>) -> Vec<SolidInterval> {
+        let origin_distance = ray.origin.to_vec().dot(self.n) + self.d;
+        let approach = ray.direction.dot(self.n);
+        if approach == 0.0 {
+            return if origin_distance < 0.0 {
+                vec![SolidInterval {
+                    enter: SolidCrossing::infinite(-f32::INFINITY),
+                    exit: SolidCrossing::infinite(f32::INFINITY),
+                }]
+            } else {
+                vec![]
+            };
+        }
+        let t = -origin_distance / approach;
+        let point = ray.origin + ray.direction * t;
+        let (uv, dpdu, dpdv) = planar_mapping(point, self.n);
+        let crossing = SolidCrossing {
+            t,
+            hit: Some(SolidHit {
+                t,
+                point,
+                normal: self.n,
+                uv,
+                dpdu,
+                dpdv,
+                front_face: approach < 0.0,
+            }),
+        };
+        if approach < 0.0 {
+            vec![SolidInterval { enter: crossing, exit: SolidCrossing::infinite(f32::INFINITY) }]
+        } else {
+            vec![SolidInterval { enter: SolidCrossing::infinite(-f32::INFINITY), exit: crossing }]
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct SceneObjectHit {
     pub solid: SolidHit,
     pub material: Material,
 }
 
-pub trait SceneObject {
+pub trait SceneObject: Send + Sync {
     /// If `ray` intersects this object, `trace` returns a `SceneObjectHit` object detailing this
     /// intersection; otherwise it returns `None`.
     fn trace(&self, ray: &Ray3<f32>) -> Option<SceneObjectHit>;
+
+    /// Returns a box that contains the whole object, or `None` if it's unbounded.
+    fn bounds(&self) -> Option<Aabb3<f32>>;
+
+    /// Returns the area of the part of the object that emits light, or `None` if the object
+    /// doesn't emit light or can't be sampled with `sample_emission`. The default implementation
+    /// returns `None`.
+    fn emitting_area(&self) -> Option<f32> {
+        None
+    }
+
+    /// Maps the uniformly distributed sample `(u1, u2)` to a point uniformly distributed over
+    /// the part of the object that emits light, and returns it, like `Solid::sample_surface`,
+    /// along with the light emitted there. This is only called if `emitting_area` isn't `None`.
+    fn sample_emission(&self, _u1: f32, _u2: f32) -> Option<(SolidHit, Color)> {
+        None
+    }
 }
 
 pub struct SimpleObject {
@@ -91,4 +344,25 @@ impl SceneObject for SimpleObject {
             }
         })
     }
+
+    fn bounds(&self) -> Option<Aabb3<f32>> {
+        self.solid.bounds()
+    }
+
+    fn emitting_area(&self) -> Option<f32> {
+        if self.material.is_emissive() {
+            self.solid.surface_area()
+        } else {
+            None
+        }
+    }
+
+    /// The emission is evaluated without texture filtering, since there's no pixel footprint at
+    /// the sampled point.
+    fn sample_emission(&self, u1: f32, u2: f32) -> Option<(SolidHit, Color)> {
+        let emission = self.material.emission.as_ref()?;
+        self.solid.sample_surface(u1, u2).map(|sample| {
+            (sample, emission.color(&ShadingContext::new(sample)))
+        })
+    }
 }